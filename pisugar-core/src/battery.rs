@@ -1,14 +1,111 @@
 use std::collections::VecDeque;
-use std::time::Instant;
+use std::time::{Duration, Instant};
 
-use crate::{PiSugarConfig, Result, TapType};
+use crate::i2c::RegisterDump;
+use crate::{Error, PiSugarConfig, Result, TapType};
+
+/// Minimal intensity into the battery that counts as charging (A)
+pub const CHARGING_INTENSITY_THRESHOLD: f32 = 0.05;
+
+/// Minimal current step between two polls to estimate internal resistance (A)
+pub const LOAD_STEP_THRESHOLD: f32 = 0.2;
+
+/// Default voltage samples of charging detection, same as the display smoothing window
+pub const CHARGING_WINDOW: usize = 30;
+
+/// Voltage samples of charging detection, 3 to 1min of polls
+pub const CHARGING_WINDOW_RANGE: (usize, usize) = (3, 600);
 
 /// Battery event
 pub enum BatteryEvent {
     TapEvent(TapType),
+    ButtonStuck,
     SoftPowerOff,
 }
 
+/// Charge counter, integrates intensity readings with the trapezoidal rule
+///
+/// `dt` comes from the previous intensity reading, not the poll interval, so uneven polls are fine
+#[derive(Default)]
+pub struct ChargeCounter {
+    intensity: f32,
+    intensity_updated_at: Option<Instant>,
+    charge: f64,
+}
+
+impl ChargeCounter {
+    /// Add an intensity (A) reading
+    pub fn update(&mut self, now: Instant, intensity: f32) {
+        if let Some(updated_at) = self.intensity_updated_at {
+            let dt = now.saturating_duration_since(updated_at).as_secs_f64();
+            self.charge += (self.intensity + intensity) as f64 / 2.0 * dt;
+        }
+        self.intensity = intensity;
+        self.intensity_updated_at = Some(now);
+    }
+
+    /// Time of last intensity reading
+    pub fn intensity_updated_at(&self) -> Option<Instant> {
+        self.intensity_updated_at
+    }
+
+    /// Net charge into the battery (Ah), negative when discharging
+    pub fn charge_ah(&self) -> f64 {
+        self.charge / 3600.0
+    }
+}
+
+/// Voltage history of charging detection, sized separately from display smoothing
+pub struct ChargingTrend {
+    voltages: VecDeque<(Instant, f32)>,
+    window: usize,
+}
+
+impl ChargingTrend {
+    /// Keep the last `window` samples, clamped to `CHARGING_WINDOW_RANGE`
+    pub fn new(window: usize) -> Self {
+        let window = window.clamp(CHARGING_WINDOW_RANGE.0, CHARGING_WINDOW_RANGE.1);
+        Self {
+            voltages: VecDeque::with_capacity(window),
+            window,
+        }
+    }
+
+    /// Add a voltage (V) reading
+    pub fn push(&mut self, now: Instant, voltage: f32) {
+        if self.voltages.len() >= self.window {
+            self.voltages.pop_front();
+        }
+        self.voltages.push_back((now, voltage));
+    }
+
+    /// Charging if voltage slope over the window is positive
+    pub fn is_charging(&self) -> bool {
+        self.voltages.len() > 2 && matches!(voltage_slope(&self.voltages), Some(slope) if slope > 0.0)
+    }
+}
+
+/// Voltage slope (V/s), least squares fit in one pass, None if all samples are at the same time
+pub fn voltage_slope(voltages: &VecDeque<(Instant, f32)>) -> Option<f32> {
+    let (t0, v0) = *voltages.front()?;
+    let (mut n, mut st, mut sv, mut stt, mut stv) = (0.0, 0.0, 0.0, 0.0, 0.0);
+    for (t, v) in voltages {
+        // relative to the first sample, keeps the sums small
+        let t = t.saturating_duration_since(t0).as_secs_f64();
+        let v = (v - v0) as f64;
+        n += 1.0;
+        st += t;
+        sv += v;
+        stt += t * t;
+        stv += t * v;
+    }
+    let d = n * stt - st * st;
+    if d <= 0.0 {
+        return None;
+    }
+    Some(((n * stv - st * sv) / d) as f32)
+}
+
 /// Battery chip controller
 pub trait Battery {
     /// Init battery chip
@@ -32,15 +129,30 @@ pub trait Battery {
     /// Battery voltage level
     fn level(&self) -> Result<f32>;
 
+    /// Battery level of a voltage (V) on the battery curve
+    fn voltage_level(&self, voltage: f32) -> Result<f32>;
+
     /// Battery current intensity (A)
     fn intensity(&self) -> Result<f32>;
 
     /// Battery average current intensity (A)
     fn intensity_avg(&self) -> Result<f32>;
 
+    /// Average current drawn from the battery (A), 0 or negative while charging
+    ///
+    /// IP5xxx intensity is signed, positive when charging
+    fn discharge_current(&self) -> Result<f32> {
+        self.intensity_avg().map(|i| -i)
+    }
+
     /// Is power cable plugged in
     fn is_power_plugged(&self) -> Result<bool>;
 
+    /// External power present, read from the VBUS/input-present bit, regardless of charging
+    fn external_power_present(&self) -> Result<bool> {
+        Err(Error::Other("Not available".to_string()))
+    }
+
     /// Restore power after
     fn toggle_power_restore(&self, enable: bool) -> Result<()>;
 
@@ -53,6 +165,11 @@ pub trait Battery {
     /// Is battery charging
     fn is_charging(&self) -> Result<bool>;
 
+    /// Is battery charging, judged by the current intensity (positive means charging)
+    fn is_charging_by_current(&self) -> Result<bool> {
+        self.intensity().map(check_charging_by_current)
+    }
+
     /// Is input protect enabled
     fn is_input_protected(&self) -> Result<bool>;
 
@@ -65,14 +182,55 @@ pub trait Battery {
     /// Toggle output enable
     fn toggle_output_enabled(&self, enable: bool) -> Result<()>;
 
+    /// Read voltage, level and intensity into history, `poll` does this before checking taps
+    fn refresh(&mut self, now: Instant) -> Result<()>;
+
     /// Poll and check tapped
     fn poll(&mut self, now: Instant, config: &PiSugarConfig) -> Result<Vec<BatteryEvent>>;
 
+    /// Internal resistance (Ohm) estimated from recent load steps
+    fn internal_resistance(&self) -> Option<f64> {
+        None
+    }
+
+    /// Net charge into the battery (Ah) since start, negative when discharging
+    fn net_charge(&self) -> Option<f64> {
+        None
+    }
+
+    /// Button held down duration
+    fn button_hold_duration(&self) -> Option<Duration> {
+        None
+    }
+
+    /// Battery curve segment of the average voltage, see `curve_segment_for`
+    fn curve_segment(&self) -> Result<Option<usize>>;
+
+    /// Raw tap gpio samples, oldest first, '1' pressed
+    fn tap_history(&self) -> &str {
+        ""
+    }
+
     /// Shutdown battery chip, call `toggle_output_enabled(false)`
     fn shutdown(&self) -> Result<()> {
         self.toggle_output_enabled(false)
     }
 
+    /// Registers `shutdown` changes, kept after init so an interrupted shutdown can be undone at the next init
+    fn read_shutdown_config(&self) -> Result<Vec<(u8, u8)>> {
+        Ok(Vec::new())
+    }
+
+    /// Write back registers from `read_shutdown_config`, returns true if any had changed
+    fn restore_shutdown_config(&self, _regs: &[(u8, u8)]) -> Result<bool> {
+        Ok(false)
+    }
+
+    /// Chip registers for diagnostics, None if the chip has no register dump
+    fn register_dump(&self) -> Option<RegisterDump> {
+        None
+    }
+
     /// Enable/disable light load shutdown
     fn toggle_light_load_shutdown(&self, enable: bool) -> Result<()>;
 
@@ -86,8 +244,12 @@ pub trait Battery {
     fn temperature(&self) -> Result<f32>;
 }
 
+/// Charging if the level regression slope is positive, false with fewer than 2 levels (no slope)
 #[allow(dead_code)]
 pub fn check_charging(levels: &VecDeque<f32>) -> bool {
+    if levels.len() < 2 {
+        return false;
+    }
     let capacity = levels.len() as f32;
     let x_sum = (0.0 + capacity - 1.0) * capacity / 2.0;
     let x_bar = x_sum / capacity;
@@ -102,7 +264,200 @@ pub fn check_charging(levels: &VecDeque<f32>) -> bool {
         a += yi * (xi - x_bar);
         b += (xi - x_bar) * (xi - x_bar);
     }
+    if b < f32::EPSILON {
+        return false;
+    }
     let k = a / b;
     log::debug!("Charging k: {}", k);
-    k >= 0.005
+    k.is_finite() && k >= 0.005
+}
+
+/// Charging if current flows into the battery
+pub fn check_charging_by_current(intensity: f32) -> bool {
+    intensity >= CHARGING_INTENSITY_THRESHOLD
+}
+
+/// Estimate internal resistance (Ohm) from the largest current step between two polls, R = dV / dI
+///
+/// Voltages and intensities are sampled in the same polls, returns None without a clear load step
+pub fn estimate_internal_resistance(
+    voltages: &VecDeque<(Instant, f32)>,
+    intensities: &VecDeque<(Instant, f32)>,
+) -> Option<f64> {
+    let samples: Vec<(f32, f32)> = voltages
+        .iter()
+        .zip(intensities.iter())
+        .map(|(v, i)| (v.1, i.1))
+        .collect();
+    let (dv, di) = samples
+        .windows(2)
+        .map(|w| (w[1].0 - w[0].0, w[1].1 - w[0].1))
+        .max_by(|a, b| a.1.abs().partial_cmp(&b.1.abs()).unwrap_or(std::cmp::Ordering::Equal))?;
+    if di.abs() < LOAD_STEP_THRESHOLD {
+        return None;
+    }
+    // current into the battery is positive, so voltage rises with it
+    let r = dv / di;
+    if r > 0.0 {
+        Some(r as f64)
+    } else {
+        None
+    }
+}
+
+/// Open circuit voltage (V), the sag under load `internal_resistance` (Ohm) added back
+///
+/// `discharge_current` is the current drawn from the battery (A), see `Battery::discharge_current`
+pub fn load_compensated_voltage(voltage: f32, discharge_current: f32, internal_resistance: f64) -> f32 {
+    voltage + discharge_current * internal_resistance as f32
+}
+
+/// Days on standby, None if idle intensity (A) is unknown or zero
+pub fn standby_days(remaining_mah: f64, idle_intensity: f64) -> Option<f64> {
+    if idle_intensity.is_nan() || idle_intensity <= 0.0 || !remaining_mah.is_finite() {
+        return None;
+    }
+    Some(remaining_mah.max(0.0) / (idle_intensity * 1000.0) / 24.0)
+}
+
+/// IP5xxx 14-bit two's complement ADC counts, bit13 of high byte is the sign
+pub fn adc_counts(low: u16, high: u16) -> i16 {
+    let v = ((high & 0x3f) << 8) | (low & 0xff);
+    // sign extend from bit13
+    ((v << 2) as i16) >> 2
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+    use std::time::{Duration, Instant};
+
+    use super::{
+        adc_counts, check_charging, check_charging_by_current, estimate_internal_resistance, standby_days,
+        voltage_slope, ChargeCounter, ChargingTrend,
+    };
+
+    #[test]
+    fn test_charging_trend() {
+        let now = Instant::now();
+        // discharging, bump at the end
+        let samples: Vec<(Instant, f32)> = (0..60)
+            .map(|i| {
+                let v = 4.0 - 0.001 * i as f32 + if i < 57 { 0.0 } else { 0.1 };
+                (now + Duration::from_millis(100 * i), v)
+            })
+            .collect();
+
+        // short bump doesn't flip a long window
+        let mut long = ChargingTrend::new(60);
+        let mut short = ChargingTrend::new(5);
+        for (t, v) in &samples {
+            long.push(*t, *v);
+            short.push(*t, *v);
+        }
+        assert!(!long.is_charging());
+        assert!(short.is_charging());
+
+        let mut rising = ChargingTrend::new(60);
+        for i in 0..60 {
+            rising.push(now + Duration::from_millis(100 * i), 3.9 + 0.001 * i as f32);
+        }
+        assert!(rising.is_charging());
+        let slope = voltage_slope(&rising.voltages).unwrap();
+        assert!((slope - 0.01).abs() < 1e-4, "{}", slope);
+
+        // same time, no slope
+        let same = VecDeque::from(vec![(now, 4.0), (now, 4.1)]);
+        assert_eq!(voltage_slope(&same), None);
+
+        let config = crate::PiSugarConfig::from_json_str(r#"{"charging_window": 100000}"#).unwrap();
+        assert_eq!(config.charging_window, Some(600));
+    }
+
+    #[test]
+    fn test_check_charging_by_current() {
+        assert!(check_charging_by_current(0.8));
+        assert!(!check_charging_by_current(0.01));
+        assert!(!check_charging_by_current(-0.3));
+    }
+
+    #[test]
+    fn test_check_charging() {
+        assert!(!check_charging(&VecDeque::new()));
+        assert!(!check_charging(&VecDeque::from(vec![50.0])));
+        assert!(!check_charging(&VecDeque::from(vec![f32::NAN, f32::NAN])));
+        assert!(check_charging(&VecDeque::from(vec![50.0, 50.5, 51.0])));
+        assert!(!check_charging(&VecDeque::from(vec![51.0, 50.5, 50.0])));
+    }
+
+    #[test]
+    fn test_estimate_internal_resistance() {
+        let now = Instant::now();
+        let history = |samples: &[(f32, f32)]| {
+            let mut voltages = VecDeque::new();
+            let mut intensities = VecDeque::new();
+            for (n, (v, i)) in samples.iter().enumerate() {
+                let at = now + Duration::from_millis(100 * n as u64);
+                voltages.push_back((at, *v));
+                intensities.push_back((at, *i));
+            }
+            (voltages, intensities)
+        };
+
+        // 0.2A -> 1.2A discharge, 4.00V -> 3.85V, 0.15Ohm
+        let (voltages, intensities) = history(&[(4.0, -0.2), (4.0, -0.2), (3.85, -1.2), (3.85, -1.2)]);
+        let r = estimate_internal_resistance(&voltages, &intensities).unwrap();
+        assert!((r - 0.15).abs() < 1e-4, "{}", r);
+
+        // no clear step
+        let (voltages, intensities) = history(&[(4.0, -0.2), (3.99, -0.25), (3.98, -0.3)]);
+        assert_eq!(estimate_internal_resistance(&voltages, &intensities), None);
+
+        // voltage moves against current, noise
+        let (voltages, intensities) = history(&[(4.0, -0.2), (4.05, -1.2)]);
+        assert_eq!(estimate_internal_resistance(&voltages, &intensities), None);
+
+        assert_eq!(estimate_internal_resistance(&VecDeque::new(), &VecDeque::new()), None);
+    }
+
+    #[test]
+    fn test_adc_counts() {
+        assert_eq!(adc_counts(0x00, 0x00), 0);
+        assert_eq!(adc_counts(0xb8, 0x0b), 3000);
+        assert_eq!(adc_counts(0xff, 0x1f), 8191);
+        // sign bit set
+        assert_eq!(adc_counts(0xff, 0x3f), -1);
+        assert_eq!(adc_counts(0x18, 0x3c), -1000);
+        assert_eq!(adc_counts(0x00, 0x20), -8192);
+        // bits above 14 are ignored
+        assert_eq!(adc_counts(0x18, 0xfc), -1000);
+        assert_eq!(adc_counts(0xb8, 0xcb), 3000);
+    }
+
+    #[test]
+    fn test_charge_counter() {
+        let start = Instant::now();
+        let mut counter = ChargeCounter::default();
+        assert_eq!(counter.intensity_updated_at(), None);
+
+        // (seconds since start, intensity A), uneven intervals
+        let readings = [(0.0, -1.0), (1.0, -0.5), (4.0, -0.5), (4.5, 1.0), (10.0, 2.0)];
+        for (t, i) in readings.iter() {
+            counter.update(start + Duration::from_secs_f64(*t), *i);
+        }
+        let expected =
+            (-1.0 - 0.5) / 2.0 * 1.0 + (-0.5 - 0.5) / 2.0 * 3.0 + (-0.5 + 1.0) / 2.0 * 0.5 + (1.0 + 2.0) / 2.0 * 5.5;
+        assert!((counter.charge_ah() - expected / 3600.0).abs() < 1e-9);
+        assert_eq!(counter.intensity_updated_at(), Some(start + Duration::from_secs(10)));
+    }
+
+    #[test]
+    fn test_standby_days() {
+        // 1200mAh at 110mA idle
+        let days = standby_days(1200.0, 0.11).unwrap();
+        assert!((days - 1200.0 / 110.0 / 24.0).abs() < 1e-9);
+        assert_eq!(standby_days(2400.0, 0.1), Some(1.0));
+        assert_eq!(standby_days(1200.0, 0.0), None);
+        assert_eq!(standby_days(1200.0, f64::NAN), None);
+    }
 }
@@ -0,0 +1,101 @@
+use std::time::Duration;
+
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
+
+/// Default max time between rapid boots, 2min
+pub const DEFAULT_POWER_FLAPPING_INTERVAL: u64 = 120;
+
+/// Rapid boots in a row to be considered flapping power
+pub const POWER_FLAPPING_BOOTS: u32 = 3;
+
+/// Kernel boot id, changes on every boot but not on a server restart
+pub const BOOT_ID_PATH: &str = "/proc/sys/kernel/random/boot_id";
+
+/// Last boot and rapid boots in a row, kept across reboots to detect flapping power
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct BootHistory {
+    boot_id: Option<String>,
+    last_boot: Option<DateTime<Local>>,
+    rapid_boots: u32,
+}
+
+impl BootHistory {
+    /// Record boot `boot_id` at `now` (rtc time), returns true if power is flapping, i.e. the last
+    /// `POWER_FLAPPING_BOOTS` boots in a row each came within `interval` of the previous one
+    ///
+    /// A boot already recorded (server restarted) is not counted again
+    pub fn record(&mut self, boot_id: &str, now: DateTime<Local>, interval: Duration) -> bool {
+        if self.boot_id.as_deref() == Some(boot_id) {
+            return false;
+        }
+        let rapid = match self.last_boot {
+            // a clock going backwards is not a rapid boot
+            Some(last) => (now - last).to_std().is_ok_and(|d| d <= interval),
+            None => false,
+        };
+        self.rapid_boots = if rapid { self.rapid_boots + 1 } else { 0 };
+        self.boot_id = Some(boot_id.to_string());
+        self.last_boot = Some(now);
+        self.rapid_boots >= POWER_FLAPPING_BOOTS
+    }
+
+    pub fn last_boot(&self) -> Option<DateTime<Local>> {
+        self.last_boot
+    }
+
+    /// Boots in a row within the interval of the previous one
+    pub fn rapid_boots(&self) -> u32 {
+        self.rapid_boots
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use chrono::{Local, NaiveDate, TimeZone};
+
+    use super::{BootHistory, POWER_FLAPPING_BOOTS};
+
+    #[test]
+    fn test_boot_history() {
+        let at = |m, s| {
+            let t = NaiveDate::from_ymd_opt(2026, 10, 16)
+                .unwrap()
+                .and_hms_opt(8, m, s)
+                .unwrap();
+            Local.from_local_datetime(&t).unwrap()
+        };
+        let interval = Duration::from_secs(120);
+        let mut history = BootHistory::default();
+        assert!(!history.record("a", at(0, 0), interval));
+
+        // rebooted after 5min, fine
+        assert!(!history.record("b", at(5, 0), interval));
+        assert_eq!(history.rapid_boots(), 0);
+
+        // power cut every 40s, 3 short intervals
+        assert!(!history.record("c", at(5, 40), interval));
+        assert!(!history.record("d", at(6, 20), interval));
+        assert!(history.record("e", at(7, 0), interval));
+        assert_eq!(history.rapid_boots(), POWER_FLAPPING_BOOTS);
+
+        // server restarted, same boot
+        assert!(!history.record("e", at(7, 10), interval));
+        assert_eq!(history.rapid_boots(), POWER_FLAPPING_BOOTS);
+        assert_eq!(history.last_boot(), Some(at(7, 0)));
+
+        // stable again
+        assert!(!history.record("f", at(20, 0), interval));
+        assert_eq!(history.last_boot(), Some(at(20, 0)));
+
+        // clock went backwards
+        assert!(!history.record("g", at(19, 59), interval));
+        assert_eq!(history.rapid_boots(), 0);
+
+        let json = serde_json::to_string(&history).unwrap();
+        assert_eq!(serde_json::from_str::<BootHistory>(&json).unwrap(), history);
+    }
+}
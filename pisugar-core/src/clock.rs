@@ -0,0 +1,41 @@
+use std::process::Command;
+use std::time::Instant;
+
+use chrono::{DateTime, Local};
+
+/// Time source
+pub trait Clock {
+    /// Monotonic now
+    fn now_instant(&self) -> Instant;
+
+    /// Local datetime now
+    fn now_local(&self) -> DateTime<Local>;
+
+    /// Datetime is synchronized, e.g. by NTP, and can be trusted to set the rtc
+    fn is_synchronized(&self) -> bool {
+        true
+    }
+}
+
+/// System clock
+#[derive(Debug, Default, Copy, Clone)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now_instant(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn now_local(&self) -> DateTime<Local> {
+        Local::now()
+    }
+
+    /// NTP synchronized as reported by timedated, false if it can't tell
+    fn is_synchronized(&self) -> bool {
+        Command::new("timedatectl")
+            .args(["show", "--property=NTPSynchronized", "--value"])
+            .output()
+            .map(|out| out.status.success() && String::from_utf8_lossy(&out.stdout).trim() == "yes")
+            .unwrap_or(false)
+    }
+}
@@ -1,12 +1,18 @@
 use std::{
+    fmt::{self, Display, Formatter},
     fs::{File, OpenOptions},
     io::{self, Read, Write},
-    path::Path,
+    path::{Path, PathBuf},
+    time::Duration,
 };
 
 use chrono::{DateTime, Local};
 use serde::{Deserialize, Serialize};
 
+use crate::battery::CHARGING_WINDOW_RANGE;
+use crate::wake_queue::WakeQueue;
+use crate::{long_tap_polls, TapType, I2C_READ_INTERVAL, LONG_TAP_POLLS};
+
 /// Battery voltage threshold, (low, percentage at low)
 pub type BatteryThreshold = (f32, f32);
 
@@ -19,6 +25,118 @@ fn default_session_timeout() -> u32 {
     60 * 60
 }
 
+/// Weekday repeat mask (de)serialized as weekday names, numbers are accepted too
+mod weekday_repeat {
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    use crate::WeekdayMask;
+
+    pub fn serialize<S: Serializer>(bits: &u8, serializer: S) -> Result<S::Ok, S::Error> {
+        WeekdayMask::from_bits(*bits).serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u8, D::Error> {
+        WeekdayMask::deserialize(deserializer).map(|mask| mask.bits())
+    }
+
+    /// Same for an optional mask, e.g. in `ConfigPatch`
+    pub mod option {
+        use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+        use crate::WeekdayMask;
+
+        pub fn serialize<S: Serializer>(bits: &Option<u8>, serializer: S) -> Result<S::Ok, S::Error> {
+            bits.map(WeekdayMask::from_bits).serialize(serializer)
+        }
+
+        pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<u8>, D::Error> {
+            Option::<WeekdayMask>::deserialize(deserializer).map(|mask| mask.map(|mask| mask.bits()))
+        }
+    }
+}
+
+/// Patch of an optional field, null is `Some(None)` to clear it, a missing field is `None` by `#[serde(default)]`
+mod clearable {
+    use serde::{Deserialize, Deserializer};
+
+    pub fn deserialize<'de, T: Deserialize<'de>, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<Option<T>>, D::Error> {
+        Option::<T>::deserialize(deserializer).map(Some)
+    }
+}
+
+/// Charging detection method
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ChargingDetector {
+    /// Rising voltage trend
+    Voltage,
+    /// Positive current intensity
+    Current,
+}
+
+/// Auto shutdown action at battery low
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ShutdownMode {
+    /// Power off
+    Poweroff,
+    /// Halt, stay powered for a quick manual restart
+    Halt,
+    /// Custom shell
+    Custom(String),
+}
+
+impl ShutdownMode {
+    /// Shell to execute
+    pub fn shell(&self) -> String {
+        match self {
+            ShutdownMode::Poweroff => "shutdown --poweroff 0".to_string(),
+            ShutdownMode::Halt => "shutdown --halt 0".to_string(),
+            ShutdownMode::Custom(shell) => shell.clone(),
+        }
+    }
+}
+
+/// Tap script started while the previous one is still running
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ScriptOverlap {
+    /// Run at the same time, each on its own thread
+    Parallel,
+    /// Run after the previous ones
+    Queue,
+    /// Ignore it
+    Drop,
+}
+
+/// Battery chemistry, selects the voltage curve of battery level
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BatteryChemistry {
+    /// Lithium polymer, built-in curve of the chip
+    LiPo,
+    /// Lithium iron phosphate, flat curve around 3.3V
+    LiFePO4,
+    /// User defined `battery_curve`
+    Custom,
+}
+
+/// LiFePO4 battery curve, resting voltage
+pub const LIFEPO4_BATTERY_CURVE: [BatteryThreshold; 10] = [
+    (3.40, 100.0),
+    (3.35, 95.0),
+    (3.33, 90.0),
+    (3.32, 70.0),
+    (3.30, 50.0),
+    (3.28, 30.0),
+    (3.25, 20.0),
+    (3.20, 10.0),
+    (3.00, 5.0),
+    (2.50, 0.0),
+];
+
 /// PiSugar configuration
 #[derive(Clone, Serialize, Deserialize)]
 pub struct PiSugarConfig {
@@ -37,16 +155,45 @@ pub struct PiSugarConfig {
     #[serde(default = "default_i2c_bus")]
     pub i2c_bus: u8,
 
-    /// I2C addr, default 0x57 (87), available in PiSugar3
+    /// Battery chip I2C addr, default 0x75 (117), 0x57 (87) in PiSugar3
     #[serde(default)]
+    pub battery_i2c_addr: Option<u16>,
+
+    /// Old name of `battery_i2c_addr`, used if that is not set
+    #[deprecated(note = "use battery_i2c_addr")]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
     pub i2c_addr: Option<u16>,
 
+    /// RTC chip I2C addr, default 0x32 (50), 0x57 (87) in PiSugar3
+    #[serde(default)]
+    pub rtc_i2c_addr: Option<u16>,
+
+    /// Wait up to seconds for PiSugar to answer on I2C bus at startup
+    #[serde(default)]
+    pub startup_probe_delay: Option<u64>,
+
+    /// Retry times of detecting PiSugar chips at startup, default 0
+    #[serde(default)]
+    pub detection_retries: Option<u32>,
+
+    /// Delay between detection retries (ms), default 500
+    #[serde(default)]
+    pub detection_retry_delay: Option<u64>,
+
+    /// Re-detect a missing PiSugar every n polls, default 10
+    #[serde(default)]
+    pub redetect_interval: Option<u32>,
+
+    /// Boots within seconds of the previous one count as rapid boots, default 120, 0 to disable
+    #[serde(default)]
+    pub power_flapping_interval: Option<u64>,
+
     /// Alarm time
     #[serde(default)]
     pub auto_wake_time: Option<DateTime<Local>>,
 
-    /// Alarm weekday repeat
-    #[serde(default)]
+    /// Alarm weekday repeat, bit 0 = Sunday, weekday names in json
+    #[serde(default, with = "weekday_repeat")]
     pub auto_wake_repeat: u8,
 
     /// Single tap enable
@@ -57,6 +204,10 @@ pub struct PiSugarConfig {
     #[serde(default)]
     pub single_tap_shell: String,
 
+    /// Ignore repeated single tap action within seconds
+    #[serde(default)]
+    pub single_tap_cooldown: Option<f64>,
+
     /// Double tap enable
     #[serde(default)]
     pub double_tap_enable: bool,
@@ -65,6 +216,14 @@ pub struct PiSugarConfig {
     #[serde(default)]
     pub double_tap_shell: String,
 
+    /// Ignore repeated double tap action within seconds
+    #[serde(default)]
+    pub double_tap_cooldown: Option<f64>,
+
+    /// Double tap max gap between two taps, in polls (100ms), default 2
+    #[serde(default)]
+    pub double_tap_max_gap: Option<usize>,
+
     /// Long tap enable
     #[serde(default)]
     pub long_tap_enable: bool,
@@ -73,6 +232,27 @@ pub struct PiSugarConfig {
     #[serde(default)]
     pub long_tap_shell: String,
 
+    /// Long tap hold duration in seconds, default 0.8
+    #[serde(default)]
+    pub long_press_duration: Option<f64>,
+
+    /// Ignore repeated long tap action within seconds
+    #[serde(default)]
+    pub long_tap_cooldown: Option<f64>,
+
+    /// Kill tap and charge full scripts still running after seconds
+    #[serde(default)]
+    pub shell_timeout: Option<u64>,
+
+    /// Tap script while the previous one is running, parallel (default), queue or drop, the last two need
+    /// `shell_timeout`
+    #[serde(default)]
+    pub tap_script_overlap: Option<ScriptOverlap>,
+
+    /// Button gpio is active-low, i.e. reads 1 when released
+    #[serde(default)]
+    pub invert_button: Option<bool>,
+
     /// Auto shutdown when battery level is low
     #[serde(default)]
     pub auto_shutdown_level: Option<f64>,
@@ -81,18 +261,74 @@ pub struct PiSugarConfig {
     #[serde(default)]
     pub auto_shutdown_delay: Option<f64>,
 
+    /// Battery level to re-arm auto power on after auto shutdown
+    #[serde(default)]
+    pub auto_shutdown_recover_level: Option<f64>,
+
+    /// Auto shutdown action, default soft_poweroff_shell or poweroff
+    #[serde(default)]
+    pub shutdown_mode: Option<ShutdownMode>,
+
+    /// Over voltage warning threshold (V)
+    #[serde(default)]
+    pub over_voltage: Option<f32>,
+
+    /// Under voltage warning threshold (V)
+    #[serde(default)]
+    pub under_voltage: Option<f32>,
+
+    /// Force shutdown if still alive after graceful poweroff, seconds
+    #[serde(default)]
+    pub force_shutdown_timeout: Option<u64>,
+
+    /// Battery level to run critical_shell and then force shutdown
+    #[serde(default)]
+    pub critical_level: Option<f64>,
+
+    /// Last-ditch shell at critical_level, e.g. flush a database
+    #[serde(default)]
+    pub critical_shell: Option<String>,
+
+    /// Force shutdown this long after critical_shell, even if it is still running, seconds
+    #[serde(default)]
+    pub critical_timeout: Option<u64>,
+
+    /// Power consumption CSV log path
+    #[serde(default)]
+    pub log_csv_path: Option<String>,
+
+    /// Export battery capacity/status files to the directory, like linux power_supply sysfs
+    #[serde(default)]
+    pub power_supply_dir: Option<String>,
+
     /// Charging range
     #[serde(default)]
     pub auto_charging_range: Option<(f32, f32)>,
 
+    /// Charging detection method, default voltage
+    #[serde(default)]
+    pub charging_detector: Option<ChargingDetector>,
+
+    /// Voltage samples of charging detection, default 30, display smoothing is not affected
+    #[serde(default)]
+    pub charging_window: Option<usize>,
+
     /// Keep charging duration
     #[serde(default)]
     pub full_charge_duration: Option<u64>,
 
+    /// Shell script executed once when battery is fully charged
+    #[serde(default)]
+    pub charge_full_shell: Option<String>,
+
     /// UPS automatically power on when power recovered
     #[serde(default)]
     pub auto_power_on: Option<bool>,
 
+    /// Chip's built-in light load auto shutdown, default true, PiSugar 2 only
+    #[serde(default)]
+    pub enable_chip_auto_shutdown: Option<bool>,
+
     /// Soft poweroff, PiSugar 3 only
     #[serde(default)]
     pub soft_poweroff: Option<bool>,
@@ -105,6 +341,18 @@ pub struct PiSugarConfig {
     #[serde(default)]
     pub auto_rtc_sync: Option<bool>,
 
+    /// Read rtc every n polls, battery is read every poll, default 600 (1min)
+    #[serde(default)]
+    pub rtc_poll_divisor: Option<u32>,
+
+    /// Read back rtc time after writing it
+    #[serde(default)]
+    pub verify_rtc_writes: Option<bool>,
+
+    /// Set rtc from system time at init if the rtc lost power, SD3078 only
+    #[serde(default)]
+    pub rtc_init_from_system_if_invalid: Option<bool>,
+
     /// RTC ppm adjust comm (every second)
     #[serde(default)]
     pub adj_comm: Option<u8>,
@@ -125,9 +373,33 @@ pub struct PiSugarConfig {
     #[serde(default)]
     pub bat_protect: Option<bool>,
 
+    /// Battery chemistry, default LiPo, or Custom if `battery_curve` is set
+    #[serde(default)]
+    pub battery_chemistry: Option<BatteryChemistry>,
+
     /// User defined battery curve
     #[serde(default)]
     pub battery_curve: Option<Vec<BatteryThreshold>>,
+
+    /// Battery capacity (mAh), default from model
+    #[serde(default)]
+    pub battery_capacity: Option<f64>,
+
+    /// Voltage calibration offset (V), default 0.0
+    #[serde(default)]
+    pub voltage_offset: Option<f32>,
+
+    /// Voltage calibration scale, default 1.0
+    #[serde(default)]
+    pub voltage_scale: Option<f32>,
+
+    /// Battery internal resistance (Ohm) for load compensated level, default 0.0
+    #[serde(default)]
+    pub internal_resistance: Option<f64>,
+
+    /// Pending wake alarms, the soonest is armed instead of auto wake
+    #[serde(default)]
+    pub wake_queue: WakeQueue,
 }
 
 impl PiSugarConfig {
@@ -143,59 +415,356 @@ impl PiSugarConfig {
         true
     }
 
+    /// Battery curve of the selected chemistry, `lipo_curve` is the built-in curve of the chip
+    pub fn battery_curve<'a>(&'a self, lipo_curve: &'a [BatteryThreshold]) -> &'a [BatteryThreshold] {
+        let custom = self.battery_curve.as_deref();
+        match self.battery_chemistry {
+            Some(BatteryChemistry::LiPo) => lipo_curve,
+            Some(BatteryChemistry::LiFePO4) => LIFEPO4_BATTERY_CURVE.as_ref(),
+            Some(BatteryChemistry::Custom) | None => custom.unwrap_or(lipo_curve),
+        }
+    }
+
+    /// Calibrated voltage (V), raw * voltage_scale + voltage_offset
+    pub fn calibrate_voltage(&self, raw: f32) -> f32 {
+        raw * self.voltage_scale.unwrap_or(1.0) + self.voltage_offset.unwrap_or(0.0)
+    }
+
+    /// Auto wake is disabled if auto_wake_time is null or no weekday repeats
+    pub fn auto_wake_enabled(&self) -> bool {
+        self.auto_wake_time.is_some() && self.auto_wake_repeat & 0x7f != 0
+    }
+
+    /// Chip's built-in auto shutdown, off in auto_power_on or if enable_chip_auto_shutdown is false
+    pub fn chip_auto_shutdown_enabled(&self) -> bool {
+        self.auto_power_on != Some(true) && self.enable_chip_auto_shutdown != Some(false)
+    }
+
+    /// Auto shutdown is disabled if auto_shutdown_level is null or <= 0
+    pub fn auto_shutdown_enabled(&self) -> bool {
+        matches!(self.auto_shutdown_level, Some(l) if l > 0.0)
+    }
+
+    /// Battery level is below auto_shutdown_level
+    pub fn is_auto_shutdown_level(&self, level: f32) -> bool {
+        self.auto_shutdown_enabled() && self.auto_shutdown_level.unwrap_or(0.0) > level as f64
+    }
+
+    /// Battery level is below critical_level, disabled if null or <= 0
+    pub fn is_critical_level(&self, level: f32) -> bool {
+        matches!(self.critical_level, Some(l) if l > 0.0 && l > level as f64)
+    }
+
+    /// Shell of auto shutdown at battery low
+    pub fn auto_shutdown_shell(&self) -> String {
+        match &self.shutdown_mode {
+            Some(mode) => mode.shell(),
+            None => self
+                .soft_poweroff_shell
+                .clone()
+                .unwrap_or_else(|| ShutdownMode::Poweroff.shell()),
+        }
+    }
+
+    /// Cooldown of repeated tap action of the tap type
+    pub fn tap_cooldown(&self, tap_type: TapType) -> Option<Duration> {
+        let cooldown = match tap_type {
+            TapType::Single => self.single_tap_cooldown,
+            TapType::Double => self.double_tap_cooldown,
+            TapType::Long => self.long_tap_cooldown,
+        };
+        cooldown
+            .filter(|c| *c > 0.0)
+            .and_then(|c| Duration::try_from_secs_f64(c).ok())
+    }
+
+    /// Long tap hold, in polls
+    pub fn long_tap_polls(&self) -> usize {
+        match self.long_press_duration.filter(|d| *d > 0.0) {
+            // whole ms, so that e.g. 2.0 / 0.1 isn't rounded up to 21
+            Some(d) => long_tap_polls(Duration::from_millis((d * 1000.0).round() as u64), I2C_READ_INTERVAL),
+            None => LONG_TAP_POLLS,
+        }
+    }
+
+    /// Serialize to pretty json
+    pub fn to_json_pretty(&self) -> serde_json::Result<String> {
+        serde_json::to_string_pretty(self)
+    }
+
+    /// Deserialize from json, with validation
+    pub fn from_json_str(s: &str) -> serde_json::Result<Self> {
+        let mut config: PiSugarConfig = serde_json::from_str(s)?;
+        if !PiSugarConfig::_validate_battery_curve(&config) {
+            return Err(serde::de::Error::custom("Invalid battery curve"));
+        }
+        // a hung script would hold up all later taps
+        if matches!(config.tap_script_overlap, Some(o) if o != ScriptOverlap::Parallel)
+            && config.shell_timeout.is_none()
+        {
+            return Err(serde::de::Error::custom(
+                "tap_script_overlap queue or drop requires shell_timeout",
+            ));
+        }
+        if let Some(window) = config.charging_window {
+            let (min, max) = CHARGING_WINDOW_RANGE;
+            if !(min..=max).contains(&window) {
+                log::warn!("charging_window {} out of range {}-{}, clamped", window, min, max);
+                config.charging_window = Some(window.clamp(min, max));
+            }
+        }
+        let cooldowns = [
+            config.single_tap_cooldown,
+            config.double_tap_cooldown,
+            config.long_tap_cooldown,
+        ];
+        if cooldowns
+            .iter()
+            .flatten()
+            .any(|c| Duration::try_from_secs_f64(*c).is_err())
+        {
+            return Err(serde::de::Error::custom("Invalid tap cooldown"));
+        }
+        Ok(config)
+    }
+
     pub fn load(&mut self, path: &Path) -> io::Result<()> {
         let mut f = File::open(path)?;
         let mut buff = String::new();
         let _ = f.read_to_string(&mut buff)?;
-        let config = serde_json::from_str(&buff)?;
-        if !PiSugarConfig::_validate_battery_curve(&config) {
-            return Err(io::ErrorKind::InvalidData.into());
-        }
+        let config = PiSugarConfig::from_json_str(&buff).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
         *self = config;
         Ok(())
     }
 
+    /// Load config file, errors tell a directory, permission and parse problems apart
+    pub fn load_checked(path: &Path) -> std::result::Result<Self, ConfigError> {
+        if path.exists() && !path.is_file() {
+            return Err(ConfigError::NotAFile(path.to_path_buf()));
+        }
+        let mut config = PiSugarConfig::default();
+        config.load(path).map_err(|e| ConfigError::from_io(path, e))?;
+        Ok(config)
+    }
+
     pub fn save_to(&self, path: &Path) -> io::Result<()> {
         let mut options = OpenOptions::new();
         options.write(true).create(true);
         let mut f = options.open(path)?;
-        let s = serde_json::to_string_pretty(self)?;
+        let s = self.to_json_pretty()?;
         log::info!("Dump config:\n{}", s);
         f.set_len(0)?;
         f.write_all(s.as_bytes())
     }
 }
 
+/// Config file load error
+#[derive(Debug)]
+pub enum ConfigError {
+    /// Path exists but is not a regular file, e.g. a directory
+    NotAFile(PathBuf),
+    /// No permission to read the file
+    PermissionDenied(PathBuf),
+    /// Malformed json or invalid values
+    Parse(PathBuf, String),
+    /// Other io error, e.g. not found
+    Io(PathBuf, io::Error),
+}
+
+impl ConfigError {
+    /// Classify io error of loading config file
+    pub fn from_io(path: &Path, e: io::Error) -> Self {
+        match e.kind() {
+            io::ErrorKind::PermissionDenied => ConfigError::PermissionDenied(path.to_path_buf()),
+            io::ErrorKind::InvalidData => ConfigError::Parse(path.to_path_buf(), e.to_string()),
+            _ => ConfigError::Io(path.to_path_buf(), e),
+        }
+    }
+
+    /// Stable machine-readable code, see `Error::code`
+    pub fn code(&self) -> &'static str {
+        match self {
+            ConfigError::NotAFile(_) => "config_not_a_file",
+            ConfigError::PermissionDenied(_) => "config_permission_denied",
+            ConfigError::Parse(..) => "config_invalid",
+            ConfigError::Io(_, e) if e.kind() == io::ErrorKind::NotFound => "config_not_found",
+            ConfigError::Io(..) => "config_io",
+        }
+    }
+}
+
+impl Display for ConfigError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::NotAFile(path) => write!(f, "Config {} is not a regular file", path.display()),
+            ConfigError::PermissionDenied(path) => {
+                write!(f, "Permission denied to read config {}", path.display())
+            }
+            ConfigError::Parse(path, e) => write!(f, "Invalid config {}: {}", path.display(), e),
+            ConfigError::Io(path, e) => write!(f, "Could not open config {}: {}", path.display(), e),
+        }
+    }
+}
+
+/// Partial config update, only `Some` fields are applied
+///
+/// Optional config fields are cleared by `Some(None)`, i.e. null in json, a missing field is left as is, `None`
+/// fields are not serialized
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ConfigPatch {
+    #[serde(deserialize_with = "clearable::deserialize", skip_serializing_if = "Option::is_none")]
+    pub auto_wake_time: Option<Option<DateTime<Local>>>,
+    #[serde(with = "weekday_repeat::option", skip_serializing_if = "Option::is_none")]
+    pub auto_wake_repeat: Option<u8>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub single_tap_enable: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub single_tap_shell: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub double_tap_enable: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub double_tap_shell: Option<String>,
+    #[serde(deserialize_with = "clearable::deserialize", skip_serializing_if = "Option::is_none")]
+    pub double_tap_max_gap: Option<Option<usize>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub long_tap_enable: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub long_tap_shell: Option<String>,
+    #[serde(deserialize_with = "clearable::deserialize", skip_serializing_if = "Option::is_none")]
+    pub long_press_duration: Option<Option<f64>>,
+    #[serde(deserialize_with = "clearable::deserialize", skip_serializing_if = "Option::is_none")]
+    pub auto_shutdown_level: Option<Option<f64>>,
+    #[serde(deserialize_with = "clearable::deserialize", skip_serializing_if = "Option::is_none")]
+    pub auto_shutdown_delay: Option<Option<f64>>,
+    #[serde(deserialize_with = "clearable::deserialize", skip_serializing_if = "Option::is_none")]
+    pub auto_shutdown_recover_level: Option<Option<f64>>,
+    #[serde(deserialize_with = "clearable::deserialize", skip_serializing_if = "Option::is_none")]
+    pub shutdown_mode: Option<Option<ShutdownMode>>,
+    #[serde(deserialize_with = "clearable::deserialize", skip_serializing_if = "Option::is_none")]
+    pub auto_charging_range: Option<Option<(f32, f32)>>,
+    #[serde(deserialize_with = "clearable::deserialize", skip_serializing_if = "Option::is_none")]
+    pub full_charge_duration: Option<Option<u64>>,
+    #[serde(deserialize_with = "clearable::deserialize", skip_serializing_if = "Option::is_none")]
+    pub auto_power_on: Option<Option<bool>>,
+    #[serde(deserialize_with = "clearable::deserialize", skip_serializing_if = "Option::is_none")]
+    pub soft_poweroff: Option<Option<bool>>,
+    #[serde(deserialize_with = "clearable::deserialize", skip_serializing_if = "Option::is_none")]
+    pub soft_poweroff_shell: Option<Option<String>>,
+    #[serde(deserialize_with = "clearable::deserialize", skip_serializing_if = "Option::is_none")]
+    pub auto_rtc_sync: Option<Option<bool>>,
+    #[serde(deserialize_with = "clearable::deserialize", skip_serializing_if = "Option::is_none")]
+    pub anti_mistouch: Option<Option<bool>>,
+    #[serde(deserialize_with = "clearable::deserialize", skip_serializing_if = "Option::is_none")]
+    pub bat_protect: Option<Option<bool>>,
+}
+
+impl ConfigPatch {
+    /// Apply `Some` fields to config
+    pub fn apply_to(&self, config: &mut PiSugarConfig) {
+        macro_rules! patch {
+            ($($field:ident),*) => {
+                $(if let Some(v) = &self.$field {
+                    config.$field = v.clone();
+                })*
+            };
+        }
+        patch!(
+            auto_wake_time,
+            auto_wake_repeat,
+            single_tap_enable,
+            single_tap_shell,
+            double_tap_enable,
+            double_tap_shell,
+            double_tap_max_gap,
+            long_tap_enable,
+            long_tap_shell,
+            long_press_duration,
+            auto_shutdown_level,
+            auto_shutdown_delay,
+            auto_shutdown_recover_level,
+            shutdown_mode,
+            auto_charging_range,
+            full_charge_duration,
+            auto_power_on,
+            soft_poweroff,
+            soft_poweroff_shell,
+            auto_rtc_sync,
+            anti_mistouch,
+            bat_protect
+        );
+    }
+}
+
 impl Default for PiSugarConfig {
+    #[allow(deprecated)]
     fn default() -> Self {
         Self {
             auth_user: Default::default(),
             auth_password: Default::default(),
             session_timeout: default_session_timeout(),
             i2c_bus: default_i2c_bus(),
+            battery_i2c_addr: Default::default(),
             i2c_addr: Default::default(),
+            rtc_i2c_addr: Default::default(),
+            startup_probe_delay: Default::default(),
+            detection_retries: Default::default(),
+            detection_retry_delay: Default::default(),
+            redetect_interval: Default::default(),
+            power_flapping_interval: Default::default(),
             auto_wake_time: Default::default(),
             auto_wake_repeat: Default::default(),
             single_tap_enable: Default::default(),
             single_tap_shell: Default::default(),
+            single_tap_cooldown: Default::default(),
             double_tap_enable: Default::default(),
             double_tap_shell: Default::default(),
+            double_tap_cooldown: Default::default(),
+            double_tap_max_gap: Default::default(),
             long_tap_enable: Default::default(),
             long_tap_shell: Default::default(),
+            long_tap_cooldown: Default::default(),
+            long_press_duration: Default::default(),
+            shell_timeout: Default::default(),
+            tap_script_overlap: Default::default(),
+            invert_button: Default::default(),
             auto_shutdown_level: Default::default(),
             auto_shutdown_delay: Default::default(),
+            auto_shutdown_recover_level: Default::default(),
+            shutdown_mode: Default::default(),
+            over_voltage: Default::default(),
+            under_voltage: Default::default(),
+            force_shutdown_timeout: Default::default(),
+            critical_level: Default::default(),
+            critical_shell: Default::default(),
+            critical_timeout: Default::default(),
+            log_csv_path: Default::default(),
+            power_supply_dir: Default::default(),
             auto_charging_range: Default::default(),
+            charging_detector: Default::default(),
+            charging_window: Default::default(),
             full_charge_duration: Default::default(),
+            charge_full_shell: Default::default(),
             auto_power_on: Default::default(),
+            enable_chip_auto_shutdown: Default::default(),
             soft_poweroff: Default::default(),
             soft_poweroff_shell: Default::default(),
             auto_rtc_sync: Default::default(),
+            rtc_poll_divisor: Default::default(),
+            rtc_init_from_system_if_invalid: Default::default(),
+            verify_rtc_writes: Default::default(),
             adj_comm: Default::default(),
             adj_diff: Default::default(),
             rtc_adj_ppm: Default::default(),
             anti_mistouch: Default::default(),
             bat_protect: Default::default(),
+            battery_chemistry: Default::default(),
             battery_curve: Default::default(),
+            battery_capacity: Default::default(),
+            voltage_offset: Default::default(),
+            voltage_scale: Default::default(),
+            internal_resistance: Default::default(),
+            wake_queue: Default::default(),
         }
     }
 }
@@ -0,0 +1,155 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+use chrono::{DateTime, Local};
+
+/// CSV header
+pub const CSV_LOG_HEADER: &str = "timestamp,voltage,current,level";
+
+/// Max CSV log size before rotation, 8MB
+pub const CSV_LOG_MAX_SIZE: u64 = 8 * 1024 * 1024;
+
+/// CSV log flush interval
+const CSV_LOG_FLUSH_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Power consumption CSV log, rotated to `<path>.1` when full
+pub struct CsvLog {
+    path: PathBuf,
+    max_size: u64,
+    writer: Option<BufWriter<File>>,
+    size: u64,
+    flush_at: Instant,
+}
+
+impl CsvLog {
+    /// Create new CSV log, file is opened on first write
+    pub fn new(path: &str, max_size: u64) -> Self {
+        Self {
+            path: PathBuf::from(path),
+            max_size,
+            writer: None,
+            size: 0,
+            flush_at: Instant::now(),
+        }
+    }
+
+    /// Log file path
+    pub fn path(&self) -> &str {
+        self.path.to_str().unwrap_or_default()
+    }
+
+    fn open(&mut self) -> io::Result<()> {
+        let mut file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+        self.size = file.metadata()?.len();
+        if self.size == 0 {
+            writeln!(file, "{}", CSV_LOG_HEADER)?;
+            self.size = CSV_LOG_HEADER.len() as u64 + 1;
+        }
+        self.writer = Some(BufWriter::new(file));
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        if let Some(mut writer) = self.writer.take() {
+            writer.flush()?;
+        }
+        let mut rotated = self.path.clone().into_os_string();
+        rotated.push(".1");
+        fs::rename(&self.path, rotated)?;
+        self.open()
+    }
+
+    /// Append a row, flush periodically
+    pub fn append(
+        &mut self,
+        now: Instant,
+        dt: DateTime<Local>,
+        voltage: f32,
+        current: f32,
+        level: f32,
+    ) -> io::Result<()> {
+        if self.writer.is_none() {
+            self.open()?;
+        }
+        if self.size >= self.max_size {
+            self.rotate()?;
+        }
+
+        let row = format!("{},{:.3},{:.3},{:.1}\n", dt.to_rfc3339(), voltage, current, level);
+        if let Some(writer) = &mut self.writer {
+            writer.write_all(row.as_bytes())?;
+            self.size += row.len() as u64;
+            if self.flush_at + CSV_LOG_FLUSH_INTERVAL <= now {
+                self.flush_at = now;
+                writer.flush()?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Flush buffered rows
+    pub fn flush(&mut self) -> io::Result<()> {
+        match &mut self.writer {
+            Some(writer) => writer.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for CsvLog {
+    fn drop(&mut self) {
+        let _ = self.flush();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::time::Instant;
+
+    use chrono::Local;
+
+    use super::{CsvLog, CSV_LOG_HEADER};
+
+    #[test]
+    fn test_csv_log() {
+        let path = std::env::temp_dir().join(format!("pisugar-csv-log-{}.csv", std::process::id()));
+        let rotated = format!("{}.1", path.to_string_lossy());
+        let _ = fs::remove_file(&path);
+        let _ = fs::remove_file(&rotated);
+
+        let mut log = CsvLog::new(path.to_str().unwrap(), 1024);
+        let now = Instant::now();
+        for i in 0..3 {
+            log.append(now, Local::now(), 4.0 + i as f32 * 0.1, 0.5, 90.0 + i as f32)
+                .unwrap();
+        }
+        log.flush().unwrap();
+
+        let content = fs::read_to_string(&path).unwrap();
+        let mut lines = content.lines();
+        assert_eq!(lines.next(), Some(CSV_LOG_HEADER));
+        let rows: Vec<Vec<&str>> = lines.map(|l| l.split(',').collect()).collect();
+        assert_eq!(rows.len(), 3);
+        for (i, row) in rows.iter().enumerate() {
+            assert_eq!(row.len(), 4);
+            let voltage: f32 = row[1].parse().unwrap();
+            let level: f32 = row[3].parse().unwrap();
+            assert!((voltage - (4.0 + i as f32 * 0.1)).abs() < 0.001);
+            assert!((level - (90.0 + i as f32)).abs() < 0.01);
+        }
+
+        // rotate when full
+        for _ in 0..30 {
+            log.append(now, Local::now(), 4.0, 0.5, 90.0).unwrap();
+        }
+        log.flush().unwrap();
+        assert!(fs::metadata(&rotated).is_ok());
+        assert!(fs::metadata(&path).unwrap().len() <= 1024);
+
+        let _ = fs::remove_file(&path);
+        let _ = fs::remove_file(&rotated);
+    }
+}
@@ -0,0 +1,251 @@
+//! I2C bus, rppal on the Pi, or an in-memory register file with the `mock-hardware` feature
+
+#[cfg(not(any(feature = "rppal", feature = "mock-hardware")))]
+compile_error!("pisugar-core requires either the `rppal` (default) or the `mock-hardware` feature");
+
+// the mock would silently replace the real bus, e.g. with --all-features
+#[cfg(all(feature = "rppal", feature = "mock-hardware"))]
+compile_error!("`mock-hardware` replaces rppal, enable it with --no-default-features, not together with `rppal`");
+
+#[cfg(not(feature = "mock-hardware"))]
+pub use rppal::i2c::{Error, I2c};
+
+#[cfg(feature = "mock-hardware")]
+pub use self::mock::{Error, I2c};
+
+use std::fmt;
+
+use serde::ser::SerializeMap;
+use serde::{Serialize, Serializer};
+
+#[cfg(feature = "mock-hardware")]
+mod mock {
+    use std::cell::RefCell;
+    use std::collections::HashMap;
+    use std::fmt;
+    use std::io;
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    use std::sync::{Arc, Mutex};
+
+    /// Same variants as `rppal::i2c::Error` that pisugar-core matches on
+    #[derive(Debug)]
+    pub enum Error {
+        Io(io::Error),
+        FeatureNotSupported,
+    }
+
+    impl fmt::Display for Error {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            match self {
+                Error::Io(e) => write!(f, "I/O error: {}", e),
+                Error::FeatureNotSupported => write!(f, "I2C/SMBus feature not supported"),
+            }
+        }
+    }
+
+    impl std::error::Error for Error {}
+
+    impl From<io::Error> for Error {
+        fn from(e: io::Error) -> Self {
+            Error::Io(e)
+        }
+    }
+
+    pub type Result<T> = std::result::Result<T, Error>;
+
+    /// Chips on a bus, 256 byte registers per slave address, all 0 at start
+    type Bus = Arc<Mutex<HashMap<u16, [u8; 256]>>>;
+
+    /// Called after each byte read with the register and the registers of the chip, e.g. a clock ticking
+    type ReadHook = Arc<Mutex<Option<Box<dyn FnMut(u8, &mut [u8; 256]) + Send>>>>;
+
+    thread_local! {
+        /// Buses with a board plugged in, per thread so parallel tests don't share boards
+        static PLUGGED_BUSES: RefCell<Vec<(u8, Bus)>> = const { RefCell::new(Vec::new()) };
+    }
+
+    /// In-memory bus, registers of the chip at the slave address
+    pub struct I2c {
+        bus: Bus,
+        addr: u16,
+        no_block: AtomicBool,
+        block_reads: Arc<AtomicUsize>,
+        read_hook: ReadHook,
+    }
+
+    impl I2c {
+        /// No i2c device on the host, same as rppal without `/dev/i2c-N`, so the core runs without chips
+        pub fn with_bus(bus: u8) -> Result<I2c> {
+            if let Some(bus) =
+                PLUGGED_BUSES.with(|buses| buses.borrow().iter().find(|b| b.0 == bus).map(|b| b.1.clone()))
+            {
+                return Ok(I2c::on_bus(bus));
+            }
+            Err(Error::Io(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("/dev/i2c-{}", bus),
+            )))
+        }
+
+        /// Fake chip on a bus of its own, for tests
+        #[cfg(test)]
+        pub fn mock() -> I2c {
+            I2c::on_bus(Bus::default())
+        }
+
+        fn on_bus(bus: Bus) -> I2c {
+            I2c {
+                bus,
+                addr: 0,
+                no_block: AtomicBool::new(false),
+                block_reads: Arc::new(AtomicUsize::new(0)),
+                read_hook: Arc::new(Mutex::new(None)),
+            }
+        }
+
+        /// Registers of the chip at the slave address
+        fn with_regs<T>(&self, f: impl FnOnce(&mut [u8; 256]) -> T) -> T {
+            let mut bus = self.bus.lock().unwrap();
+            f(bus.entry(self.addr).or_insert([0; 256]))
+        }
+
+        /// Another handle to the same chip, to set registers after the chip is moved
+        #[cfg(test)]
+        pub fn share(&self) -> I2c {
+            I2c {
+                bus: self.bus.clone(),
+                addr: self.addr,
+                no_block: AtomicBool::new(self.no_block.load(Ordering::SeqCst)),
+                block_reads: self.block_reads.clone(),
+                read_hook: self.read_hook.clone(),
+            }
+        }
+
+        /// Count of block reads, shared so it can be checked after the chip is moved
+        #[cfg(test)]
+        pub fn block_reads(&self) -> Arc<AtomicUsize> {
+            self.block_reads.clone()
+        }
+
+        /// Plug a board into the bus, `with_bus` opens a fake chip from now on, also for tests of dependent crates
+        pub fn plug_bus(bus: u8) {
+            PLUGGED_BUSES.with(|buses| buses.borrow_mut().push((bus, Bus::default())));
+        }
+
+        /// Change registers after each byte read, e.g. tick a clock between the reads of its time registers
+        #[cfg(test)]
+        pub fn on_byte_read(&self, hook: impl FnMut(u8, &mut [u8; 256]) + Send + 'static) {
+            *self.read_hook.lock().unwrap() = Some(Box::new(hook));
+        }
+
+        /// Reject block transfers like an SMBus-only adapter
+        #[cfg(test)]
+        pub fn set_block_supported(&self, supported: bool) {
+            self.no_block.store(!supported, Ordering::SeqCst);
+        }
+
+        pub fn set_slave_address(&mut self, addr: u16) -> Result<()> {
+            self.addr = addr;
+            Ok(())
+        }
+
+        pub fn smbus_read_byte(&self, command: u8) -> Result<u8> {
+            Ok(self.with_regs(|regs| {
+                let v = regs[command as usize];
+                if let Some(hook) = self.read_hook.lock().unwrap().as_mut() {
+                    hook(command, regs);
+                }
+                v
+            }))
+        }
+
+        pub fn smbus_write_byte(&self, command: u8, value: u8) -> Result<()> {
+            self.with_regs(|regs| regs[command as usize] = value);
+            Ok(())
+        }
+
+        pub fn block_read(&self, command: u8, buffer: &mut [u8]) -> Result<()> {
+            self.block_reads.fetch_add(1, Ordering::SeqCst);
+            if self.no_block.load(Ordering::SeqCst) {
+                return Err(Error::FeatureNotSupported);
+            }
+            let start = command as usize;
+            if start + buffer.len() > 256 {
+                return Err(Error::FeatureNotSupported);
+            }
+            self.with_regs(|regs| buffer.copy_from_slice(&regs[start..start + buffer.len()]));
+            Ok(())
+        }
+
+        pub fn block_write(&self, command: u8, buffer: &[u8]) -> Result<()> {
+            if self.no_block.load(Ordering::SeqCst) {
+                return Err(Error::FeatureNotSupported);
+            }
+            let start = command as usize;
+            if start + buffer.len() > 256 {
+                return Err(Error::FeatureNotSupported);
+            }
+            self.with_regs(|regs| regs[start..start + buffer.len()].copy_from_slice(buffer));
+            Ok(())
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::I2c;
+
+        #[test]
+        fn test_mock_slave_address() {
+            I2c::plug_bus(7);
+            let mut battery = I2c::with_bus(7).unwrap();
+            battery.set_slave_address(0x75).unwrap();
+            let mut rtc = I2c::with_bus(7).unwrap();
+            rtc.set_slave_address(0x32).unwrap();
+
+            // chips at different addresses have their own registers
+            battery.smbus_write_byte(0x10, 0xaa).unwrap();
+            assert_eq!(rtc.smbus_read_byte(0x10).unwrap(), 0);
+
+            // same chip opened again
+            let mut again = I2c::with_bus(7).unwrap();
+            again.set_slave_address(0x75).unwrap();
+            assert_eq!(again.smbus_read_byte(0x10).unwrap(), 0xaa);
+            assert!(I2c::with_bus(8).is_err());
+        }
+    }
+}
+
+/// Register values read for debugging, e.g. `{0xa2: 0xb8, 0xa3: ??}`, ?? is unreadable
+///
+/// Serialized as a map of hex strings, unreadable registers are null
+pub struct RegisterDump(Vec<(u8, Option<u8>)>);
+
+impl RegisterDump {
+    /// Read `regs` one by one
+    pub fn read(i2c: &I2c, regs: &[u8]) -> Self {
+        Self(regs.iter().map(|reg| (*reg, i2c.smbus_read_byte(*reg).ok())).collect())
+    }
+}
+
+impl fmt::Debug for RegisterDump {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut map = f.debug_map();
+        for (reg, value) in &self.0 {
+            match value {
+                Some(value) => map.entry(&format_args!("{:#04x}", reg), &format_args!("{:#04x}", value)),
+                None => map.entry(&format_args!("{:#04x}", reg), &format_args!("??")),
+            };
+        }
+        map.finish()
+    }
+}
+
+impl Serialize for RegisterDump {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(Some(self.0.len()))?;
+        for (reg, value) in &self.0 {
+            map.serialize_entry(&format!("{:#04x}", reg), &value.map(|v| format!("{:#04x}", v)))?;
+        }
+        map.end()
+    }
+}
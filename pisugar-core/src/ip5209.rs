@@ -1,14 +1,17 @@
 use std::collections::VecDeque;
-use std::time::Instant;
+use std::fmt;
+use std::time::{Duration, Instant};
 
-use rppal::i2c::I2c;
+use crate::i2c::{I2c, RegisterDump};
 
-use crate::config::BatteryThreshold;
+use crate::battery::{
+    adc_counts, estimate_internal_resistance, Battery, BatteryEvent, ChargeCounter, ChargingTrend, CHARGING_WINDOW,
+};
+use crate::config::{BatteryThreshold, ChargingDetector};
 use crate::{
-    battery::{Battery, BatteryEvent},
-    I2C_ADDR_BAT,
+    convert_battery_voltage_to_level, curve_segment_for, gpio_detect_tap_with, gpio_hold_duration, gpio_push_tap,
+    open_i2c, ButtonStuckDetector, Error, Model, PiSugarConfig, Result, DOUBLE_TAP_MAX_GAP, TAP_HISTORY_LEN,
 };
-use crate::{convert_battery_voltage_to_level, gpio_detect_tap, Error, Model, PiSugarConfig, Result};
 
 /// Battery threshold curve
 pub const BATTERY_CURVE: [BatteryThreshold; 10] = [
@@ -27,34 +30,50 @@ pub const BATTERY_CURVE: [BatteryThreshold; 10] = [
 /// Idle intensity
 const PI_ZERO_IDLE_INTENSITY: f64 = 0.11;
 
+/// Registers in `Debug` output, system control, gpio, voltage and intensity ADC
+const IP5209_DEBUG_REGS: [u8; 7] = [0x01, 0x02, 0x55, 0xa2, 0xa3, 0xa4, 0xa5];
+
 /// IP5209, pi-zero bat chip
 pub struct IP5209 {
     i2c: I2c,
+    i2c_addr: u16,
+}
+
+impl fmt::Debug for IP5209 {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("IP5209")
+            .field("i2c_addr", &format_args!("{:#04x}", self.i2c_addr))
+            .finish()
+    }
 }
 
 impl IP5209 {
     /// Create new IP5209
     pub fn new(i2c_bus: u8, i2c_addr: u16) -> Result<Self> {
-        let mut i2c = I2c::with_bus(i2c_bus)?;
-        i2c.set_slave_address(i2c_addr)?;
-        Ok(Self { i2c })
+        let i2c = open_i2c(i2c_bus, i2c_addr)?;
+        Ok(Self { i2c, i2c_addr })
     }
 
-    /// Read voltage (V)
-    pub fn read_voltage(&self) -> Result<f64> {
+    /// Read `IP5209_DEBUG_REGS` one by one, hardware reads, keep it out of hot paths
+    pub fn debug_dump(&self) -> RegisterDump {
+        RegisterDump::read(&self.i2c, &IP5209_DEBUG_REGS)
+    }
+
+    /// Voltage (V) of ADC counts, 2.6V + 0.26855mV per count
+    pub fn voltage_from_raw(raw: i16) -> f64 {
+        (2600.0 + (raw as f64) * 0.26855) / 1000.0
+    }
+
+    /// Read voltage ADC counts, sign corrected
+    pub fn read_voltage_raw(&self) -> Result<i16> {
         let low = self.i2c.smbus_read_byte(0xa2)? as u16;
         let high = self.i2c.smbus_read_byte(0xa3)? as u16;
+        Ok(adc_counts(low, high))
+    }
 
-        // check negative values
-        let voltage = if high & 0x20 == 0x20 {
-            let v = (((high | 0b1100_0000) << 8) + low) as i16;
-            2600.0 - (v as f64) * 0.26855
-        } else {
-            let v = ((high & 0x1f) << 8) + low;
-            2600.0 + (v as f64) * 0.26855
-        };
-
-        Ok(voltage / 1000.0)
+    /// Read voltage (V)
+    pub fn read_voltage(&self) -> Result<f64> {
+        self.read_voltage_raw().map(Self::voltage_from_raw)
     }
 
     /// Parse level(%)
@@ -66,21 +85,21 @@ impl IP5209 {
         }
     }
 
-    /// Read intensity (A)
-    pub fn read_intensity(&self) -> Result<f64> {
+    /// Intensity (A) of ADC counts, 0.745985mA per count
+    pub fn intensity_from_raw(raw: i16) -> f64 {
+        (raw as f64) * 0.745985 / 1000.0
+    }
+
+    /// Read intensity ADC counts, sign corrected
+    pub fn read_intensity_raw(&self) -> Result<i16> {
         let low = self.i2c.smbus_read_byte(0xa4)? as u16;
         let high = self.i2c.smbus_read_byte(0xa5)? as u16;
+        Ok(adc_counts(low, high))
+    }
 
-        // check negative value
-        let intensity = if high & 0x20 == 0x20 {
-            let i = (((high | 0b1100_0000) << 8) + low) as i16;
-            (i as f64) * 0.745985
-        } else {
-            let i = ((high & 0x1f) << 8) + low;
-            (i as f64) * 0.745985
-        };
-
-        Ok(intensity / 1000.0)
+    /// Read intensity (A)
+    pub fn read_intensity(&self) -> Result<f64> {
+        self.read_intensity_raw().map(Self::intensity_from_raw)
     }
 
     /// Shutdown under light load (144mA and 8s)
@@ -213,13 +232,15 @@ impl IP5209 {
         Ok(())
     }
 
+    /// VBUS present bit, GPIO data register 0x55 bit4, VBUS is wired to GPIO4 in 2-led
+    pub fn parse_vbus_present(gpio: u8) -> bool {
+        gpio & 0b0001_0000 != 0
+    }
+
     /// Is power cable plugged in
     pub fn is_power_plugged_2led(&self) -> Result<bool> {
         let v = self.i2c.smbus_read_byte(0x55)?;
-        if v & 0b0001_0000 != 0 {
-            return Ok(true);
-        }
-        Ok(false)
+        Ok(Self::parse_vbus_present(v))
     }
 
     /// Read gpio tap 4:0, gpio4 / gpio1
@@ -248,22 +269,49 @@ pub struct IP5209Battery {
     voltages: VecDeque<(Instant, f32)>,
     levels: VecDeque<f32>,
     intensities: VecDeque<(Instant, f32)>,
+    charge: ChargeCounter,
+    charging_trend: ChargingTrend,
     tap_history: String,
+    button_stuck: ButtonStuckDetector,
     cfg: PiSugarConfig,
 }
 
 impl IP5209Battery {
     pub fn new(cfg: PiSugarConfig, model: Model) -> Result<Self> {
-        let ip5209 = IP5209::new(cfg.i2c_bus, cfg.i2c_addr.unwrap_or(I2C_ADDR_BAT))?;
-        Ok(Self {
+        let ip5209 = IP5209::new(cfg.i2c_bus, model.battery_i2c_addr(&cfg))?;
+        Ok(Self::with_chip(ip5209, cfg, model))
+    }
+
+    /// Battery of an opened chip
+    fn with_chip(ip5209: IP5209, cfg: PiSugarConfig, model: Model) -> Self {
+        Self {
             ip5209,
             model,
             voltages: VecDeque::with_capacity(30),
             intensities: VecDeque::with_capacity(30),
+            charge: ChargeCounter::default(),
+            charging_trend: ChargingTrend::new(cfg.charging_window.unwrap_or(CHARGING_WINDOW)),
             levels: VecDeque::with_capacity(30),
-            tap_history: String::with_capacity(30),
+            tap_history: String::with_capacity(TAP_HISTORY_LEN),
+            button_stuck: ButtonStuckDetector::default(),
             cfg,
-        })
+        }
+    }
+
+    /// Battery of an in-memory chip
+    #[cfg(all(test, feature = "mock-hardware"))]
+    pub(crate) fn mock(cfg: PiSugarConfig, model: Model) -> Self {
+        let ip5209 = IP5209 {
+            i2c: I2c::mock(),
+            i2c_addr: model.battery_i2c_addr(&cfg),
+        };
+        Self::with_chip(ip5209, cfg, model)
+    }
+
+    /// Set a register of the in-memory chip
+    #[cfg(all(test, feature = "mock-hardware"))]
+    pub(crate) fn set_register(&self, reg: u8, value: u8) {
+        self.ip5209.i2c.smbus_write_byte(reg, value).unwrap();
     }
 }
 
@@ -276,10 +324,10 @@ impl Battery for IP5209Battery {
             self.ip5209.init_gpio()?;
         }
         // NOTE: Disable auto shutdown in auto_power_on
-        if config.auto_power_on == Some(true) {
-            self.ip5209.disable_light_load_shutdown()?;
-        } else {
+        if config.chip_auto_shutdown_enabled() {
             self.ip5209.enable_light_load_auto_shutdown()?;
+        } else {
+            self.ip5209.disable_light_load_shutdown()?;
         }
 
         let v = self.voltage()?;
@@ -309,7 +357,7 @@ impl Battery for IP5209Battery {
     }
 
     fn voltage(&self) -> Result<f32> {
-        self.ip5209.read_voltage().map(|v| v as f32)
+        self.ip5209.read_voltage().map(|v| self.cfg.calibrate_voltage(v as f32))
     }
 
     fn voltage_avg(&self) -> Result<f32> {
@@ -323,13 +371,17 @@ impl Battery for IP5209Battery {
     }
 
     fn level(&self) -> Result<f32> {
-        let curve = self
-            .cfg
-            .battery_curve
-            .as_ref()
-            .map(|x| &x[..])
-            .unwrap_or(BATTERY_CURVE.as_ref());
-        self.voltage_avg().map(|x| IP5209::parse_voltage_level(x, curve))
+        self.voltage_avg().and_then(|x| self.voltage_level(x))
+    }
+
+    fn voltage_level(&self, voltage: f32) -> Result<f32> {
+        let curve = self.cfg.battery_curve(BATTERY_CURVE.as_ref());
+        Ok(IP5209::parse_voltage_level(voltage, curve))
+    }
+
+    fn curve_segment(&self) -> Result<Option<usize>> {
+        let curve = self.cfg.battery_curve(BATTERY_CURVE.as_ref());
+        self.voltage_avg().map(|v| curve_segment_for(v, curve))
     }
 
     fn intensity(&self) -> Result<f32> {
@@ -354,6 +406,15 @@ impl Battery for IP5209Battery {
         }
     }
 
+    fn external_power_present(&self) -> Result<bool> {
+        // 4-led has no VBUS input wired to gpio
+        if self.model.led_amount() == 2 {
+            self.ip5209.is_power_plugged_2led()
+        } else {
+            Err(Error::Other("Not available".to_string()))
+        }
+    }
+
     fn toggle_power_restore(&self, _enable: bool) -> Result<()> {
         Err(Error::Other("Not supported".to_string()))
     }
@@ -375,12 +436,10 @@ impl Battery for IP5209Battery {
     }
 
     fn is_charging(&self) -> Result<bool> {
-        if self.levels.len() > 2 {
-            if let Ok(avg) = self.voltage_avg() {
-                return Ok(self.voltages[0].1 < avg && avg < self.voltages[self.voltages.len() - 1].1);
-            }
+        if self.cfg.charging_detector == Some(ChargingDetector::Current) {
+            return self.is_charging_by_current();
         }
-        Ok(false)
+        Ok(self.charging_trend.is_charging())
     }
 
     fn is_input_protected(&self) -> Result<bool> {
@@ -402,13 +461,19 @@ impl Battery for IP5209Battery {
         Err(Error::Other("Not available".to_string()))
     }
 
-    fn poll(&mut self, now: Instant, _config: &PiSugarConfig) -> Result<Vec<BatteryEvent>> {
+    fn register_dump(&self) -> Option<RegisterDump> {
+        Some(self.ip5209.debug_dump())
+    }
+
+    fn refresh(&mut self, now: Instant) -> Result<()> {
         let voltage = self.voltage()?;
         if self.voltages.len() >= self.voltages.capacity() {
             self.voltages.pop_front();
         }
         self.voltages.push_back((now, voltage));
 
+        self.charging_trend.push(now, voltage);
+
         let level = self.level()?;
         if self.levels.len() >= self.levels.capacity() {
             self.levels.pop_front();
@@ -416,36 +481,60 @@ impl Battery for IP5209Battery {
         self.levels.push_back(level);
 
         let intensity = self.intensity()?;
+        self.charge.update(now, intensity);
         if self.intensities.len() >= self.intensities.capacity() {
             self.intensities.pop_front();
         }
         self.intensities.push_back((now, intensity));
 
+        Ok(())
+    }
+
+    fn poll(&mut self, now: Instant, config: &PiSugarConfig) -> Result<Vec<BatteryEvent>> {
+        self.refresh(now)?;
+
         let gpio_value = self.ip5209.read_gpio_tap()?;
-        let tapped = if self.model.led_amount() == 2 {
+        let gpio_high = if self.model.led_amount() == 2 {
             gpio_value & 0b0000_0010 != 0 // GPIO1 in 2-led
         } else {
             gpio_value & 0b0001_0000 != 0 // GPIO4 in 4-led
         };
+        let tapped = gpio_push_tap(&mut self.tap_history, gpio_high, config.invert_button == Some(true));
 
-        if self.tap_history.len() >= self.tap_history.capacity() {
-            self.tap_history.remove(0);
-        }
-        if tapped {
-            self.tap_history.push('1');
-        } else {
-            self.tap_history.push('0');
+        let mut events = Vec::new();
+        if self.button_stuck.update(tapped) {
+            events.push(BatteryEvent::ButtonStuck);
         }
 
-        let tap_result = gpio_detect_tap(&mut self.tap_history);
-
-        let mut events = Vec::new();
+        let tap_result = if self.button_stuck.is_stuck() {
+            self.tap_history.clear();
+            None
+        } else {
+            let double_tap_max_gap = config.double_tap_max_gap.unwrap_or(DOUBLE_TAP_MAX_GAP);
+            gpio_detect_tap_with(&mut self.tap_history, double_tap_max_gap, config.long_tap_polls())
+        };
         if let Some(tap_event) = tap_result {
             events.push(BatteryEvent::TapEvent(tap_event));
         }
         Ok(events)
     }
 
+    fn internal_resistance(&self) -> Option<f64> {
+        estimate_internal_resistance(&self.voltages, &self.intensities)
+    }
+
+    fn net_charge(&self) -> Option<f64> {
+        self.charge.intensity_updated_at().map(|_| self.charge.charge_ah())
+    }
+
+    fn button_hold_duration(&self) -> Option<Duration> {
+        gpio_hold_duration(&self.tap_history)
+    }
+
+    fn tap_history(&self) -> &str {
+        &self.tap_history
+    }
+
     fn toggle_light_load_shutdown(&self, enable: bool) -> Result<()> {
         if enable {
             self.ip5209.enable_light_load_auto_shutdown()
@@ -466,3 +555,118 @@ impl Battery for IP5209Battery {
         Ok(0.0)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::IP5209;
+    use crate::battery::adc_counts;
+    use crate::PiSugarConfig;
+
+    #[test]
+    fn test_chip_auto_shutdown() {
+        let mut config = PiSugarConfig::default();
+        assert!(config.chip_auto_shutdown_enabled());
+        config.auto_power_on = Some(true);
+        assert!(!config.chip_auto_shutdown_enabled());
+        config.auto_power_on = None;
+        config.enable_chip_auto_shutdown = Some(false);
+        assert!(!config.chip_auto_shutdown_enabled());
+
+        #[cfg(feature = "mock-hardware")]
+        {
+            use super::IP5209Battery;
+            use crate::battery::Battery;
+            use crate::Model;
+
+            let mut battery = IP5209Battery::mock(config.clone(), Model::PiSugar_2_4LEDs);
+            battery.set_register(0x02, 0b0000_0011);
+            battery.init(&config).unwrap();
+            assert_eq!(battery.ip5209.i2c.smbus_read_byte(0x02).unwrap() & 0b0000_0010, 0);
+
+            config.enable_chip_auto_shutdown = None;
+            battery.init(&config).unwrap();
+            assert_ne!(battery.ip5209.i2c.smbus_read_byte(0x02).unwrap() & 0b0000_0010, 0);
+        }
+    }
+
+    #[cfg(feature = "mock-hardware")]
+    #[test]
+    fn test_mock_hardware() {
+        use super::BATTERY_CURVE;
+        use crate::i2c::I2c;
+        use crate::{convert_battery_voltage_to_level, gpio_detect_tap, TapType, DOUBLE_TAP_MAX_GAP};
+
+        let ip5209 = IP5209 {
+            i2c: I2c::mock(),
+            i2c_addr: 0x75,
+        };
+
+        // 3000 counts, 3.4057V
+        ip5209.i2c.smbus_write_byte(0xa2, 0xb8).unwrap();
+        ip5209.i2c.smbus_write_byte(0xa3, 0x0b).unwrap();
+        let v = ip5209.read_voltage().unwrap() as f32;
+        assert!((v - (2.6 + 3000.0 * 0.00026855)).abs() < 1e-6);
+        let level = IP5209::parse_voltage_level(v, BATTERY_CURVE.as_ref());
+        assert_eq!(level, convert_battery_voltage_to_level(v, BATTERY_CURVE.as_ref()));
+        assert!(level > 0.0 && level < 100.0);
+
+        // 4-led tap on gpio4, pressed for 2 polls, then released longer than the double tap gap
+        let mut history = String::new();
+        for poll in 0..(3 + DOUBLE_TAP_MAX_GAP + 1) {
+            let gpio = if poll == 1 || poll == 2 { 0b0001_0000 } else { 0 };
+            ip5209.i2c.smbus_write_byte(0x55, gpio).unwrap();
+            let v = ip5209.read_gpio_tap().unwrap();
+            history.push(if v & 0b0001_0000 != 0 { '1' } else { '0' });
+        }
+        assert_eq!(gpio_detect_tap(&mut history), Some(TapType::Single));
+    }
+
+    #[cfg(feature = "mock-hardware")]
+    #[test]
+    fn test_debug_registers() {
+        use crate::i2c::I2c;
+
+        let ip5209 = IP5209 {
+            i2c: I2c::mock(),
+            i2c_addr: 0x75,
+        };
+        ip5209.i2c.smbus_write_byte(0xa2, 0xb8).unwrap();
+        ip5209.i2c.smbus_write_byte(0xa3, 0x0b).unwrap();
+        ip5209.i2c.smbus_write_byte(0x55, 0x10).unwrap();
+        assert_eq!(format!("{:?}", ip5209), "IP5209 { i2c_addr: 0x75 }");
+        assert_eq!(
+            format!("{:?}", ip5209.debug_dump()),
+            "{0x01: 0x00, 0x02: 0x00, 0x55: 0x10, 0xa2: 0xb8, 0xa3: 0x0b, 0xa4: 0x00, 0xa5: 0x00}"
+        );
+    }
+
+    #[test]
+    fn test_parse_vbus_present() {
+        assert!(IP5209::parse_vbus_present(0b0001_0000));
+        assert!(IP5209::parse_vbus_present(0b1111_1111));
+        assert!(!IP5209::parse_vbus_present(0b0000_0000));
+        assert!(!IP5209::parse_vbus_present(0b1110_1111));
+    }
+
+    #[test]
+    fn test_voltage_from_raw() {
+        let cases = [
+            ((0x00, 0x00), 2.6),
+            ((0xb8, 0x0b), 2.6 + 3000.0 * 0.00026855),
+            // below 2.6V
+            ((0xff, 0x3f), 2.6 - 0.00026855),
+            ((0x18, 0x3c), 2.6 - 1000.0 * 0.00026855),
+        ];
+        for ((low, high), volts) in cases.iter() {
+            let v = IP5209::voltage_from_raw(adc_counts(*low, *high));
+            assert!(
+                (v - volts).abs() < 1e-9,
+                "0x{:02x}{:02x}: {} != {}",
+                high,
+                low,
+                v,
+                volts
+            );
+        }
+    }
+}
@@ -1,15 +1,21 @@
 use std::collections::VecDeque;
-use std::time::Instant;
+use std::fmt;
+use std::time::{Duration, Instant};
 
-use rppal::i2c::I2c;
+use crate::i2c::{I2c, RegisterDump};
 
 use crate::Error;
 use crate::{
-    battery::{Battery, BatteryEvent},
-    config::BatteryThreshold,
+    battery::{
+        adc_counts, estimate_internal_resistance, Battery, BatteryEvent, ChargeCounter, ChargingTrend, CHARGING_WINDOW,
+    },
+    config::{BatteryThreshold, ChargingDetector},
+};
+use crate::{convert_battery_voltage_to_level, curve_segment_for, I2cError, Model, PiSugarConfig};
+use crate::{
+    gpio_detect_tap_with, gpio_hold_duration, gpio_push_tap, open_i2c, ButtonStuckDetector, Result, DOUBLE_TAP_MAX_GAP,
+    TAP_HISTORY_LEN,
 };
-use crate::{convert_battery_voltage_to_level, I2cError, Model, PiSugarConfig};
-use crate::{gpio_detect_tap, Result};
 
 /// Battery threshold curve
 pub const BATTERY_CURVE: [BatteryThreshold; 10] = [
@@ -28,21 +34,52 @@ pub const BATTERY_CURVE: [BatteryThreshold; 10] = [
 /// Idle intensity
 const PI_PRO_IDLE_INTENSITY: f64 = 0.2;
 
+/// Shutdown config, system control register 0x01
+const IP5312_SHUTDOWN_CONFIG_REG: u8 = 0x01;
+
+/// Shutdown config bit2, cleared by `force_shutdown` to arm force shutdown
+const IP5312_FORCE_SHUTDOWN_DISARMED: u8 = 0b0000_0100;
+
+/// Registers `force_shutdown` changes: system control 0x01 (bit2 force shutdown), 0x03 (bit5 light load
+/// shutdown), light load time 0x06, battery low 0x13 and light load threshold 0xc9
+const IP5312_SHUTDOWN_REGS: [u8; 5] = [IP5312_SHUTDOWN_CONFIG_REG, 0x03, 0x06, 0x13, 0xc9];
+
+/// Registers in `Debug` output, system control, gpio, voltage and intensity ADC, VBUS
+const IP5312_DEBUG_REGS: [u8; 7] = [0x01, 0x58, 0xd0, 0xd1, 0xd2, 0xd3, 0xdd];
+
 /// IP5312, pi-3/4 bat chip
 pub struct IP5312 {
     i2c: I2c,
+    i2c_addr: u16,
+}
+
+impl fmt::Debug for IP5312 {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("IP5312")
+            .field("i2c_addr", &format_args!("{:#04x}", self.i2c_addr))
+            .finish()
+    }
 }
 
 impl IP5312 {
     /// Create new IP5312
     pub fn new(i2c_bus: u8, i2c_addr: u16) -> Result<Self> {
-        let mut i2c = I2c::with_bus(i2c_bus)?;
-        i2c.set_slave_address(i2c_addr)?;
-        Ok(Self { i2c })
+        let i2c = open_i2c(i2c_bus, i2c_addr)?;
+        Ok(Self { i2c, i2c_addr })
     }
 
-    /// Read voltage (V)
-    pub fn read_voltage(&self) -> Result<f64> {
+    /// Read `IP5312_DEBUG_REGS` one by one, hardware reads, keep it out of hot paths
+    pub fn debug_dump(&self) -> RegisterDump {
+        RegisterDump::read(&self.i2c, &IP5312_DEBUG_REGS)
+    }
+
+    /// Voltage (V) of ADC counts, 2.6V + 0.26855mV per count
+    pub fn voltage_from_raw(raw: i16) -> f64 {
+        ((raw as f64) * 0.26855 + 2600.0) / 1000.0
+    }
+
+    /// Read voltage ADC counts, 14-bit unsigned
+    pub fn read_voltage_raw(&self) -> Result<i16> {
         let low = self.i2c.smbus_read_byte(0xd0)? as u16;
         let high = self.i2c.smbus_read_byte(0xd1)? as u16;
 
@@ -50,9 +87,12 @@ impl IP5312 {
             return Err(Error::I2c(I2cError::FeatureNotSupported));
         }
 
-        let v = ((high & 0b0011_1111) << 8) + low;
-        let v = (v as f64) * 0.26855 + 2600.0;
-        Ok(v / 1000.0)
+        Ok((((high & 0b0011_1111) << 8) + low) as i16)
+    }
+
+    /// Read voltage (V)
+    pub fn read_voltage(&self) -> Result<f64> {
+        self.read_voltage_raw().map(Self::voltage_from_raw)
     }
 
     /// Parse level(%)
@@ -64,19 +104,21 @@ impl IP5312 {
         }
     }
 
-    /// Read intensity (A)
-    pub fn read_intensity(&self) -> Result<f64> {
+    /// Intensity (A) of ADC counts, 2.68554mA per count
+    pub fn intensity_from_raw(raw: i16) -> f64 {
+        (raw as f64) * 2.68554 / 1000.0
+    }
+
+    /// Read intensity ADC counts, sign corrected
+    pub fn read_intensity_raw(&self) -> Result<i16> {
         let low = self.i2c.smbus_read_byte(0xd2)? as u16;
         let high = self.i2c.smbus_read_byte(0xd3)? as u16;
+        Ok(adc_counts(low, high))
+    }
 
-        let intensity = if high & 0x20 != 0 {
-            let i = (((high | 0b1100_0000) << 8) + low) as i16;
-            (i as f64) * 2.68554
-        } else {
-            let i = ((high & 0x1f) << 8) + low;
-            (i as f64) * 2.68554
-        };
-        Ok(intensity / 1000.0)
+    /// Read intensity (A)
+    pub fn read_intensity(&self) -> Result<f64> {
+        self.read_intensity_raw().map(Self::intensity_from_raw)
     }
 
     /// Shutdown under light load (126mA and 8s)
@@ -197,13 +239,15 @@ impl IP5312 {
         Ok(())
     }
 
+    /// VBUS present, gpio state register 0xdd reads 0x1f with VBUS wired in 2-led
+    pub fn parse_vbus_present(v: u8) -> bool {
+        v == 0x1f
+    }
+
     /// Is power cable plugged in, 2-led
     pub fn is_power_plugged_2led(&self) -> Result<bool> {
         let high = self.i2c.smbus_read_byte(0xdd)?;
-        if high == 0x1f {
-            return Ok(true);
-        }
-        Ok(false)
+        Ok(Self::parse_vbus_present(high))
     }
 
     /// Init boost intensity, 0x3f*50ma, 3A
@@ -230,12 +274,34 @@ impl IP5312 {
         self.enable_light_load_auto_shutdown()?;
 
         // enable force shutdown
-        let mut t = self.i2c.smbus_read_byte(0x01)?;
-        t &= 0b1111_1011;
-        self.i2c.smbus_write_byte(0x01, t)?;
+        let mut t = self.i2c.smbus_read_byte(IP5312_SHUTDOWN_CONFIG_REG)?;
+        t &= !IP5312_FORCE_SHUTDOWN_DISARMED;
+        self.i2c.smbus_write_byte(IP5312_SHUTDOWN_CONFIG_REG, t)?;
 
         Ok(())
     }
+
+    /// Read the registers `force_shutdown` changes, see `IP5312_SHUTDOWN_REGS`
+    pub fn read_shutdown_config(&self) -> Result<Vec<(u8, u8)>> {
+        IP5312_SHUTDOWN_REGS
+            .iter()
+            .map(|reg| Ok((*reg, self.i2c.smbus_read_byte(*reg)?)))
+            .collect()
+    }
+
+    /// Write back registers read by `read_shutdown_config`, only the ones that changed, returns true if any did
+    ///
+    /// Registers other than `IP5312_SHUTDOWN_REGS` are ignored
+    pub fn restore_shutdown_config(&self, regs: &[(u8, u8)]) -> Result<bool> {
+        let mut changed = false;
+        for (reg, v) in regs.iter().filter(|(reg, _)| IP5312_SHUTDOWN_REGS.contains(reg)) {
+            if self.i2c.smbus_read_byte(*reg)? != *v {
+                self.i2c.smbus_write_byte(*reg, *v)?;
+                changed = true;
+            }
+        }
+        Ok(changed)
+    }
 }
 
 pub struct IP5312Battery {
@@ -243,21 +309,27 @@ pub struct IP5312Battery {
     model: Model,
     voltages: VecDeque<(Instant, f32)>,
     intensities: VecDeque<(Instant, f32)>,
+    charge: ChargeCounter,
+    charging_trend: ChargingTrend,
     levels: VecDeque<f32>,
     tap_history: String,
+    button_stuck: ButtonStuckDetector,
     cfg: PiSugarConfig,
 }
 
 impl IP5312Battery {
     pub fn new(cfg: PiSugarConfig, model: Model) -> Result<Self> {
-        let ip5312 = IP5312::new(cfg.i2c_bus, cfg.i2c_addr.unwrap_or(model.default_battery_i2c_addr()))?;
+        let ip5312 = IP5312::new(cfg.i2c_bus, model.battery_i2c_addr(&cfg))?;
         Ok(Self {
             ip5312,
             model,
             voltages: VecDeque::with_capacity(30),
             intensities: VecDeque::with_capacity(30),
+            charge: ChargeCounter::default(),
+            charging_trend: ChargingTrend::new(cfg.charging_window.unwrap_or(CHARGING_WINDOW)),
             levels: VecDeque::with_capacity(30),
-            tap_history: String::with_capacity(30),
+            tap_history: String::with_capacity(TAP_HISTORY_LEN),
+            button_stuck: ButtonStuckDetector::default(),
             cfg,
         })
     }
@@ -273,10 +345,10 @@ impl Battery for IP5312Battery {
         }
         self.ip5312.init_boost_intensity()?;
         // NOTE: Disable auto shutdown in auto_power_on
-        if config.auto_power_on == Some(true) {
-            self.ip5312.disable_light_load_shutdown()?;
-        } else {
+        if config.chip_auto_shutdown_enabled() {
             self.ip5312.enable_light_load_auto_shutdown()?;
+        } else {
+            self.ip5312.disable_light_load_shutdown()?;
         }
 
         let v = self.voltage()?;
@@ -306,7 +378,7 @@ impl Battery for IP5312Battery {
     }
 
     fn voltage(&self) -> Result<f32> {
-        self.ip5312.read_voltage().map(|v| v as f32)
+        self.ip5312.read_voltage().map(|v| self.cfg.calibrate_voltage(v as f32))
     }
 
     fn voltage_avg(&self) -> Result<f32> {
@@ -320,13 +392,17 @@ impl Battery for IP5312Battery {
     }
 
     fn level(&self) -> Result<f32> {
-        let curve = self
-            .cfg
-            .battery_curve
-            .as_ref()
-            .map(|x| &x[..])
-            .unwrap_or(BATTERY_CURVE.as_ref());
-        self.voltage_avg().map(|x| IP5312::parse_voltage_level(x, curve))
+        self.voltage_avg().and_then(|x| self.voltage_level(x))
+    }
+
+    fn voltage_level(&self, voltage: f32) -> Result<f32> {
+        let curve = self.cfg.battery_curve(BATTERY_CURVE.as_ref());
+        Ok(IP5312::parse_voltage_level(voltage, curve))
+    }
+
+    fn curve_segment(&self) -> Result<Option<usize>> {
+        let curve = self.cfg.battery_curve(BATTERY_CURVE.as_ref());
+        self.voltage_avg().map(|v| curve_segment_for(v, curve))
     }
 
     fn intensity(&self) -> Result<f32> {
@@ -351,6 +427,15 @@ impl Battery for IP5312Battery {
         }
     }
 
+    fn external_power_present(&self) -> Result<bool> {
+        // 4-led has no VBUS input wired to gpio
+        if self.model.led_amount() == 2 {
+            self.ip5312.is_power_plugged_2led()
+        } else {
+            Err(Error::Other("Not available".to_string()))
+        }
+    }
+
     fn toggle_power_restore(&self, _enable: bool) -> Result<()> {
         Err(Error::Other("Not supported".to_string()))
     }
@@ -372,12 +457,10 @@ impl Battery for IP5312Battery {
     }
 
     fn is_charging(&self) -> Result<bool> {
-        if self.levels.len() > 2 {
-            if let Ok(avg) = self.voltage_avg() {
-                return Ok(self.voltages[0].1 < avg && avg < self.voltages[self.voltages.len() - 1].1);
-            }
+        if self.cfg.charging_detector == Some(ChargingDetector::Current) {
+            return self.is_charging_by_current();
         }
-        Ok(false)
+        Ok(self.charging_trend.is_charging())
     }
 
     fn is_input_protected(&self) -> Result<bool> {
@@ -399,13 +482,27 @@ impl Battery for IP5312Battery {
         Err(Error::Other("Not available".to_string()))
     }
 
-    fn poll(&mut self, now: Instant, _config: &PiSugarConfig) -> Result<Vec<BatteryEvent>> {
+    fn read_shutdown_config(&self) -> Result<Vec<(u8, u8)>> {
+        self.ip5312.read_shutdown_config()
+    }
+
+    fn restore_shutdown_config(&self, regs: &[(u8, u8)]) -> Result<bool> {
+        self.ip5312.restore_shutdown_config(regs)
+    }
+
+    fn register_dump(&self) -> Option<RegisterDump> {
+        Some(self.ip5312.debug_dump())
+    }
+
+    fn refresh(&mut self, now: Instant) -> Result<()> {
         let voltage = self.voltage()?;
         self.voltages.pop_front();
         while self.voltages.len() < self.voltages.capacity() {
             self.voltages.push_back((now, voltage));
         }
 
+        self.charging_trend.push(now, voltage);
+
         let level = self.level()?;
         self.levels.pop_front();
         while self.levels.len() < self.levels.capacity() {
@@ -413,31 +510,59 @@ impl Battery for IP5312Battery {
         }
 
         let intensity = self.intensity()?;
+        self.charge.update(now, intensity);
         self.intensities.pop_front();
         while self.intensities.len() < self.intensities.capacity() {
             self.intensities.push_back((now, intensity));
         }
 
-        let gpio_value = self.ip5312.read_gpio_tap()?;
-        let tapped = gpio_value != 0;
-        if self.tap_history.len() >= self.tap_history.capacity() {
-            self.tap_history.remove(0);
-        }
-        if tapped {
-            self.tap_history.push('1');
-        } else {
-            self.tap_history.push('0');
-        }
+        Ok(())
+    }
 
-        let tap_result = gpio_detect_tap(&mut self.tap_history);
+    fn poll(&mut self, now: Instant, config: &PiSugarConfig) -> Result<Vec<BatteryEvent>> {
+        self.refresh(now)?;
+
+        let gpio_value = self.ip5312.read_gpio_tap()?;
+        let tapped = gpio_push_tap(
+            &mut self.tap_history,
+            gpio_value != 0,
+            config.invert_button == Some(true),
+        );
 
         let mut events = Vec::new();
+        if self.button_stuck.update(tapped) {
+            events.push(BatteryEvent::ButtonStuck);
+        }
+
+        let tap_result = if self.button_stuck.is_stuck() {
+            self.tap_history.clear();
+            None
+        } else {
+            let double_tap_max_gap = config.double_tap_max_gap.unwrap_or(DOUBLE_TAP_MAX_GAP);
+            gpio_detect_tap_with(&mut self.tap_history, double_tap_max_gap, config.long_tap_polls())
+        };
         if let Some(tap_event) = tap_result {
             events.push(BatteryEvent::TapEvent(tap_event));
         }
         Ok(events)
     }
 
+    fn internal_resistance(&self) -> Option<f64> {
+        estimate_internal_resistance(&self.voltages, &self.intensities)
+    }
+
+    fn net_charge(&self) -> Option<f64> {
+        self.charge.intensity_updated_at().map(|_| self.charge.charge_ah())
+    }
+
+    fn button_hold_duration(&self) -> Option<Duration> {
+        gpio_hold_duration(&self.tap_history)
+    }
+
+    fn tap_history(&self) -> &str {
+        &self.tap_history
+    }
+
     fn toggle_light_load_shutdown(&self, enable: bool) -> Result<()> {
         if enable {
             self.ip5312.enable_light_load_auto_shutdown()
@@ -458,3 +583,74 @@ impl Battery for IP5312Battery {
         Ok(0.0)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::IP5312;
+    use crate::battery::adc_counts;
+
+    #[cfg(feature = "mock-hardware")]
+    #[test]
+    fn test_restore_shutdown_config() {
+        use crate::i2c::I2c;
+
+        let ip5312 = IP5312 {
+            i2c: I2c::mock(),
+            i2c_addr: 0x75,
+        };
+        // force shutdown off, light load shutdown off, other bits set
+        for (reg, v) in [
+            (0x01, 0b1010_0110),
+            (0x03, 0b0100_0001),
+            (0x06, 0b1100_0011),
+            (0x13, 0b1110_0001),
+        ] {
+            ip5312.i2c.smbus_write_byte(reg, v).unwrap();
+        }
+        ip5312.i2c.smbus_write_byte(0xc9, 0b1000_0000).unwrap();
+        let saved = ip5312.read_shutdown_config().unwrap();
+        assert_eq!(saved.len(), 5);
+        assert!(!ip5312.restore_shutdown_config(&saved).unwrap());
+
+        // interrupted force shutdown, only the shutdown bits change
+        ip5312.force_shutdown().unwrap();
+        let read = |reg| ip5312.i2c.smbus_read_byte(reg).unwrap();
+        assert_eq!(read(0x01), 0b1010_0010);
+        assert_eq!(read(0x03), 0b0110_0001);
+        assert_eq!(read(0x06), 0b0000_0011);
+        assert_eq!(read(0x13), 0b1101_0001);
+        assert_eq!(read(0xc9), 0b1000_0000 | 46);
+
+        assert!(ip5312.restore_shutdown_config(&saved).unwrap());
+        assert_eq!(ip5312.read_shutdown_config().unwrap(), saved);
+
+        // other registers in a tampered state file are left alone
+        assert!(!ip5312.restore_shutdown_config(&[(0x30, 0xff)]).unwrap());
+        assert_eq!(read(0x30), 0);
+    }
+
+    #[test]
+    fn test_parse_vbus_present() {
+        assert!(IP5312::parse_vbus_present(0x1f));
+        assert!(!IP5312::parse_vbus_present(0x00));
+        assert!(!IP5312::parse_vbus_present(0x0f));
+    }
+
+    #[test]
+    fn test_intensity_from_raw() {
+        let cases = [
+            ((0x00, 0x00), 0.0),
+            ((0x01, 0x00), 0.00268554),
+            ((0xe8, 0x03), 2.68554),
+            ((0xff, 0x1f), 8191.0 * 0.00268554),
+            // negative, discharging
+            ((0xff, 0x3f), -0.00268554),
+            ((0x18, 0x3c), -2.68554),
+            ((0x00, 0x20), -8192.0 * 0.00268554),
+        ];
+        for ((low, high), amps) in cases.iter() {
+            let i = IP5312::intensity_from_raw(adc_counts(*low, *high));
+            assert!((i - amps).abs() < 1e-9, "0x{:02x}{:02x}: {} != {}", high, low, i, amps);
+        }
+    }
+}
@@ -0,0 +1,94 @@
+use std::io;
+use std::os::unix::net::UnixDatagram;
+
+use crate::Event;
+
+/// Journald native protocol socket
+pub const JOURNAL_SOCKET: &str = "/run/systemd/journal/socket";
+
+/// Syslog identifier of journal entries
+pub const SYSLOG_IDENTIFIER: &str = "pisugar-server";
+
+/// Journal priorities, same as syslog
+pub const PRIORITY_WARNING: u8 = 4;
+pub const PRIORITY_NOTICE: u8 = 5;
+pub const PRIORITY_INFO: u8 = 6;
+
+/// Journal priority of event
+pub fn event_priority(event: &Event) -> u8 {
+    match event {
+        Event::Tap(_) | Event::Detected => PRIORITY_INFO,
+        Event::OverVoltage | Event::UnderVoltage | Event::ButtonStuck | Event::PowerFlapping => PRIORITY_WARNING,
+    }
+}
+
+/// Journal fields, `PISUGAR_EVENT` is the event name
+pub fn fields(event: &str, priority: u8, message: &str) -> Vec<(&'static str, String)> {
+    vec![
+        ("MESSAGE", message.to_string()),
+        ("PRIORITY", priority.to_string()),
+        ("SYSLOG_IDENTIFIER", SYSLOG_IDENTIFIER.to_string()),
+        ("PISUGAR_EVENT", event.to_string()),
+    ]
+}
+
+/// Journal fields of event
+pub fn event_fields(event: &Event) -> Vec<(&'static str, String)> {
+    let message = format!("PiSugar event: {}", event);
+    fields(&event.to_string(), event_priority(event), &message)
+}
+
+/// Encode fields in journald native protocol, multi-line values are length prefixed
+pub fn encode(fields: &[(&str, String)]) -> Vec<u8> {
+    let mut buf = Vec::new();
+    for (key, value) in fields {
+        buf.extend_from_slice(key.as_bytes());
+        if value.contains('\n') {
+            buf.push(b'\n');
+            buf.extend_from_slice(&(value.len() as u64).to_le_bytes());
+        } else {
+            buf.push(b'=');
+        }
+        buf.extend_from_slice(value.as_bytes());
+        buf.push(b'\n');
+    }
+    buf
+}
+
+/// Write an entry to journald
+pub fn send(event: &str, priority: u8, message: &str) -> io::Result<()> {
+    let socket = UnixDatagram::unbound()?;
+    socket.send_to(&encode(&fields(event, priority, message)), JOURNAL_SOCKET)?;
+    Ok(())
+}
+
+/// Write event to journald
+pub fn send_event(event: &Event) -> io::Result<()> {
+    let socket = UnixDatagram::unbound()?;
+    socket.send_to(&encode(&event_fields(event)), JOURNAL_SOCKET)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{encode, event_fields, PRIORITY_INFO, PRIORITY_WARNING};
+    use crate::{Event, TapType};
+
+    #[test]
+    fn test_event_fields() {
+        let fields = event_fields(&Event::UnderVoltage);
+        assert!(fields.contains(&("PISUGAR_EVENT", "under_voltage".to_string())));
+        assert!(fields.contains(&("PRIORITY", PRIORITY_WARNING.to_string())));
+
+        let fields = event_fields(&Event::Tap(TapType::Double));
+        assert!(fields.contains(&("PISUGAR_EVENT", "double".to_string())));
+        assert!(fields.contains(&("PRIORITY", PRIORITY_INFO.to_string())));
+        assert!(fields.contains(&("MESSAGE", "PiSugar event: double".to_string())));
+
+        let buf = encode(&[("PISUGAR_EVENT", "double".to_string()), ("MESSAGE", "a\nb".to_string())]);
+        let mut expected = b"PISUGAR_EVENT=double\nMESSAGE\n".to_vec();
+        expected.extend_from_slice(&3_u64.to_le_bytes());
+        expected.extend_from_slice(b"a\nb\n");
+        assert_eq!(buf, expected);
+    }
+}
@@ -1,35 +1,69 @@
+use std::collections::HashMap;
 use std::convert::{From, TryInto};
 use std::fmt;
 use std::fmt::{Display, Formatter};
 
 use std::io;
 use std::io::Write;
+use std::os::unix::process::CommandExt;
 use std::path::{Path, PathBuf};
 use std::process::{Command, ExitStatus};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
 use std::thread;
 use std::time::{Duration, Instant};
 
 use battery::BatteryEvent;
-use chrono::{DateTime, Datelike, Local, Timelike};
-pub use config::{BatteryThreshold, PiSugarConfig};
+use chrono::{DateTime, Datelike, Local, Timelike, Utc};
+pub use config::{
+    BatteryChemistry, BatteryThreshold, ChargingDetector, ConfigError, ConfigPatch, PiSugarConfig, ScriptOverlap,
+    ShutdownMode,
+};
 use hyper::client::Client;
-use rppal::i2c::Error as I2cError;
+use tokio::sync::broadcast;
 
+pub use boot_history::{BootHistory, BOOT_ID_PATH, DEFAULT_POWER_FLAPPING_INTERVAL, POWER_FLAPPING_BOOTS};
+pub use log_limit::LogRateLimiter;
 pub use model::Model;
+pub use script_runner::{ScriptRunner, SCRIPT_QUEUE_CAPACITY};
 pub use sd3078::*;
+pub use shared::SharedCore;
+pub use status::{Board, CoreInfo, Health, HealthStatus, ShutdownState, StatusSnapshot};
+pub use wake_queue::{check_wake_time, WakeQueue, WAKE_QUEUE_CAPACITY};
 
-use crate::battery::Battery;
-pub use crate::rtc::RTCRawTime;
+use crate::battery::{load_compensated_voltage, standby_days, Battery};
+pub use crate::clock::{Clock, SystemClock};
+use crate::csv_log::{CsvLog, CSV_LOG_MAX_SIZE};
+/// In-memory i2c, plug a fake board with `MockI2c::plug_bus` in tests of dependent crates
+#[cfg(feature = "mock-hardware")]
+pub use crate::i2c::I2c as MockI2c;
+use crate::i2c::{Error as I2cError, I2c};
+use crate::power_supply::{PowerSupplyExport, PowerSupplyStatus};
 use crate::rtc::RTC;
+pub use crate::rtc::{RTCRawTime, WeekdayMask};
+use crate::state::CoreState;
 
 mod battery;
+mod boot_history;
+mod clock;
 mod config;
+mod csv_log;
+mod i2c;
 mod ip5209;
 mod ip5312;
+#[cfg(feature = "journald")]
+pub mod journald;
+mod log_limit;
 mod model;
 mod pisugar3;
+mod power_supply;
 mod rtc;
+mod script_runner;
 mod sd3078;
+mod shared;
+mod state;
+mod status;
+mod wake_queue;
 
 /// Time host
 pub const TIME_HOST: &str = "http://cdn.pisugar.com";
@@ -40,22 +74,131 @@ pub const RTC_TIME: &str = "rtc.time";
 /// I2c poll interval, no more than 1s
 pub const I2C_READ_INTERVAL: std::time::Duration = std::time::Duration::from_millis(100);
 
+/// Config fields redacted in the diagnostic bundle, besides all `*_shell` commands
+pub const DIAGNOSTIC_REDACTED_FIELDS: [&str; 2] = ["auth_user", "auth_password"];
+
+/// Placeholder of a redacted config field
+pub const DIAGNOSTIC_REDACTED: &str = "<redacted>";
+
 /// RTC address, SD3078
 const I2C_ADDR_RTC: u16 = 0x32;
 
 /// Battery address, IP5209/IP5312
 const I2C_ADDR_BAT: u16 = 0x75;
 
+/// Consecutive remote I/O errors before re-init chips
+const REMOTE_IO_ERROR_THRESHOLD: u32 = 5;
+
+/// Startup probe interval
+const STARTUP_PROBE_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Default rtc_poll_divisor, polls, 1min
+const DEFAULT_RTC_POLL_DIVISOR: u32 = 600;
+
+/// Default redetect_interval, polls
+const DEFAULT_REDETECT_INTERVAL: u32 = 10;
+
+/// Status broadcast capacity, lagging subscribers miss older snapshots
+const STATUS_BROADCAST_CAPACITY: usize = 16;
+
 /// Battery full charge 5min after full, 5min, should be adjust as needed
 const BAT_FULL_CHARGE_DURATION: u64 = 5 * 60;
 
+/// Battery level of fully charged
+const BAT_FULL_LEVEL: f32 = 99.9;
+
+/// Battery level that rearms charge full script
+const BAT_FULL_REARM_LEVEL: f32 = 95.0;
+
+/// Interval to check a script with timeout has exited
+const SHELL_WAIT_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Battery cycles are saved to state file every 0.1 cycle
+const CYCLES_SAVE_STEP: f64 = 0.1;
+
+/// Default critical_timeout, seconds
+const DEFAULT_CRITICAL_TIMEOUT: u64 = 10;
+
 /// PiSugar error
 #[derive(Debug)]
 pub enum Error {
     I2c(I2cError),
+    /// I2C bus could not be opened, e.g. I2C interface not enabled
+    I2cUnavailable(u8, I2cError),
+    /// Config file could not be loaded
+    Config(ConfigError),
     Other(String),
 }
 
+/// errno, no such device or address
+const ENXIO: i32 = 6;
+
+/// errno, remote I/O error
+const EREMOTEIO: i32 = 121;
+
+/// I2C device not responding, i.e. no ack from the slave address
+fn is_i2c_not_responding(e: &I2cError) -> bool {
+    match e {
+        I2cError::Io(e) => matches!(e.raw_os_error(), Some(ENXIO) | Some(EREMOTEIO)),
+        _ => false,
+    }
+}
+
+/// Max attempts of i2c operation interrupted by signal
+const I2C_INTERRUPTED_ATTEMPTS: usize = 3;
+
+/// I2C operation interrupted by signal, i.e. EINTR
+fn is_i2c_interrupted(e: &I2cError) -> bool {
+    matches!(e, I2cError::Io(e) if e.kind() == io::ErrorKind::Interrupted)
+}
+
+/// Retry i2c operation interrupted by signal, other errors return immediately
+fn retry_i2c<T, F>(mut op: F) -> Result<T>
+where
+    F: FnMut() -> std::result::Result<T, I2cError>,
+{
+    let mut attempts = 1;
+    loop {
+        match op() {
+            Err(e) if is_i2c_interrupted(&e) && attempts < I2C_INTERRUPTED_ATTEMPTS => {
+                log::debug!("I2C interrupted, retry: {}", e);
+                attempts += 1;
+            }
+            r => return r.map_err(Error::from),
+        }
+    }
+}
+
+/// Remote I/O error, chip dropped off the bus
+fn is_i2c_remote_io(e: &I2cError) -> bool {
+    matches!(e, I2cError::Io(e) if e.raw_os_error() == Some(EREMOTEIO))
+}
+
+impl Error {
+    /// Is remote I/O error, chip dropped off the bus
+    pub fn is_remote_io(&self) -> bool {
+        matches!(self, Error::I2c(e) if is_i2c_remote_io(e))
+    }
+
+    /// Stable machine-readable code, unlike the Display message
+    pub fn code(&self) -> &'static str {
+        match self {
+            Error::I2c(e) if is_i2c_not_responding(e) => "i2c_not_responding",
+            Error::I2c(_) => "i2c",
+            Error::I2cUnavailable(..) => "i2c_unavailable",
+            Error::Config(e) => e.code(),
+            Error::Other(_) => "other",
+        }
+    }
+}
+
+/// Open i2c bus and set slave address
+fn open_i2c(i2c_bus: u8, i2c_addr: u16) -> Result<I2c> {
+    let mut i2c = I2c::with_bus(i2c_bus).map_err(|e| Error::I2cUnavailable(i2c_bus, e))?;
+    i2c.set_slave_address(i2c_addr)?;
+    Ok(i2c)
+}
+
 /// Wrap I2cError
 impl From<I2cError> for Error {
     fn from(e: I2cError) -> Self {
@@ -63,6 +206,12 @@ impl From<I2cError> for Error {
     }
 }
 
+impl From<ConfigError> for Error {
+    fn from(e: ConfigError) -> Self {
+        Error::Config(e)
+    }
+}
+
 impl From<String> for Error {
     fn from(e: String) -> Self {
         Error::Other(e)
@@ -78,7 +227,16 @@ impl From<io::Error> for Error {
 impl Display for Error {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
         match self {
+            Error::I2c(e) if is_i2c_not_responding(e) => {
+                write!(f, "PiSugar not responding on I2C bus, check the connection: {}", e)
+            }
             Error::I2c(e) => write!(f, "{}", e),
+            Error::I2cUnavailable(bus, e) => write!(
+                f,
+                "I2C interface /dev/i2c-{} not available ({}), enable I2C with raspi-config",
+                bus, e
+            ),
+            Error::Config(e) => write!(f, "{}", e),
             Error::Other(e) => write!(f, "{}", e),
         }
     }
@@ -106,6 +264,12 @@ fn convert_battery_voltage_to_level(voltage: f32, battery_curve: &[BatteryThresh
     0.0
 }
 
+/// Index of the curve point the voltage is interpolated down from, level is between point `i` and `i - 1`,
+/// 0 is above the top point, None below the lowest point
+pub fn curve_segment_for(voltage: f32, battery_curve: &[BatteryThreshold]) -> Option<usize> {
+    battery_curve.iter().position(|(v_low, _)| voltage >= *v_low)
+}
+
 /// Write time to system
 pub fn sys_write_time(dt: DateTime<Local>) {
     let cmd = format!(
@@ -129,8 +293,15 @@ pub fn sys_write_time(dt: DateTime<Local>) {
     }
 }
 
+/// Time from the Date header of `TIME_HOST`, None if unreachable
+pub async fn fetch_network_time() -> Option<DateTime<Local>> {
+    let resp = Client::new().get(TIME_HOST.parse().unwrap()).await.ok()?;
+    let date = resp.headers().get("Date")?.to_str().ok()?;
+    DateTime::parse_from_rfc2822(date).ok().map(|dt| dt.into())
+}
+
 /// Button tap type
-#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+#[derive(Debug, PartialEq, Eq, Hash, Copy, Clone)]
 pub enum TapType {
     Single,
     Double,
@@ -148,25 +319,132 @@ impl Display for TapType {
     }
 }
 
+/// Boot reason
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum BootReason {
+    /// Woken up by rtc alarm
+    AlarmWake,
+    /// Powered on manually
+    ManualPowerOn,
+    /// Rtc alarm flag could not be read, e.g. no rtc
+    Unknown,
+}
+
+impl From<bool> for BootReason {
+    /// From rtc alarm flag
+    fn from(alarm_flag: bool) -> Self {
+        if alarm_flag {
+            BootReason::AlarmWake
+        } else {
+            BootReason::ManualPowerOn
+        }
+    }
+}
+
+impl Display for BootReason {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let s = match self {
+            BootReason::AlarmWake => "alarm_wake",
+            BootReason::ManualPowerOn => "manual_power_on",
+            BootReason::Unknown => "unknown",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+/// PiSugar event, more may be added
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+#[non_exhaustive]
+pub enum Event {
+    /// Button tapped
+    Tap(TapType),
+    /// Battery voltage above `over_voltage`
+    OverVoltage,
+    /// Battery voltage below `under_voltage`
+    UnderVoltage,
+    /// Button always pressed, taps are suppressed until released
+    ButtonStuck,
+    /// PiSugar found after startup, e.g. plugged in later
+    Detected,
+    /// Rapid successive boots, power is unstable
+    PowerFlapping,
+}
+
+impl Display for Event {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Event::Tap(tap_type) => write!(f, "{}", tap_type),
+            Event::OverVoltage => write!(f, "over_voltage"),
+            Event::UnderVoltage => write!(f, "under_voltage"),
+            Event::ButtonStuck => write!(f, "button_stuck"),
+            Event::Detected => write!(f, "detected"),
+            Event::PowerFlapping => write!(f, "power_flapping"),
+        }
+    }
+}
+
+/// Default max gap between two taps of double tap, in polls
+pub const DOUBLE_TAP_MAX_GAP: usize = 2;
+
+/// Double tap patterns, gaps of 1..=max_gap and second press of 1..=3 polls
+fn double_tap_patterns(max_gap: usize) -> Vec<String> {
+    let mut patterns = Vec::new();
+    for gap in 1..=max_gap.max(1) {
+        for press in 1..=3 {
+            patterns.push(format!("1{}{}0", "0".repeat(gap), "1".repeat(press)));
+        }
+    }
+    patterns
+}
+
+/// Tap history length, in polls
+pub const TAP_HISTORY_LEN: usize = 30;
+
+/// Default long tap hold, in polls
+pub const LONG_TAP_POLLS: usize = 8;
+
+/// Polls to hold the button for a long tap of `duration`, rounded up
+///
+/// NOTE: capped by the history length, a longer hold would never be detected
+pub fn long_tap_polls(duration: Duration, poll_interval: Duration) -> usize {
+    let polls = duration.as_nanos().div_ceil(poll_interval.as_nanos().max(1));
+    (polls as usize).clamp(1, TAP_HISTORY_LEN - 1)
+}
+
 /// Detect button tap
 pub fn gpio_detect_tap(gpio_history: &mut String) -> Option<TapType> {
-    let long_pattern = "111111110";
-    let double_pattern = vec!["1010", "10010", "10110", "100110", "101110", "1001110"];
-    let single_pattern = "1000";
+    gpio_detect_tap_with_gap(gpio_history, DOUBLE_TAP_MAX_GAP)
+}
+
+/// Detect button tap, double tap allows gaps up to `double_tap_max_gap` polls
+pub fn gpio_detect_tap_with_gap(gpio_history: &mut String, double_tap_max_gap: usize) -> Option<TapType> {
+    gpio_detect_tap_with(gpio_history, double_tap_max_gap, LONG_TAP_POLLS)
+}
+
+/// Detect button tap, long tap is held for `long_tap_polls` polls
+pub fn gpio_detect_tap_with(
+    gpio_history: &mut String,
+    double_tap_max_gap: usize,
+    long_tap_polls: usize,
+) -> Option<TapType> {
+    let long_pattern = format!("{}0", "1".repeat(long_tap_polls));
+    let double_pattern = double_tap_patterns(double_tap_max_gap);
+    // wait longer than double tap gap
+    let single_pattern = format!("1{}", "0".repeat(double_tap_max_gap.max(1) + 1));
 
-    if gpio_history.contains(long_pattern) {
+    if gpio_history.contains(long_pattern.as_str()) {
         gpio_history.clear();
         return Some(TapType::Long);
     }
 
     for pattern in double_pattern {
-        if gpio_history.contains(pattern) {
+        if gpio_history.contains(pattern.as_str()) {
             gpio_history.clear();
             return Some(TapType::Double);
         }
     }
 
-    if gpio_history.contains(single_pattern) {
+    if gpio_history.contains(single_pattern.as_str()) {
         gpio_history.clear();
         return Some(TapType::Single);
     }
@@ -174,13 +452,186 @@ pub fn gpio_detect_tap(gpio_history: &mut String) -> Option<TapType> {
     None
 }
 
+/// Push gpio state of a poll into tap history, returns button pressed
+///
+/// `invert` for active-low wiring, where the gpio reads 1 when released
+pub fn gpio_push_tap(gpio_history: &mut String, gpio_high: bool, invert: bool) -> bool {
+    let pressed = gpio_high != invert;
+    if gpio_history.len() >= gpio_history.capacity() {
+        gpio_history.remove(0);
+    }
+    gpio_history.push(if pressed { '1' } else { '0' });
+    pressed
+}
+
+/// Button pressed polls to be considered stuck, 10s
+pub const BUTTON_STUCK_POLLS: u32 = 100;
+
+/// Detect stuck button, i.e. gpio always pressed
+#[derive(Debug, Default)]
+pub struct ButtonStuckDetector {
+    pressed_polls: u32,
+}
+
+impl ButtonStuckDetector {
+    /// Feed gpio state of a poll, returns true once when the button gets stuck
+    pub fn update(&mut self, pressed: bool) -> bool {
+        if !pressed {
+            if self.is_stuck() {
+                log::info!("Button released");
+            }
+            self.pressed_polls = 0;
+            return false;
+        }
+        self.pressed_polls = self.pressed_polls.saturating_add(1);
+        self.pressed_polls == BUTTON_STUCK_POLLS
+    }
+
+    /// Button is stuck, tap detection should be suppressed
+    pub fn is_stuck(&self) -> bool {
+        self.pressed_polls >= BUTTON_STUCK_POLLS
+    }
+}
+
+/// Suppress repeated tap actions, each tap type has its own cooldown
+#[derive(Debug, Default)]
+pub struct TapCooldown {
+    fired_at: HashMap<TapType, Instant>,
+}
+
+impl TapCooldown {
+    /// Check and record tap action, false if the same tap type fired within `cooldown`
+    pub fn fire(&mut self, tap_type: TapType, cooldown: Option<Duration>, now: Instant) -> bool {
+        if let (Some(cooldown), Some(fired_at)) = (cooldown, self.fired_at.get(&tap_type)) {
+            // cooldown past the end of time never ends
+            match fired_at.checked_add(cooldown) {
+                Some(until) if now >= until => {}
+                _ => return false,
+            }
+        }
+        self.fired_at.insert(tap_type, now);
+        true
+    }
+}
+
+/// Button hold duration, from the run of '1's at the end of gpio history
+///
+/// NOTE: capped by the history length, e.g. 30 polls (3s)
+pub fn gpio_hold_duration(gpio_history: &str) -> Option<Duration> {
+    let run = gpio_history.chars().rev().take_while(|c| *c == '1').count();
+    if run > 0 {
+        Some(I2C_READ_INTERVAL * run as u32)
+    } else {
+        None
+    }
+}
+
+/// Call `f` every `interval` until it returns true, or give up after `timeout`
+fn wait_until<F: FnMut() -> bool>(timeout: Duration, interval: Duration, mut f: F) -> bool {
+    let start = Instant::now();
+    loop {
+        if f() {
+            return true;
+        }
+        if start.elapsed() >= timeout {
+            return false;
+        }
+        thread::sleep(interval);
+    }
+}
+
+/// Call `f` until it returns true, at most `retries` more times with `delay` in between
+fn retry_times<F: FnMut() -> bool>(retries: u32, delay: Duration, mut f: F) -> bool {
+    for i in 0..=retries {
+        if i > 0 {
+            thread::sleep(delay);
+        }
+        if f() {
+            return true;
+        }
+    }
+    false
+}
+
+/// Verify rtc time read back matches the written one, within a second
+fn verify_rtc_write(written: RTCRawTime, read: RTCRawTime) -> Result<()> {
+    let to_utc = |t: RTCRawTime| -> Result<DateTime<Utc>> { t.try_into().map_err(Error::Other) };
+    let diff = to_utc(read)? - to_utc(written)?;
+    if diff < chrono::Duration::zero() || diff > chrono::Duration::seconds(1) {
+        return Err(Error::Other(format!(
+            "RTC write not verified, written {} read {}",
+            written, read
+        )));
+    }
+    Ok(())
+}
+
 /// Execute shell with sh
 pub fn execute_shell(shell: &str) -> io::Result<ExitStatus> {
-    let args = ["-c", shell];
-    let mut child = Command::new("/bin/sh").args(args).spawn()?;
+    execute_shell_with_env(shell, &[])
+}
+
+/// Shell command with sh, extra environment variables are added to the inherited ones
+pub fn shell_command(shell: &str, envs: &[(&str, String)]) -> Command {
+    let mut command = Command::new("/bin/sh");
+    command.args(["-c", shell]);
+    for (key, value) in envs {
+        command.env(key, value);
+    }
+    command
+}
+
+/// Execute shell with sh and extra environment variables
+pub fn execute_shell_with_env(shell: &str, envs: &[(&str, String)]) -> io::Result<ExitStatus> {
+    let mut child = shell_command(shell, envs).spawn()?;
     child.wait()
 }
 
+/// Execute shell with sh and extra environment variables, killed after `timeout` with a `TimedOut` error
+///
+/// With a timeout the script runs in its own process group, and the whole group is killed, background jobs of
+/// the script included
+pub fn execute_shell_with_timeout(
+    shell: &str,
+    envs: &[(&str, String)],
+    timeout: Option<Duration>,
+) -> io::Result<ExitStatus> {
+    let mut command = shell_command(shell, envs);
+    let timeout = match timeout {
+        Some(timeout) => timeout,
+        None => return command.spawn()?.wait(),
+    };
+    let mut child = command.process_group(0).spawn()?;
+    let mut status = Ok(None);
+    wait_until(timeout, SHELL_WAIT_INTERVAL, || {
+        status = child.try_wait();
+        !matches!(status, Ok(None))
+    });
+    match status? {
+        Some(status) => Ok(status),
+        None => {
+            // pgid is the pid of the group leader
+            if unsafe { libc::killpg(child.id() as libc::pid_t, libc::SIGKILL) } != 0 {
+                child.kill()?;
+            }
+            child.wait()?;
+            Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                format!("Script \"{}\" killed after {}s", shell, timeout.as_secs_f64()),
+            ))
+        }
+    }
+}
+
+/// Environment variables of tap scripts, `PISUGAR_TAP` is the tap type, `PISUGAR_LEVEL` the battery level (%)
+pub fn tap_env(tap_type: TapType, level: Option<f32>) -> Vec<(&'static str, String)> {
+    let mut envs = vec![("PISUGAR_TAP", tap_type.to_string())];
+    if let Some(level) = level {
+        envs.push(("PISUGAR_LEVEL", format!("{:.0}", level)));
+    }
+    envs
+}
+
 /// Execute cmds
 pub fn execute_cmd(cmd: &str, args: &[&str]) -> io::Result<ExitStatus> {
     let mut child = Command::new(cmd).args(args).spawn()?;
@@ -228,7 +679,22 @@ macro_rules! call_rtc {
     }
 }
 
+/// Critical battery action
+#[derive(Debug, Clone, PartialEq)]
+enum CriticalAction {
+    /// Run critical_shell
+    Launch(String),
+    /// Critical shell done or timed out, cut the power
+    ForceShutdown,
+    /// Battery recovered or charger plugged in before the power was cut
+    Cancel,
+}
+
 /// Core
+///
+/// A core only uses the i2c bus and addresses of its own config, and keeps no global state, so boards on separate
+/// buses can be managed by one core each, polled side by side in one process. pisugar-server manages one board,
+/// boards on separate buses run one server each, see `doc/config.md`
 pub struct PiSugarCore {
     config_path: Option<String>,
     config: PiSugarConfig,
@@ -238,6 +704,39 @@ pub struct PiSugarCore {
     rtc: Option<Box<dyn RTC + Send>>,
     poll_check_at: Instant,
     rtc_sync_at: Instant,
+    poweroff_at: Option<Instant>,
+    status_tx: broadcast::Sender<StatusSnapshot>,
+    tap_cooldown: TapCooldown,
+    updated_at: Instant,
+    shutdown_at: Option<Instant>,
+    over_voltage: bool,
+    under_voltage: bool,
+    charge_full: bool,
+    shutdown_issued: Arc<AtomicBool>,
+    shutdown_forced: AtomicBool,
+    terminated: bool,
+    critical_at: Option<Instant>,
+    critical_done: Arc<AtomicBool>,
+    critical_forced: bool,
+    cycles_net_charge: Option<f64>,
+    tap_scripts: ScriptRunner,
+    csv_log: Option<CsvLog>,
+    power_supply: Option<PowerSupplyExport>,
+    power_supply_errors: LogRateLimiter,
+    boot_alarm_flag: Option<bool>,
+    state: CoreState,
+    state_path: Option<PathBuf>,
+    clock: Box<dyn Clock + Send>,
+    remote_io_errors: u32,
+    redetect_polls: u32,
+    /// Chips dropped after remote I/O errors, re-init them at the next poll
+    reinit_pending: bool,
+    /// Network time sync due, fetched after `poll_hardware`, see `take_rtc_sync`
+    rtc_sync_pending: bool,
+    rtc_polls: u32,
+    rtc_time: Option<(Instant, DateTime<Local>)>,
+    paused: bool,
+    power_flapping: bool,
 }
 
 impl PiSugarCore {
@@ -245,7 +744,29 @@ impl PiSugarCore {
         if self.battery.is_none() {
             log::debug!("Core init battery...");
             let mut battery = self.model.bind(self.config.clone())?;
+            // undo an interrupted force shutdown, back to the registers of the last init
+            if let Some(regs) = &self.state.shutdown_config {
+                match battery.restore_shutdown_config(regs) {
+                    Ok(true) => log::warn!(
+                        "Battery shutdown config changed since last init, e.g. interrupted force shutdown, restored"
+                    ),
+                    Ok(false) => {}
+                    Err(e) => log::warn!("Restore battery shutdown config error: {}", e),
+                }
+            }
             battery.init(&self.config)?;
+            match battery.read_shutdown_config() {
+                Ok(regs) if regs.is_empty() => {}
+                Ok(regs) => {
+                    if self.state.shutdown_config.as_ref() != Some(&regs) {
+                        self.state.shutdown_config = Some(regs);
+                        if let Err(e) = self.save_state() {
+                            log::warn!("{}", e);
+                        }
+                    }
+                }
+                Err(e) => log::warn!("Read battery shutdown config error: {}", e),
+            }
             self.battery = Some(battery);
         }
         Ok(())
@@ -255,13 +776,52 @@ impl PiSugarCore {
         if self.rtc.is_none() {
             log::debug!("Core init rtc...");
             let mut rtc = self.model.rtc(self.config.clone())?;
+            // alarm flag is cleared in init, keep it for boot reason
+            if self.boot_alarm_flag.is_none() {
+                self.boot_alarm_flag = rtc.read_alarm_flag().ok();
+            }
             rtc.init(&self.config)?;
+            if self.config.rtc_init_from_system_if_invalid == Some(true) {
+                if let Err(e) = self.init_rtc_time_from_system(rtc.as_ref()) {
+                    log::warn!("Set invalid rtc time from system: {}", e);
+                }
+            }
             self.rtc = Some(rtc);
+            if !self.config.wake_queue.is_empty() {
+                if let Err(e) = self.rearm_wake_queue() {
+                    log::warn!("Arm wake queue error: {}", e);
+                }
+            }
         }
         Ok(())
     }
 
+    /// Rtc lost power, write system time instead of keeping the invalid time, if the system time is synchronized
+    fn init_rtc_time_from_system(&self, rtc: &(dyn RTC + Send)) -> Result<()> {
+        if !rtc.read_power_down_flag()? {
+            return Ok(());
+        }
+        if !self.clock.is_synchronized() {
+            log::warn!("RTC lost power, system time not synchronized, keep rtc time");
+            return Ok(());
+        }
+        let t = RTCRawTime::checked_from(self.now_local().with_timezone(&Utc))?;
+        log::warn!("RTC lost power, set it from system time {}", t.to_rfc3339()?);
+        rtc.write_time(t)?;
+        rtc.clear_power_down_flag()
+    }
+
     pub fn new(config: PiSugarConfig, model: Model) -> Result<Self> {
+        Self::new_with_state(config, model, CoreState::default(), None)
+    }
+
+    /// Core with runtime state loaded before the chips are initialized
+    fn new_with_state(
+        config: PiSugarConfig,
+        model: Model,
+        state: CoreState,
+        state_path: Option<PathBuf>,
+    ) -> Result<Self> {
         let mut core = Self {
             config_path: None,
             config,
@@ -271,7 +831,62 @@ impl PiSugarCore {
             rtc: None,
             poll_check_at: Instant::now(),
             rtc_sync_at: Instant::now(),
+            poweroff_at: None,
+            status_tx: broadcast::channel(STATUS_BROADCAST_CAPACITY).0,
+            tap_cooldown: TapCooldown::default(),
+            updated_at: Instant::now(),
+            shutdown_at: None,
+            over_voltage: false,
+            under_voltage: false,
+            charge_full: false,
+            shutdown_issued: Arc::new(AtomicBool::new(false)),
+            shutdown_forced: AtomicBool::new(false),
+            terminated: false,
+            critical_at: None,
+            critical_done: Arc::new(AtomicBool::new(false)),
+            critical_forced: false,
+            cycles_net_charge: None,
+            tap_scripts: ScriptRunner::new(),
+            csv_log: None,
+            power_supply: None,
+            power_supply_errors: LogRateLimiter::default(),
+            boot_alarm_flag: None,
+            state,
+            state_path,
+            clock: Box::new(SystemClock),
+            remote_io_errors: 0,
+            redetect_polls: 0,
+            reinit_pending: false,
+            rtc_sync_pending: false,
+            rtc_polls: 0,
+            rtc_time: None,
+            paused: false,
+            power_flapping: false,
         };
+        // board may not be ready at boot, wait for the battery chip to answer
+        if let Some(delay) = core.config.startup_probe_delay.filter(|d| *d > 0) {
+            log::info!("Wait up to {}s for PiSugar to answer on I2C bus", delay);
+            let timeout = Duration::from_secs(delay);
+            if !wait_until(timeout, STARTUP_PROBE_INTERVAL, || core.init_battery().is_ok()) {
+                log::warn!("PiSugar not answering after {}s", delay);
+            }
+        }
+        // chips may need a few more tries on cold boot
+        if let Some(retries) = core.config.detection_retries.filter(|r| *r > 0) {
+            let delay = core
+                .config
+                .detection_retry_delay
+                .map(Duration::from_millis)
+                .unwrap_or(STARTUP_PROBE_INTERVAL);
+            let detected = retry_times(retries, delay, || {
+                let rtc = core.init_rtc().is_ok();
+                let battery = core.init_battery().is_ok();
+                rtc && battery
+            });
+            if !detected {
+                log::warn!("PiSugar not found after {} retries", retries);
+            }
+        }
         if let Err(e) = core.init_rtc() {
             log::warn!("Retry to init rtc, error: {}", e);
         }
@@ -291,6 +906,37 @@ impl PiSugarCore {
             rtc: None,
             poll_check_at: Instant::now(),
             rtc_sync_at: Instant::now(),
+            poweroff_at: None,
+            status_tx: broadcast::channel(STATUS_BROADCAST_CAPACITY).0,
+            tap_cooldown: TapCooldown::default(),
+            updated_at: Instant::now(),
+            shutdown_at: None,
+            over_voltage: false,
+            under_voltage: false,
+            charge_full: false,
+            shutdown_issued: Arc::new(AtomicBool::new(false)),
+            shutdown_forced: AtomicBool::new(false),
+            terminated: false,
+            critical_at: None,
+            critical_done: Arc::new(AtomicBool::new(false)),
+            critical_forced: false,
+            cycles_net_charge: None,
+            tap_scripts: ScriptRunner::new(),
+            csv_log: None,
+            power_supply: None,
+            power_supply_errors: LogRateLimiter::default(),
+            boot_alarm_flag: None,
+            state: CoreState::default(),
+            state_path: None,
+            clock: Box::new(SystemClock),
+            remote_io_errors: 0,
+            redetect_polls: 0,
+            reinit_pending: false,
+            rtc_sync_pending: false,
+            rtc_polls: 0,
+            rtc_time: None,
+            paused: false,
+            power_flapping: false,
         };
         core.battery = Some(model.bind(config.clone())?);
         core.rtc = Some(model.rtc(config.clone())?);
@@ -300,17 +946,20 @@ impl PiSugarCore {
     pub fn new_with_path(config_path: &str, recover_config: bool, model: Model) -> Result<Self> {
         let config_path = PathBuf::from(config_path);
         if config_path.is_dir() {
-            return Err(Error::Other("Not a file".to_string()));
+            return Err(ConfigError::NotAFile(config_path).into());
         }
 
         match Self::load_config(config_path.as_path(), model) {
             Ok(core) => Ok(core),
+            // recovery would overwrite a file the user can't read
+            Err(e @ Error::Config(ConfigError::PermissionDenied(_))) => Err(e),
             Err(e) => {
                 log::error!("Load configuration error:{}", e);
                 log::warn!("Load configuration auto recovery...");
                 if recover_config {
                     // backup old config
-                    let local_now = Local::now();
+                    // no core yet to take the clock from
+                    let local_now = SystemClock.now_local();
                     let backup_path_template = format!(
                         "{}-{}{}{}",
                         config_path.to_string_lossy(),
@@ -339,25 +988,44 @@ impl PiSugarCore {
                     let core = Self::new(config, model)?;
                     Ok(core)
                 } else {
-                    Err(Error::Other("Not recoverable".to_string()))
+                    Err(e)
                 }
             }
         }
     }
 
     fn load_config(path: &Path, model: Model) -> Result<Self> {
-        if path.exists() && path.is_file() {
-            let mut config = PiSugarConfig::default();
-            match config.load(path) {
-                Ok(_) => {
-                    let mut core = Self::new(config, model)?;
-                    core.config_path = Some(path.to_string_lossy().to_string());
-                    Ok(core)
-                }
-                Err(e) => Err(Error::Other(format!("{}", e))),
+        let config = PiSugarConfig::load_checked(path)?;
+        let state_path = CoreState::path_for(path);
+        let state = CoreState::load(&state_path);
+        let mut core = Self::new_with_state(config, model, state, Some(state_path))?;
+        core.config_path = Some(path.to_string_lossy().to_string());
+        // chips are initialized from config, keep a requested disarm across restarts
+        if core.state.auto_power_on_disarmed && core.config.auto_power_on == Some(true) {
+            if let Err(e) = core.apply_auto_power_on(false) {
+                log::warn!("Keep auto power on disarmed error: {}", e);
+            }
+        }
+        Ok(core)
+    }
+
+    /// Save runtime state, nothing to do without a state file, e.g. not loaded from a config file
+    pub fn save_state(&self) -> Result<()> {
+        match &self.state_path {
+            Some(path) => self
+                .state
+                .save_to(path)
+                .map_err(|e| Error::Other(format!("Failed to save state file {}: {}", path.display(), e))),
+            None => Ok(()),
+        }
+    }
+
+    fn set_auto_power_on_disarmed(&mut self, disarmed: bool) {
+        if self.state.auto_power_on_disarmed != disarmed {
+            self.state.auto_power_on_disarmed = disarmed;
+            if let Err(e) = self.save_state() {
+                log::warn!("{}", e);
             }
-        } else {
-            Err(Error::Other("File not found or could not open".to_string()))
         }
     }
 
@@ -371,10 +1039,46 @@ impl PiSugarCore {
         Err(Error::Other("Failed to save config file".to_string()))
     }
 
+    /// Replace time source, e.g. a fake clock in tests
+    pub fn set_clock(&mut self, clock: Box<dyn Clock + Send>) {
+        self.clock = clock;
+    }
+
+    /// Monotonic now, from core clock
+    pub fn now(&self) -> Instant {
+        self.clock.now_instant()
+    }
+
+    /// Local datetime now, from core clock
+    pub fn now_local(&self) -> DateTime<Local> {
+        self.clock.now_local()
+    }
+
     pub fn model(&self) -> String {
         self.model.to_string()
     }
 
+    /// Core version and detected hardware
+    pub fn info(&self) -> CoreInfo {
+        CoreInfo {
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            model: self.model.to_string(),
+            battery_i2c_addr: self.model.battery_i2c_addr(&self.config),
+            rtc_i2c_addr: self.model.rtc_i2c_addr(&self.config),
+        }
+    }
+
+    /// Board hardware of the model, with configured i2c addresses
+    pub fn board(&self) -> Board {
+        Board {
+            model: self.model,
+            battery_chip: self.model.battery_chip(),
+            rtc_chip: self.model.rtc_chip(),
+            battery_i2c_addr: self.model.battery_i2c_addr(&self.config),
+            rtc_i2c_addr: self.model.rtc_i2c_addr(&self.config),
+        }
+    }
+
     pub fn led_amount(&self) -> Result<u32> {
         Ok(self.model.led_amount())
     }
@@ -403,10 +1107,29 @@ impl PiSugarCore {
         call_battery!(&self.battery, level)
     }
 
+    /// Battery level with the voltage sag under load added back, using config `internal_resistance`
+    ///
+    /// Same as `level` with the default resistance of 0
+    pub fn level_load_compensated(&self) -> Result<f32> {
+        let voltage = self.voltage_avg()?;
+        let discharge = call_battery!(&self.battery, discharge_current)?;
+        let r = self.config.internal_resistance.unwrap_or(0.0);
+        call_battery!(
+            &self.battery,
+            voltage_level,
+            load_compensated_voltage(voltage, discharge, r)
+        )
+    }
+
     pub fn power_plugged(&self) -> Result<bool> {
         call_battery!(&self.battery, is_power_plugged)
     }
 
+    /// External power present from the VBUS/input-present bit, plugged in but full is still true
+    pub fn external_power_present(&self) -> Result<bool> {
+        call_battery!(&self.battery, external_power_present)
+    }
+
     pub fn allow_charging(&self) -> Result<bool> {
         call_battery!(&self.battery, is_allow_charging)
     }
@@ -435,6 +1158,84 @@ impl PiSugarCore {
         call_battery!(&self.battery, toggle_output_enabled, enable)
     }
 
+    /// Snapshot of current battery status
+    pub fn snapshot(&self) -> Result<StatusSnapshot> {
+        self.snapshot_with_rtc_time(self.read_time().ok())
+    }
+
+    /// Snapshot with rtc time read elsewhere
+    fn snapshot_with_rtc_time(&self, rtc_time: Option<DateTime<Local>>) -> Result<StatusSnapshot> {
+        Ok(StatusSnapshot {
+            model: self.model,
+            voltage: self.voltage_avg()?,
+            intensity: self.intensity_avg()?,
+            level: self.level()?,
+            charging: self.charging()?,
+            power_plugged: self.external_power_present().ok(),
+            shutdown_state: self.shutdown_state(),
+            rtc_time,
+            data_age_secs: self.age(self.now()).as_secs(),
+        })
+    }
+
+    /// Subsystem health
+    pub fn health(&self) -> Health {
+        Health::new(
+            self.voltage().is_ok(),
+            self.read_time().is_ok(),
+            self.age(self.now()).as_secs(),
+            self.config_path.is_some(),
+        )
+    }
+
+    /// Diagnostic bundle for support, hardware, health, readings, chip registers and config
+    ///
+    /// Credentials and shell commands in the config are redacted, see `DIAGNOSTIC_REDACTED_FIELDS`
+    pub fn diagnostic_bundle(&self) -> serde_json::Value {
+        let mut config = serde_json::to_value(&self.config).unwrap_or(serde_json::Value::Null);
+        if let Some(config) = config.as_object_mut() {
+            for (key, value) in config.iter_mut() {
+                let secret = DIAGNOSTIC_REDACTED_FIELDS.contains(&key.as_str()) || key.ends_with("_shell");
+                let empty = value.is_null() || value.as_str() == Some("");
+                if secret && !empty {
+                    *value = serde_json::Value::from(DIAGNOSTIC_REDACTED);
+                }
+            }
+        }
+        let snapshot = match self.snapshot() {
+            Ok(snapshot) => serde_json::to_value(snapshot).unwrap_or(serde_json::Value::Null),
+            Err(e) => serde_json::json!({ "error": e.to_string() }),
+        };
+        serde_json::json!({
+            "info": self.info(),
+            "board": self.board(),
+            "battery_version": self.version().ok(),
+            "health": self.health(),
+            "snapshot": snapshot,
+            "tap_history": self.tap_history(),
+            "registers": {
+                "battery": self.battery.as_ref().and_then(|b| b.register_dump()),
+                "rtc": self.rtc.as_ref().and_then(|r| r.register_dump()),
+            },
+            "config": config,
+        })
+    }
+
+    /// Subscribe status snapshots, published every second while there are subscribers
+    pub fn subscribe(&self) -> broadcast::Receiver<StatusSnapshot> {
+        self.status_tx.subscribe()
+    }
+
+    /// Publish status snapshot, never blocks on slow subscribers
+    fn publish_status(&self, snapshot: StatusSnapshot) {
+        let _ = self.status_tx.send(snapshot);
+    }
+
+    /// Time since last successful battery read
+    pub fn age(&self, now: Instant) -> Duration {
+        now.saturating_duration_since(self.updated_at)
+    }
+
     pub fn charging_range(&self) -> Result<Option<(f32, f32)>> {
         Ok(self.config.auto_charging_range)
     }
@@ -461,7 +1262,13 @@ impl PiSugarCore {
     }
 
     pub fn write_time(&self, dt: DateTime<Local>) -> Result<()> {
-        call_rtc!(&self.rtc, write_time, dt.into())
+        let t = RTCRawTime::checked_from(dt)?;
+        call_rtc!(&self.rtc, write_time, t)?;
+        if self.config.verify_rtc_writes == Some(true) {
+            let read = self.read_raw_time()?;
+            verify_rtc_write(t, read)?;
+        }
+        Ok(())
     }
 
     pub fn write_alarm(&self, t: RTCRawTime, weekday_repeat: u8) -> Result<()> {
@@ -493,6 +1300,64 @@ impl PiSugarCore {
         call_rtc!(&self.rtc, clear_alarm_flag)
     }
 
+    /// Boot reason, from rtc alarm flag read before it was cleared, `Unknown` if it can't be read
+    pub fn boot_reason(&self) -> BootReason {
+        let alarm_flag = match self.boot_alarm_flag {
+            Some(flag) => flag,
+            None => match self.read_alarm_flag() {
+                Ok(flag) => flag,
+                Err(e) => {
+                    log::debug!("Read alarm flag error: {}", e);
+                    return BootReason::Unknown;
+                }
+            },
+        };
+        if alarm_flag {
+            if let Err(e) = self.clear_alarm_flag() {
+                log::warn!("Clear alarm flag error: {}", e);
+            }
+        }
+        alarm_flag.into()
+    }
+
+    /// Record this boot at rtc time, returns true if power is flapping
+    ///
+    /// Boots are keyed by the kernel boot id, a server restart is not a boot. A `power_flapping` event is sent on the
+    /// next poll
+    pub fn record_boot(&mut self) -> Result<bool> {
+        let boot_id = std::fs::read_to_string(BOOT_ID_PATH)
+            .map_err(|e| Error::Other(format!("Failed to read {}: {}", BOOT_ID_PATH, e)))?;
+        let now = self.read_time()?;
+        self.record_boot_at(boot_id.trim(), now)
+    }
+
+    fn record_boot_at(&mut self, boot_id: &str, now: DateTime<Local>) -> Result<bool> {
+        let interval = self
+            .config
+            .power_flapping_interval
+            .unwrap_or(DEFAULT_POWER_FLAPPING_INTERVAL);
+        if interval == 0 {
+            return Ok(false);
+        }
+        let history = self.state.boot_history.clone();
+        let flapping = self
+            .state
+            .boot_history
+            .record(boot_id, now, Duration::from_secs(interval));
+        if flapping {
+            log::warn!(
+                "Power flapping, {} boots in a row within {}s of the previous one, check the power cable and battery",
+                self.state.boot_history.rapid_boots(),
+                interval
+            );
+            self.power_flapping = true;
+        }
+        if self.state.boot_history != history {
+            self.save_state()?;
+        }
+        Ok(flapping)
+    }
+
     pub fn disable_alarm(&self) -> Result<()> {
         call_rtc!(&self.rtc, toggle_alarm_enable, false)
     }
@@ -500,7 +1365,11 @@ impl PiSugarCore {
     pub fn toggle_auto_power_on(&mut self, auto_power_on: bool) -> Result<()> {
         self.config.auto_power_on = Some(auto_power_on);
         self.save_config()?;
+        self.set_auto_power_on_disarmed(false);
+        self.apply_auto_power_on(auto_power_on)
+    }
 
+    fn apply_auto_power_on(&self, auto_power_on: bool) -> Result<()> {
         match self.model {
             Model::PiSugar_3 => {
                 call_battery!(&self.battery, toggle_power_restore, auto_power_on)?;
@@ -512,74 +1381,651 @@ impl PiSugarCore {
                 } else {
                     call_rtc!(&self.rtc, toggle_frequency_alarm, false)?;
                     // restore clock alarm
-                    if let Some(wakeup_time) = self.config.auto_wake_time {
-                        self.write_alarm(wakeup_time.into(), self.config.auto_wake_repeat)?;
-                    }
+                    self.apply_auto_wake()?;
                 }
-                call_battery!(&self.battery, toggle_light_load_shutdown, !auto_power_on)?;
+                let chip_auto_shutdown = !auto_power_on && self.config.enable_chip_auto_shutdown != Some(false);
+                call_battery!(&self.battery, toggle_light_load_shutdown, chip_auto_shutdown)?;
             }
         }
 
         Ok(())
     }
 
-    pub fn toggle_anti_mistouch(&mut self, anti_mistouch: bool) -> Result<()> {
-        self.config.anti_mistouch = Some(anti_mistouch);
+    /// Arm rtc alarm of auto wake, or disable the armed one if auto wake is disabled
+    ///
+    /// A pending queued wake keeps the alarm, see `rearm_wake_queue`
+    fn apply_auto_wake(&self) -> Result<()> {
+        if let Some(at) = self.config.wake_queue.next() {
+            log::info!("Arm wake alarm {}", at);
+            let weekday = WeekdayMask::default().with(at.weekday());
+            return self.write_alarm(at.into(), weekday.bits());
+        }
+        match self.config.auto_wake_time {
+            Some(wakeup_time) if self.config.auto_wake_enabled() => {
+                self.write_alarm(wakeup_time.into(), self.config.auto_wake_repeat)
+            }
+            _ => {
+                log::info!("Auto wake disabled, disable rtc alarm");
+                self.disable_alarm()
+            }
+        }
+    }
+
+    /// Add a pending wake alarm and arm the soonest one, it must be after rtc time (system time without rtc)
+    pub fn add_wake_alarm(&mut self, at: DateTime<Local>) -> Result<()> {
+        let now = self.read_time().unwrap_or_else(|_| self.now_local());
+        check_wake_time(at, now)?;
+        self.config.wake_queue.add(at)?;
         self.save_config()?;
-        call_battery!(&self.battery, toggle_anti_mistouch, anti_mistouch)
+        self.rearm_wake_queue().map(|_| ())
     }
 
-    pub fn toggle_soft_poweroff(&mut self, soft_poweroff: bool) -> Result<()> {
-        self.config.soft_poweroff = Some(soft_poweroff);
+    /// Remove a pending wake alarm and arm the soonest one, false if not found
+    pub fn remove_wake_alarm(&mut self, at: DateTime<Local>) -> Result<bool> {
+        if !self.config.wake_queue.remove(at) {
+            return Ok(false);
+        }
         self.save_config()?;
-        call_battery!(&self.battery, toggle_soft_poweroff, soft_poweroff)
+        self.rearm_wake_queue().map(|_| true)
     }
 
-    pub fn get_temperature(&self) -> Result<f32> {
-        call_battery!(&self.battery, temperature)
+    /// Drop fired wake alarms and arm the soonest pending one, auto wake gets the rtc alarm back when none is left
+    pub fn rearm_wake_queue(&mut self) -> Result<Option<DateTime<Local>>> {
+        let now = self.now_local();
+        if self.config.wake_queue.pop_fired(now) > 0 && self.config_path.is_some() {
+            self.save_config()?;
+        }
+        self.apply_auto_wake()?;
+        Ok(self.config.wake_queue.next())
     }
 
-    pub fn test_wake(&self) -> Result<()> {
-        call_rtc!(&self.rtc, set_test_wake)
+    /// Disarm auto power on on request, until battery reaches auto_shutdown_recover_level or power is restored
+    ///
+    /// Kept in the state file across restarts
+    pub fn disarm_auto_power_on(&mut self) -> Result<()> {
+        if self.config.auto_power_on == Some(true) {
+            log::info!("Disarm auto power on until battery recovered or power restored");
+            self.apply_auto_power_on(false)?;
+            self.set_auto_power_on_disarmed(true);
+        }
+        Ok(())
     }
 
-    pub fn config(&self) -> &PiSugarConfig {
-        &self.config
+    /// Auto power on disarmed, see `disarm_auto_power_on`
+    pub fn auto_power_on_disarmed(&self) -> bool {
+        self.state.auto_power_on_disarmed
     }
 
-    pub fn config_mut(&mut self) -> &mut PiSugarConfig {
-        &mut self.config
+    /// Check if auto power on should be re-armed, battery recovered or power plugged in
+    fn check_auto_power_on_recovered(&mut self, level: f32, power_plugged: bool) -> bool {
+        let recovered =
+            power_plugged || matches!(self.config.auto_shutdown_recover_level, Some(l) if level as f64 >= l);
+        if self.state.auto_power_on_disarmed && recovered {
+            self.set_auto_power_on_disarmed(false);
+            return true;
+        }
+        false
     }
 
-    pub fn force_shutdown(&self) -> Result<()> {
-        // exec 30 sync before shutdown
-        for _ in 0..30 {
-            let _ = execute_shell("sync");
+    /// Update some config fields, apply changed hardware settings and save,
+    /// the old config is restored (and re-applied) if any hardware setting fails
+    pub fn update_config(&mut self, patch: ConfigPatch) -> Result<()> {
+        let mut config = self.config.clone();
+        patch.apply_to(&mut config);
+        if let Some((begin, end)) = config.auto_charging_range {
+            if begin < 0.0 || end < begin || end > 100.0 {
+                return Err(Error::Other("Invalid charging range".to_string()));
+            }
         }
 
-        call_rtc!(&self.rtc, force_shutdown)?;
-        call_battery!(&self.battery, shutdown)
+        // hardware first, the new config is only committed and saved if all applied
+        let old = std::mem::replace(&mut self.config, config);
+        let disarmed = self.state.auto_power_on_disarmed;
+        if let Err(e) = self.apply_config_changes(&old) {
+            log::warn!("Apply config error, roll back: {}", e);
+            let new = std::mem::replace(&mut self.config, old);
+            if let Err(e) = self.apply_config_changes(&new) {
+                log::warn!("Roll back hardware config error: {}", e);
+            }
+            self.set_auto_power_on_disarmed(disarmed);
+            return Err(e);
+        }
+        self.save_config()
     }
 
-    pub async fn poll(&mut self, now: Instant) -> Result<Option<TapType>> {
-        if self.rtc.is_none() {
-            log::info!("Init rtc...");
-            self.init_rtc()?;
+    /// Apply hardware settings changed from `old` to current config
+    fn apply_config_changes(&mut self, old: &PiSugarConfig) -> Result<()> {
+        if old.auto_power_on != self.config.auto_power_on {
+            self.set_auto_power_on_disarmed(false);
+            self.apply_auto_power_on(self.config.auto_power_on == Some(true))?;
         }
+        // pisugar 2 rtc alarm is taken by frequency alarm in auto power on
+        let alarm_free = self.model == Model::PiSugar_3 || self.config.auto_power_on != Some(true);
+        let auto_wake_changed =
+            old.auto_wake_time != self.config.auto_wake_time || old.auto_wake_repeat != self.config.auto_wake_repeat;
+        if auto_wake_changed && alarm_free {
+            self.apply_auto_wake()?;
+        }
+        if old.anti_mistouch != self.config.anti_mistouch {
+            if let Some(anti_mistouch) = self.config.anti_mistouch {
+                call_battery!(&self.battery, toggle_anti_mistouch, anti_mistouch)?;
+            }
+        }
+        if old.soft_poweroff != self.config.soft_poweroff {
+            if let Some(soft_poweroff) = self.config.soft_poweroff {
+                call_battery!(&self.battery, toggle_soft_poweroff, soft_poweroff)?;
+            }
+        }
+        if old.bat_protect != self.config.bat_protect {
+            if let Some(bat_protect) = self.config.bat_protect {
+                self.toggle_input_protected(bat_protect)?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn toggle_anti_mistouch(&mut self, anti_mistouch: bool) -> Result<()> {
+        self.config.anti_mistouch = Some(anti_mistouch);
+        self.save_config()?;
+        call_battery!(&self.battery, toggle_anti_mistouch, anti_mistouch)
+    }
+
+    pub fn toggle_soft_poweroff(&mut self, soft_poweroff: bool) -> Result<()> {
+        self.config.soft_poweroff = Some(soft_poweroff);
+        self.save_config()?;
+        call_battery!(&self.battery, toggle_soft_poweroff, soft_poweroff)
+    }
+
+    /// Battery internal resistance (Ohm), None until a clear load step is observed
+    pub fn estimated_internal_resistance(&self) -> Option<f64> {
+        self.battery.as_ref().and_then(|b| b.internal_resistance())
+    }
+
+    /// Battery capacity (mAh), configured or the model's
+    fn battery_capacity(&self) -> f64 {
+        self.config
+            .battery_capacity
+            .unwrap_or_else(|| self.model.battery_capacity())
+    }
+
+    /// Remaining battery capacity (mAh) estimated from level
+    pub fn remaining_mah(&self) -> Result<f64> {
+        Ok(self.battery_capacity() * self.level()? as f64 / 100.0)
+    }
+
+    /// Estimated battery cycles, persisted in state file
+    pub fn estimated_cycles(&self) -> f64 {
+        self.state.battery_cycles
+    }
+
+    /// Add charge drawn since the last net charge (Ah) reading to battery cycles, true if it's time to save
+    ///
+    /// Only discharge counts, a full cycle moves the capacity both out and back in
+    fn track_cycles(&mut self, net_charge: f64) -> bool {
+        let drawn = match self.cycles_net_charge.replace(net_charge) {
+            Some(last) => (last - net_charge).max(0.0),
+            None => return false,
+        };
+        let capacity = self.battery_capacity() / 1000.0;
+        if capacity <= 0.0 {
+            return false;
+        }
+        let before = self.estimated_cycles();
+        let after = before + drawn / capacity;
+        self.state.battery_cycles = after;
+        (after / CYCLES_SAVE_STEP).floor() > (before / CYCLES_SAVE_STEP).floor()
+    }
+
+    /// Days on standby at idle intensity (A), default the model's typical idle intensity
+    pub fn standby_days_remaining(&self, idle_current_a: Option<f64>) -> Option<f64> {
+        let idle_current_a = idle_current_a.unwrap_or_else(|| self.model.idle_intensity());
+        standby_days(self.remaining_mah().ok()?, idle_current_a)
+    }
+
+    /// Net charge into the battery (Ah) since start, negative when discharging
+    pub fn net_charge(&self) -> Option<f64> {
+        self.battery.as_ref().and_then(|b| b.net_charge())
+    }
+
+    /// Battery curve segment of the average voltage, see `curve_segment_for`
+    pub fn curve_segment(&self) -> Result<Option<usize>> {
+        call_battery!(&self.battery, curve_segment)
+    }
+
+    /// Button held down duration, separate from long tap
+    pub fn button_hold_duration(&self) -> Option<Duration> {
+        self.battery.as_ref().and_then(|b| b.button_hold_duration())
+    }
+
+    /// Raw tap gpio samples, for tap tuning, empty if the battery detects taps without it
+    pub fn tap_history(&self) -> &str {
+        self.battery.as_ref().map_or("", |b| b.tap_history())
+    }
+
+    pub fn get_temperature(&self) -> Result<f32> {
+        call_battery!(&self.battery, temperature)
+    }
+
+    pub fn test_wake(&self) -> Result<()> {
+        call_rtc!(&self.rtc, set_test_wake, self.now_local().with_timezone(&Utc))
+    }
+
+    pub fn config(&self) -> &PiSugarConfig {
+        &self.config
+    }
+
+    pub fn config_mut(&mut self) -> &mut PiSugarConfig {
+        &mut self.config
+    }
+
+    /// Force shutdown, `shutdown_state` is forcing once it succeeded
+    pub fn force_shutdown(&self) -> Result<()> {
+        // the bus is taken, e.g. by a firmware update
+        if self.paused {
+            return Err(Error::Other("Polling paused".to_string()));
+        }
+        // exec 30 sync before shutdown
+        for _ in 0..30 {
+            let _ = execute_shell("sync");
+        }
+
+        call_rtc!(&self.rtc, force_shutdown)?;
+        call_battery!(&self.battery, shutdown)?;
+        self.shutdown_forced.store(true, Ordering::SeqCst);
+        Ok(())
+    }
+
+    /// Latch shutdown, true only the first time per boot, so the shutdown command is spawned once
+    pub fn issue_shutdown(&mut self) -> bool {
+        if self.shutdown_issued.swap(true, Ordering::SeqCst) {
+            log::debug!("Shutdown in progress");
+            return false;
+        }
+        true
+    }
+
+    /// Shutdown command failed, release the latch so the next `issue_shutdown` runs it again
+    pub fn shutdown_failed(&mut self) {
+        self.shutdown_issued.store(false, Ordering::SeqCst);
+    }
+
+    /// Shutdown in progress, from the shutdown latch, graceful poweroff watchdog and force shutdown
+    pub fn shutdown_state(&self) -> ShutdownState {
+        if self.shutdown_forced.load(Ordering::SeqCst) {
+            ShutdownState::Forcing
+        } else if self.shutdown_issued.load(Ordering::SeqCst) || self.poweroff_at.is_some() {
+            ShutdownState::GracefulPending
+        } else {
+            ShutdownState::None
+        }
+    }
+
+    /// Server is terminating, persist config and csv log, drop the scheduled shutdown and a stale
+    /// rtc alarm that auto wake doesn't own. Only the first call does anything.
+    pub fn on_terminate(&mut self) {
+        if self.terminated {
+            return;
+        }
+        self.terminated = true;
+
+        if self.config_path.is_some() {
+            if let Err(e) = self.save_config() {
+                log::warn!("Save config on exit: {}", e);
+            }
+        }
+        if let Some(csv_log) = &mut self.csv_log {
+            if let Err(e) = csv_log.flush() {
+                log::warn!("Flush csv log on exit: {}", e);
+            }
+        }
+        if self.cancel_scheduled_shutdown() {
+            log::info!("Scheduled shutdown cancelled on exit");
+        }
+        // pisugar 2 rtc alarm is taken by frequency alarm in auto power on
+        let alarm_free = self.model == Model::PiSugar_3 || self.config.auto_power_on != Some(true);
+        if alarm_free && self.rtc.is_some() {
+            if !self.config.wake_queue.is_empty() {
+                if let Err(e) = self.rearm_wake_queue() {
+                    log::warn!("Arm wake queue on exit: {}", e);
+                }
+            } else if !self.config.auto_wake_enabled() {
+                if let Err(e) = self.disable_alarm() {
+                    log::warn!("Disable rtc alarm on exit: {}", e);
+                }
+            }
+        }
+    }
+
+    /// Graceful poweroff issued, start the force shutdown watchdog
+    pub fn notify_poweroff(&mut self, now: Instant) {
+        if self.poweroff_at.is_none() {
+            self.poweroff_at = Some(now);
+        }
+    }
+
+    /// Graceful poweroff stalls longer than `force_shutdown_timeout`
+    fn poweroff_stalled(&self, now: Instant) -> bool {
+        match (self.poweroff_at, self.config.force_shutdown_timeout) {
+            (Some(poweroff_at), Some(timeout)) => poweroff_at + Duration::from_secs(timeout) <= now,
+            _ => false,
+        }
+    }
+
+    /// Critical battery, launch critical_shell once, then force shutdown when it's done or critical_timeout passed
+    ///
+    /// Cancelled if the battery recovers or a charger is plugged in first. ForceShutdown is returned on every
+    /// poll until `critical_forced` is set, i.e. the force shutdown succeeded
+    fn check_critical(&mut self, level: f32, power_plugged: bool, now: Instant) -> Option<CriticalAction> {
+        if self.critical_forced {
+            return None;
+        }
+        let critical = self.config.is_critical_level(level) && !power_plugged;
+        let forced = match self.critical_at {
+            None => {
+                if !critical {
+                    return None;
+                }
+                self.critical_at = Some(now);
+                if let Some(shell) = &self.config.critical_shell {
+                    return Some(CriticalAction::Launch(shell.clone()));
+                }
+                true
+            }
+            Some(_) if !critical => {
+                self.critical_at = None;
+                // a shell still running must not force the next critical shutdown
+                self.critical_done = Arc::new(AtomicBool::new(false));
+                return Some(CriticalAction::Cancel);
+            }
+            Some(critical_at) => {
+                let timeout = Duration::from_secs(self.config.critical_timeout.unwrap_or(DEFAULT_CRITICAL_TIMEOUT));
+                // a timeout past the end of time never expires
+                self.critical_done.load(Ordering::SeqCst) || critical_at.checked_add(timeout).is_some_and(|t| t <= now)
+            }
+        };
+        if forced {
+            Some(CriticalAction::ForceShutdown)
+        } else {
+            None
+        }
+    }
+
+    /// Schedule a graceful shutdown after `after`, replaces the previous one, error if `after` is out of range
+    pub fn schedule_shutdown(&mut self, after: Duration) -> Result<()> {
+        let shutdown_at = self
+            .now()
+            .checked_add(after)
+            .ok_or_else(|| Error::Other(format!("Shutdown delay {}s out of range", after.as_secs())))?;
+        log::info!("Schedule shutdown after {}s", after.as_secs());
+        self.shutdown_at = Some(shutdown_at);
+        Ok(())
+    }
+
+    /// Cancel scheduled shutdown, returns true if there was one
+    pub fn cancel_scheduled_shutdown(&mut self) -> bool {
+        self.shutdown_at.take().is_some()
+    }
+
+    /// Scheduled shutdown deadline
+    pub fn scheduled_shutdown(&self) -> Option<Instant> {
+        self.shutdown_at
+    }
+
+    /// Scheduled shutdown reaches its deadline, fires only once
+    fn take_scheduled_shutdown(&mut self, now: Instant) -> bool {
+        match self.shutdown_at {
+            Some(shutdown_at) if shutdown_at <= now => {
+                self.shutdown_at = None;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Count remote I/O errors, drop chips to re-init them on the next poll if it recurs
+    fn check_remote_io(&mut self, e: &Error) -> bool {
+        if !e.is_remote_io() {
+            self.remote_io_errors = 0;
+            return false;
+        }
+        self.remote_io_errors += 1;
+        if self.remote_io_errors < REMOTE_IO_ERROR_THRESHOLD {
+            return false;
+        }
+        log::warn!("Remote I/O error {} times, re-init chips", self.remote_io_errors);
+        self.remote_io_errors = 0;
+        self.battery = None;
+        // charge counter restarts with the battery
+        self.cycles_net_charge = None;
+        self.rtc = None;
+        self.reinit_pending = true;
+        true
+    }
+
+    /// Rtc is read at the first poll, then every `rtc_poll_divisor` polls
+    fn rtc_poll_due(&mut self) -> bool {
+        let divisor = self.config.rtc_poll_divisor.unwrap_or(DEFAULT_RTC_POLL_DIVISOR).max(1);
+        let due = self.rtc_polls == 0;
+        self.rtc_polls = (self.rtc_polls + 1) % divisor;
+        due
+    }
+
+    /// Read rtc time, and charge the rtc battery
+    fn poll_rtc(&mut self, now: Instant) {
+        self.rtc_time = self.read_time().ok().map(|t| (now, t));
+
+        // rtc battery charging
+        if let Some(rtc) = &self.rtc {
+            if rtc.read_battery_low_flag().ok() == Some(true) {
+                log::debug!("Enable rtc charging");
+                let _ = rtc.toggle_charging(true);
+            } else if rtc.read_battery_high_flag().ok() == Some(true) {
+                log::debug!("Disable rtc charging");
+                let _ = rtc.toggle_charging(false);
+            }
+        }
+    }
+
+    /// Rtc time at `now`, counted from the last poll read
+    fn rtc_time_at(&self, now: Instant) -> Option<DateTime<Local>> {
+        self.rtc_time.and_then(|(read_at, t)| {
+            let elapsed = chrono::Duration::from_std(now.saturating_duration_since(read_at)).ok()?;
+            Some(t + elapsed)
+        })
+    }
+
+    /// Check safe voltage range, events are edge triggered
+    fn check_voltage_range(&mut self, voltage: f32) -> Vec<Event> {
+        let mut events = Vec::new();
+
+        let over_voltage = matches!(self.config.over_voltage, Some(v) if voltage > v);
+        if over_voltage && !self.over_voltage {
+            log::warn!("Battery over voltage: {}V", voltage);
+            events.push(Event::OverVoltage);
+        }
+        self.over_voltage = over_voltage;
+
+        let under_voltage = matches!(self.config.under_voltage, Some(v) if voltage < v);
+        if under_voltage && !self.under_voltage {
+            log::warn!("Battery under voltage: {}V", voltage);
+            events.push(Event::UnderVoltage);
+        }
+        self.under_voltage = under_voltage;
+
+        events
+    }
+
+    /// Check battery gets fully charged, returns charge full script once per charge
+    fn check_charge_full(&mut self, level: f32, power_plugged: bool) -> Option<String> {
+        if !power_plugged || level < BAT_FULL_REARM_LEVEL {
+            self.charge_full = false;
+            return None;
+        }
+        if self.charge_full || level < BAT_FULL_LEVEL {
+            return None;
+        }
+        log::info!("Battery fully charged");
+        self.charge_full = true;
+        self.config.charge_full_shell.clone()
+    }
+
+    /// Append a row to power consumption CSV log
+    fn log_csv(&mut self, now: Instant) {
+        let path = match &self.config.log_csv_path {
+            Some(path) => path,
+            None => {
+                self.csv_log = None;
+                return;
+            }
+        };
+        if self.csv_log.as_ref().map(|log| log.path()) != Some(path.as_str()) {
+            self.csv_log = Some(CsvLog::new(path, CSV_LOG_MAX_SIZE));
+        }
+        let dt = self.now_local();
+        if let (Ok(voltage), Ok(intensity), Ok(level)) = (self.voltage(), self.intensity(), self.level()) {
+            if let Some(csv_log) = &mut self.csv_log {
+                if let Err(e) = csv_log.append(now, dt, voltage, intensity, level) {
+                    log::warn!("Write csv log error: {}", e);
+                }
+            }
+        }
+    }
+
+    /// Export battery capacity/status files, a failing export is logged once per window
+    fn export_power_supply(&mut self, now: Instant) {
+        let dir = match &self.config.power_supply_dir {
+            Some(dir) => dir,
+            None => {
+                self.power_supply = None;
+                return;
+            }
+        };
+        if self.power_supply.as_ref().map(|export| export.dir()) != Some(dir.as_str()) {
+            self.power_supply = Some(PowerSupplyExport::new(dir));
+        }
+        if let (Ok(level), Ok(charging), Ok(power_plugged)) = (self.level(), self.charging(), self.power_plugged()) {
+            let status = PowerSupplyStatus::new(charging, power_plugged, level >= BAT_FULL_LEVEL);
+            if let Some(export) = &mut self.power_supply {
+                match export.write(level, status) {
+                    Ok(_) => self.power_supply_errors.clear(),
+                    Err(e) => {
+                        let msg = format!("Export power supply error: {}", e);
+                        if let Some(msg) = self.power_supply_errors.check(now, &msg) {
+                            log::warn!("{}", msg);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Read battery now, outside of the poll loop, e.g. a refresh button, subscribers get a new snapshot
+    pub fn refresh_now(&mut self) -> Result<()> {
         if self.battery.is_none() {
-            log::info!("Init battery...");
             self.init_battery()?;
         }
+        let now = self.now();
+        if let Err(e) = call_battery!(&mut self.battery, refresh, now) {
+            self.check_remote_io(&e);
+            return Err(e);
+        }
+        self.remote_io_errors = 0;
+        self.updated_at = now;
+        if self.status_tx.receiver_count() > 0 {
+            if let Ok(snapshot) = self.snapshot() {
+                self.publish_status(snapshot);
+            }
+        }
+        Ok(())
+    }
+
+    /// Pause polling, e.g. while the I2C bus is taken by a firmware update, history is kept
+    ///
+    /// Scheduled and critical battery shutdowns wait until resumed, force shutdown is refused
+    pub fn set_paused(&mut self, paused: bool) {
+        if self.paused != paused {
+            log::info!("Polling {}", if paused { "paused" } else { "resumed" });
+        }
+        self.paused = paused;
+    }
+
+    /// Polling paused
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    /// Poll hardware, then sync the network time if due, returns no events without touching the hardware while
+    /// paused
+    pub async fn poll(&mut self, now: Instant) -> Result<Vec<Event>> {
+        let events = self.poll_hardware(now)?;
+        if self.take_rtc_sync() {
+            if let Some(dt) = fetch_network_time().await {
+                self.sync_network_time(dt);
+            }
+        }
+        Ok(events)
+    }
 
+    /// Poll hardware without the network time sync, e.g. to fetch the time with a shared core unlocked
+    ///
+    /// The sync is due if `take_rtc_sync` returns true afterwards, see `fetch_network_time` and `sync_network_time`
+    pub fn poll_hardware(&mut self, now: Instant) -> Result<Vec<Event>> {
+        if self.paused {
+            log::debug!("Polling paused");
+            return Ok(Vec::new());
+        }
         // battery events
-        let mut tap = None; // tap event that returns
+        let mut poll_events = Vec::new(); // events that returns
+        if self.power_flapping {
+            self.power_flapping = false;
+            poll_events.push(Event::PowerFlapping);
+        }
+        if self.rtc.is_none() || self.battery.is_none() {
+            // the board was there, a failed re-init falls back to re-detecting
+            let reinit = std::mem::take(&mut self.reinit_pending);
+            if !reinit {
+                let interval = self.config.redetect_interval.unwrap_or(DEFAULT_REDETECT_INTERVAL);
+                self.redetect_polls += 1;
+                if self.redetect_polls < interval {
+                    return Ok(poll_events);
+                }
+            }
+            self.redetect_polls = 0;
+            if self.rtc.is_none() {
+                log::debug!("Init rtc...");
+                self.init_rtc()?;
+            }
+            if self.battery.is_none() {
+                log::debug!("Init battery...");
+                self.init_battery()?;
+            }
+            if !reinit {
+                log::info!("PiSugar detected");
+                poll_events.push(Event::Detected);
+            }
+        }
+        let mut poweroff = false;
+        let events = match call_battery!(&mut self.battery, poll, now, &self.config) {
+            Ok(events) => {
+                self.remote_io_errors = 0;
+                self.updated_at = now;
+                events
+            }
+            Err(e) => {
+                self.check_remote_io(&e);
+                return Err(e);
+            }
+        };
         let config = &self.config;
-        let events = call_battery!(&mut self.battery, poll, now, config)?;
+        let level = self.level().ok();
         for event in events {
+            let is_tap = matches!(event, BatteryEvent::TapEvent(_));
+            let mut envs = Vec::new();
             let script = match event {
                 BatteryEvent::TapEvent(tap_type) => {
-                    tap = Some(tap_type);
-                    match tap_type {
+                    poll_events.push(Event::Tap(tap_type));
+                    envs = tap_env(tap_type, level);
+                    let script = match tap_type {
                         TapType::Single => {
                             if config.single_tap_enable {
                                 Some(config.single_tap_shell.clone())
@@ -601,10 +2047,22 @@ impl PiSugarCore {
                                 None
                             }
                         }
+                    };
+                    if script.is_some() && !self.tap_cooldown.fire(tap_type, config.tap_cooldown(tap_type), now) {
+                        log::info!("Repeated {} tap in cooldown, ignored", tap_type);
+                        None
+                    } else {
+                        script
                     }
                 }
+                BatteryEvent::ButtonStuck => {
+                    log::warn!("Button stuck, taps are suppressed until released");
+                    poll_events.push(Event::ButtonStuck);
+                    None
+                }
                 BatteryEvent::SoftPowerOff => {
                     if config.soft_poweroff == Some(true) {
+                        poweroff = true;
                         Some(
                             config
                                 .soft_poweroff_shell
@@ -616,20 +2074,158 @@ impl PiSugarCore {
                     }
                 }
             };
-            if let Some(script) = script {
-                log::info!("Execute script \"{}\"", script);
-                thread::spawn(move || match execute_shell(script.as_str()) {
-                    Ok(r) => log::info!("Script ok, code: {:?}", r.code()),
-                    Err(e) => log::error!("{}", e),
-                });
+            let timeout = config.shell_timeout.map(Duration::from_secs);
+            let overlap = config.tap_script_overlap.unwrap_or(ScriptOverlap::Parallel);
+            match script {
+                Some(script) if is_tap && overlap != ScriptOverlap::Parallel => {
+                    log::info!("Queue script \"{}\"", script);
+                    if !self.tap_scripts.submit(script, envs, timeout, overlap) {
+                        log::warn!("Tap script dropped, previous ones still running");
+                    }
+                }
+                Some(script) => {
+                    log::info!("Execute script \"{}\"", script);
+                    thread::spawn(
+                        move || match execute_shell_with_timeout(script.as_str(), &envs, timeout) {
+                            Ok(r) => log::info!("Script ok, code: {:?}", r.code()),
+                            Err(e) => log::error!("{}", e),
+                        },
+                    );
+                }
+                None => {}
             }
         }
+        // scheduled shutdown
+        if self.take_scheduled_shutdown(now) && self.issue_shutdown() {
+            let script = self.config.auto_shutdown_shell();
+            log::info!("Scheduled shutdown, execute script \"{}\"", script);
+            let issued = self.shutdown_issued.clone();
+            thread::spawn(move || {
+                let ok = match execute_shell(script.as_str()) {
+                    Ok(r) => {
+                        log::info!("Script ok, code: {:?}", r.code());
+                        r.success()
+                    }
+                    Err(e) => {
+                        log::error!("{}", e);
+                        false
+                    }
+                };
+                // not shutting down, see shutdown_failed
+                if !ok {
+                    issued.store(false, Ordering::SeqCst);
+                }
+            });
+            poweroff = true;
+        }
+        if poweroff {
+            self.notify_poweroff(now);
+        }
+
+        // safe voltage range
+        if let Ok(voltage) = self.voltage_avg() {
+            poll_events.extend(self.check_voltage_range(voltage));
+        }
+
+        // power consumption log
+        self.log_csv(now);
 
         // slower
         if self.poll_check_at + Duration::from_secs(1) <= now {
             log::debug!("Poll slow");
             self.poll_check_at = now;
 
+            // power supply files for generic UPS monitors
+            self.export_power_supply(now);
+
+            // status subscribers, rtc time counted from the last read
+            if self.status_tx.receiver_count() > 0 {
+                if let Ok(snapshot) = self.snapshot_with_rtc_time(self.rtc_time_at(now)) {
+                    self.publish_status(snapshot);
+                }
+            }
+
+            // battery fully charged
+            if let (Ok(level), Ok(power_plugged)) = (self.level(), self.power_plugged()) {
+                if let Some(script) = self.check_charge_full(level, power_plugged) {
+                    log::info!("Charge full, execute script \"{}\"", script);
+                    let timeout = self.config.shell_timeout.map(Duration::from_secs);
+                    thread::spawn(
+                        move || match execute_shell_with_timeout(script.as_str(), &[], timeout) {
+                            Ok(r) => log::info!("Script ok, code: {:?}", r.code()),
+                            Err(e) => log::error!("{}", e),
+                        },
+                    );
+                }
+            }
+
+            // woken by the soonest wake alarm, arm the next one
+            if matches!(self.config.wake_queue.next(), Some(at) if at <= self.now_local()) {
+                if let Err(e) = self.rearm_wake_queue() {
+                    log::error!("Arm wake queue error: {}", e);
+                }
+            }
+
+            // graceful poweroff stalled, cut the power
+            if self.poweroff_stalled(now) {
+                log::warn!("Graceful poweroff stalled, force shutdown");
+                self.poweroff_at = None;
+                if let Err(e) = self.force_shutdown() {
+                    log::error!("Force shutdown error: {}", e);
+                }
+            }
+
+            // battery cycles from the charge counter
+            if let Some(net_charge) = self.net_charge() {
+                if self.track_cycles(net_charge) {
+                    if let Err(e) = self.save_state() {
+                        log::warn!("Save battery cycles error: {}", e);
+                    }
+                }
+            }
+
+            // critical battery, last-ditch shell then cut the power
+            let critical = match self.level() {
+                Ok(level) => self.check_critical(level, self.power_plugged().unwrap_or(false), now),
+                Err(_) => None,
+            };
+            match critical {
+                Some(CriticalAction::Launch(script)) => {
+                    log::warn!("Critical battery, execute script \"{}\"", script);
+                    let done = self.critical_done.clone();
+                    thread::spawn(move || {
+                        match execute_shell(script.as_str()) {
+                            Ok(r) => log::info!("Script ok, code: {:?}", r.code()),
+                            Err(e) => log::error!("{}", e),
+                        }
+                        done.store(true, Ordering::SeqCst);
+                    });
+                }
+                Some(CriticalAction::ForceShutdown) => {
+                    log::warn!("Critical battery, force shutdown");
+                    match self.force_shutdown() {
+                        Ok(_) => self.critical_forced = true,
+                        Err(e) => log::error!("Force shutdown error, retry on next poll: {}", e),
+                    }
+                }
+                Some(CriticalAction::Cancel) => {
+                    log::info!("Battery recovered or charger plugged in, critical shutdown cancelled");
+                }
+                None => {}
+            }
+
+            // battery recovered or power restored, re-arm auto power on
+            if let Ok(level) = self.level() {
+                let power_plugged = self.power_plugged().unwrap_or(false);
+                if self.check_auto_power_on_recovered(level, power_plugged) {
+                    log::info!("Battery recovered {} or power restored, re-arm auto power on", level);
+                    if let Err(e) = self.apply_auto_power_on(true) {
+                        log::error!("Re-arm auto power on error: {}", e);
+                        self.set_auto_power_on_disarmed(true);
+                    }
+                }
+            }
+
             // 2-led, auto allow charging
             if self.model != Model::PiSugar_3 && self.led_amount().unwrap_or(4) == 2 {
                 if let Some((changing_begin, changing_end)) = &self.config.auto_charging_range {
@@ -640,7 +2236,7 @@ impl PiSugarCore {
                         let is_ok = self.toggle_allow_charging(true).map_or("fail", |_| "ok");
                         log::info!("Battery {} <= {}, enable charging: {}", l, *changing_begin, is_ok);
                     }
-                    if (l >= *changing_end && allow_charging) || l >= 99.9 {
+                    if (l >= *changing_end && allow_charging) || l >= BAT_FULL_LEVEL {
                         let should_stop = match self.battery_full_at {
                             Some(full_at) => {
                                 let delay = Duration::from_secs(
@@ -662,35 +2258,31 @@ impl PiSugarCore {
                     }
                 }
             }
+        }
 
-            // rtc battery charging
-            if let Some(rtc) = &self.rtc {
-                if rtc.read_battery_low_flag().ok() == Some(true) {
-                    log::debug!("Enable rtc charging");
-                    let _ = rtc.toggle_charging(true);
-                } else if rtc.read_battery_high_flag().ok() == Some(true) {
-                    log::debug!("Disable rtc charging");
-                    let _ = rtc.toggle_charging(false);
-                }
-            }
+        // rtc, every rtc_poll_divisor polls
+        if self.rtc_poll_due() {
+            self.poll_rtc(now);
         }
 
         // much slower
         if self.config.auto_rtc_sync == Some(true) && self.rtc_sync_at + Duration::from_secs(10) <= now {
             self.rtc_sync_at = now;
-            if let Ok(resp) = Client::new().get(TIME_HOST.parse().unwrap()).await {
-                if let Some(date) = resp.headers().get("Date") {
-                    if let Ok(s) = date.to_str() {
-                        if let Ok(dt) = DateTime::parse_from_rfc2822(s) {
-                            sys_write_time(dt.into());
-                            let _ = self.write_time(dt.into());
-                        }
-                    }
-                }
-            }
+            self.rtc_sync_pending = true;
         }
 
-        Ok(tap)
+        Ok(poll_events)
+    }
+
+    /// Network time sync due after `poll_hardware`, reset once taken
+    pub fn take_rtc_sync(&mut self) -> bool {
+        std::mem::take(&mut self.rtc_sync_pending)
+    }
+
+    /// Write network time to system and rtc
+    pub fn sync_network_time(&self, dt: DateTime<Local>) {
+        sys_write_time(dt);
+        let _ = self.write_time(dt);
     }
 }
 
@@ -698,11 +2290,1810 @@ impl PiSugarCore {
 
 #[cfg(test)]
 mod tests {
-    use super::PiSugarConfig;
+    use std::fs;
+    use std::io;
+    use std::sync::atomic::Ordering;
+    use std::sync::{Arc, Mutex};
+    use std::time::{Duration, Instant};
+
+    use chrono::{DateTime, Local};
+    use tokio::sync::broadcast::error::TryRecvError;
+
+    use super::{
+        convert_battery_voltage_to_level, curve_segment_for, double_tap_patterns, execute_shell_with_timeout,
+        gpio_detect_tap, gpio_detect_tap_with, gpio_detect_tap_with_gap, gpio_hold_duration, gpio_push_tap, ip5209,
+        load_compensated_voltage, long_tap_polls, open_i2c, retry_i2c, retry_times, shell_command, tap_env,
+        verify_rtc_write, wait_until, BatteryChemistry, BootReason, ButtonStuckDetector, Clock, ConfigError,
+        ConfigPatch, CriticalAction, Error, Event, I2cError, Model, PiSugarConfig, PiSugarCore, RTCRawTime,
+        ShutdownMode, ShutdownState, StatusSnapshot, TapCooldown, TapType, BUTTON_STUCK_POLLS, DIAGNOSTIC_REDACTED,
+        DOUBLE_TAP_MAX_GAP, EREMOTEIO, LONG_TAP_POLLS, REMOTE_IO_ERROR_THRESHOLD, STATUS_BROADCAST_CAPACITY,
+        TAP_HISTORY_LEN,
+    };
 
     #[test]
     fn test_config() {
         let config = PiSugarConfig::default();
         assert!(serde_json::to_string(&config).is_ok())
     }
+
+    #[test]
+    fn test_config_load_errors() {
+        let dir = std::env::temp_dir().join(format!("pisugar-config-dir-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let r = PiSugarCore::new_with_path(dir.to_str().unwrap(), false, Model::PiSugar_3);
+        assert!(matches!(r, Err(Error::Config(ConfigError::NotAFile(_)))));
+        assert!(matches!(
+            PiSugarConfig::load_checked(&dir),
+            Err(ConfigError::NotAFile(_))
+        ));
+        let _ = fs::remove_dir(&dir);
+
+        let path = std::env::temp_dir().join(format!("pisugar-config-malformed-{}.json", std::process::id()));
+        fs::write(&path, "{\"auto_shutdown_level\": ").unwrap();
+        let r = PiSugarCore::new_with_path(path.to_str().unwrap(), false, Model::PiSugar_3);
+        assert!(matches!(r, Err(Error::Config(ConfigError::Parse(..)))));
+        let _ = fs::remove_file(&path);
+
+        let r = PiSugarConfig::load_checked(&path);
+        assert!(matches!(r, Err(ConfigError::Io(_, ref e)) if e.kind() == io::ErrorKind::NotFound));
+    }
+
+    #[test]
+    fn test_error_code() {
+        let path = || std::path::PathBuf::from("/etc/pisugar-server/config.json");
+        let io_error = |kind| io::Error::from(kind);
+        let cases = vec![
+            (
+                Error::I2c(I2cError::Io(io::Error::from_raw_os_error(EREMOTEIO))),
+                "i2c_not_responding",
+            ),
+            (Error::I2c(I2cError::Io(io_error(io::ErrorKind::Interrupted))), "i2c"),
+            (
+                Error::I2cUnavailable(1, I2cError::Io(io_error(io::ErrorKind::NotFound))),
+                "i2c_unavailable",
+            ),
+            (Error::Config(ConfigError::NotAFile(path())), "config_not_a_file"),
+            (
+                Error::Config(ConfigError::PermissionDenied(path())),
+                "config_permission_denied",
+            ),
+            (
+                Error::Config(ConfigError::Parse(path(), "eof".to_string())),
+                "config_invalid",
+            ),
+            (
+                Error::Config(ConfigError::Io(path(), io_error(io::ErrorKind::NotFound))),
+                "config_not_found",
+            ),
+            (
+                Error::Config(ConfigError::Io(path(), io_error(io::ErrorKind::Other))),
+                "config_io",
+            ),
+            (Error::Other("I2C not connected".to_string()), "other"),
+        ];
+        for (e, code) in cases {
+            assert_eq!(e.code(), code, "{}", e);
+        }
+    }
+
+    #[test]
+    fn test_config_json() {
+        let config = PiSugarConfig {
+            auth_user: Some("admin".to_string()),
+            auto_shutdown_level: Some(10.0),
+            shutdown_mode: Some(ShutdownMode::Custom("halt".to_string())),
+            battery_curve: Some(vec![(4.1, 100.0), (3.5, 10.0), (3.2, 0.0)]),
+            ..Default::default()
+        };
+        let s = config.to_json_pretty().unwrap();
+        let config2 = PiSugarConfig::from_json_str(&s).unwrap();
+        assert_eq!(config2.to_json_pretty().unwrap(), s);
+        assert_eq!(config2.auth_user, config.auth_user);
+        assert_eq!(config2.shutdown_mode, config.shutdown_mode);
+
+        let invalid = PiSugarConfig {
+            battery_curve: Some(vec![(4.1, 100.0), (4.1, 10.0)]),
+            ..Default::default()
+        };
+        let s = invalid.to_json_pretty().unwrap();
+        assert!(PiSugarConfig::from_json_str(&s).is_err());
+    }
+
+    #[test]
+    fn test_curve_segment_for() {
+        let curve = ip5209::BATTERY_CURVE.as_ref();
+        assert_eq!(curve_segment_for(4.2, curve), Some(0));
+        assert_eq!(curve_segment_for(4.16, curve), Some(0));
+        assert_eq!(curve_segment_for(4.1, curve), Some(1));
+        assert_eq!(curve_segment_for(3.90, curve), Some(4));
+        assert_eq!(curve_segment_for(3.86, curve), Some(4));
+        assert_eq!(curve_segment_for(3.859, curve), Some(5));
+        assert_eq!(curve_segment_for(3.1, curve), Some(9));
+        assert_eq!(curve_segment_for(3.0, curve), None);
+        assert_eq!(curve_segment_for(3.9, &[]), None);
+    }
+
+    #[test]
+    fn test_battery_chemistry() {
+        let lipo = ip5209::BATTERY_CURVE.as_ref();
+        let custom = vec![(4.0, 100.0), (3.0, 0.0)];
+        let mut config = PiSugarConfig::default();
+        assert_eq!(config.battery_curve(lipo), lipo);
+        config.battery_curve = Some(custom.clone());
+        assert_eq!(config.battery_curve(lipo), &custom[..]);
+        config.battery_chemistry = Some(BatteryChemistry::LiPo);
+        assert_eq!(config.battery_curve(lipo), lipo);
+
+        // flat LiFePO4 curve, 3.3V is half full, but almost empty for LiPo
+        config.battery_chemistry = Some(BatteryChemistry::LiFePO4);
+        let lifepo4_level = convert_battery_voltage_to_level(3.3, config.battery_curve(lipo));
+        let lipo_level = convert_battery_voltage_to_level(3.3, lipo);
+        assert!((lifepo4_level - 50.0).abs() < 0.1);
+        assert!(lipo_level < 5.0);
+        assert!((convert_battery_voltage_to_level(3.45, config.battery_curve(lipo)) - 100.0).abs() < 0.1);
+
+        let config = PiSugarConfig::from_json_str(r#"{"battery_chemistry": "lifepo4"}"#).unwrap();
+        assert_eq!(config.battery_chemistry, Some(BatteryChemistry::LiFePO4));
+    }
+
+    #[test]
+    fn test_load_compensated_voltage() {
+        let curve = ip5209::BATTERY_CURVE.as_ref();
+        // 1.5A discharge through 0.1 Ohm sags 0.15V
+        assert!((load_compensated_voltage(3.6, 1.5, 0.1) - 3.75).abs() < 1e-6);
+        let level = convert_battery_voltage_to_level(load_compensated_voltage(3.6, 1.5, 0.1), curve);
+        assert!(level > convert_battery_voltage_to_level(3.6, curve) + 5.0);
+
+        // charging reads high instead
+        assert!(load_compensated_voltage(4.1, -1.0, 0.1) < 4.1);
+
+        // default resistance of 0 is the plain level
+        let r = PiSugarConfig::default().internal_resistance.unwrap_or(0.0);
+        assert_eq!(load_compensated_voltage(3.6, 1.5, r), 3.6);
+
+        #[cfg(feature = "mock-hardware")]
+        {
+            let config = PiSugarConfig {
+                internal_resistance: Some(0.1),
+                ..Default::default()
+            };
+
+            // IP5209, 3.6V and 1.5A discharge, signed intensity
+            let mut core = PiSugarCore::new(config.clone(), Model::PiSugar_2_4LEDs).unwrap();
+            let battery = ip5209::IP5209Battery::mock(config.clone(), Model::PiSugar_2_4LEDs);
+            // 3724 counts, 3.6001V
+            battery.set_register(0xa2, 0x8c);
+            battery.set_register(0xa3, 0x0e);
+            // -2011 counts in 14 bit two's complement, -1.5002A
+            battery.set_register(0xa4, 0x25);
+            battery.set_register(0xa5, 0x38);
+            core.battery = Some(Box::new(battery));
+            core.refresh_now().unwrap();
+            assert!(core.intensity_avg().unwrap() < 0.0);
+            assert!(core.level_load_compensated().unwrap() > core.level().unwrap() + 5.0);
+
+            // PiSugar 3, 3.6V and 1.5A output current, unsigned
+            let mut core = PiSugarCore::new(config.clone(), Model::PiSugar_3).unwrap();
+            let battery = crate::pisugar3::PiSugar3Battery::mock(config.clone(), Model::PiSugar_3);
+            battery.set_register(0x22, 0x0e);
+            battery.set_register(0x23, 0x10);
+            battery.set_register(0x26, 0x05);
+            battery.set_register(0x27, 0xdc);
+            core.battery = Some(Box::new(battery));
+            core.refresh_now().unwrap();
+            assert!(core.intensity_avg().unwrap() > 0.0);
+            assert!(core.level_load_compensated().unwrap() > core.level().unwrap() + 5.0);
+
+            // no compensation while external power is plugged in, ctr1 bit7
+            let battery = crate::pisugar3::PiSugar3Battery::mock(config.clone(), Model::PiSugar_3);
+            battery.set_register(0x02, 0x80);
+            battery.set_register(0x22, 0x0e);
+            battery.set_register(0x23, 0x10);
+            battery.set_register(0x26, 0x05);
+            battery.set_register(0x27, 0xdc);
+            core.battery = Some(Box::new(battery));
+            core.refresh_now().unwrap();
+            assert_eq!(core.level_load_compensated().unwrap(), core.level().unwrap());
+        }
+    }
+
+    #[test]
+    fn test_calibrate_voltage() {
+        let curve = ip5209::BATTERY_CURVE.as_ref();
+        let mut config = PiSugarConfig::default();
+        assert_eq!(config.calibrate_voltage(3.7), 3.7);
+
+        config.voltage_offset = Some(0.1);
+        let v = config.calibrate_voltage(3.7);
+        assert!((v - 3.8).abs() < 1e-6);
+        let level = convert_battery_voltage_to_level(v, curve);
+        assert!(level > convert_battery_voltage_to_level(3.7, curve));
+        assert!((level - convert_battery_voltage_to_level(3.8, curve)).abs() < 0.01);
+
+        config.voltage_scale = Some(1.02);
+        assert!((config.calibrate_voltage(4.0) - 4.18).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_update_config() {
+        let config = PiSugarConfig {
+            auto_shutdown_level: Some(10.0),
+            auto_shutdown_delay: Some(30.0),
+            single_tap_enable: true,
+            single_tap_shell: "echo".to_string(),
+            ..Default::default()
+        };
+        let mut core = PiSugarCore::new(config, Model::PiSugar_3).unwrap();
+        let path = std::env::temp_dir().join(format!("pisugar-update-config-{}.json", std::process::id()));
+        core.config_path = Some(path.to_string_lossy().to_string());
+
+        let patch = ConfigPatch {
+            auto_shutdown_level: Some(Some(15.0)),
+            ..Default::default()
+        };
+        core.update_config(patch).unwrap();
+        assert_eq!(core.config().auto_shutdown_level, Some(15.0));
+        assert_eq!(core.config().auto_shutdown_delay, Some(30.0));
+        assert!(core.config().single_tap_enable);
+        assert_eq!(core.config().single_tap_shell, "echo");
+
+        let mut saved = PiSugarConfig::default();
+        saved.load(&path).unwrap();
+        assert_eq!(saved.auto_shutdown_level, Some(15.0));
+        assert_eq!(saved.single_tap_shell, "echo");
+
+        // invalid patch is not applied
+        let patch = ConfigPatch {
+            auto_charging_range: Some(Some((90.0, 60.0))),
+            ..Default::default()
+        };
+        assert!(core.update_config(patch).is_err());
+        assert_eq!(core.config().auto_charging_range, None);
+
+        // null clears an optional field, a missing one is kept
+        let patch: ConfigPatch = serde_json::from_str(r#"{"auto_shutdown_delay": null}"#).unwrap();
+        assert_eq!(patch.auto_shutdown_delay, Some(None));
+        assert_eq!(patch.auto_shutdown_level, None);
+        core.update_config(patch).unwrap();
+        assert_eq!(core.config().auto_shutdown_delay, None);
+        assert_eq!(core.config().auto_shutdown_level, Some(15.0));
+        let value = serde_json::to_value(ConfigPatch {
+            auto_shutdown_delay: Some(None),
+            ..Default::default()
+        })
+        .unwrap();
+        assert_eq!(value, serde_json::json!({ "auto_shutdown_delay": null }));
+
+        // hardware apply fails without battery, old config stays active and saved
+        let patch = ConfigPatch {
+            auto_shutdown_level: Some(Some(20.0)),
+            auto_power_on: Some(Some(true)),
+            ..Default::default()
+        };
+        assert!(core.update_config(patch).is_err());
+        assert_eq!(core.config().auto_shutdown_level, Some(15.0));
+        assert_eq!(core.config().auto_power_on, None);
+        let mut saved = PiSugarConfig::default();
+        saved.load(&path).unwrap();
+        assert_eq!(saved.auto_shutdown_level, Some(15.0));
+        assert_eq!(saved.auto_power_on, None);
+
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[cfg(feature = "mock-hardware")]
+    #[test]
+    fn test_auto_wake_disabled() {
+        let config = PiSugarConfig {
+            auto_wake_time: Some(Local::now()),
+            auto_wake_repeat: 0b0111_1111,
+            ..Default::default()
+        };
+        assert!(config.auto_wake_enabled());
+        let mut core = PiSugarCore::new(config.clone(), Model::PiSugar_2_Pro).unwrap();
+        core.rtc = Some(Box::new(crate::SD3078::mock(config)));
+        let path = std::env::temp_dir().join(format!("pisugar-auto-wake-{}.json", std::process::id()));
+        core.config_path = Some(path.to_string_lossy().to_string());
+        let alarm_enabled = |core: &PiSugarCore| core.rtc.as_ref().unwrap().is_alarm_enable().unwrap();
+
+        // unrelated change doesn't touch the alarm
+        let patch = ConfigPatch {
+            auto_rtc_sync: Some(Some(true)),
+            ..Default::default()
+        };
+        core.update_config(patch).unwrap();
+        assert!(!alarm_enabled(&core));
+
+        // new wake time arms the alarm
+        let patch = ConfigPatch {
+            auto_wake_time: Some(Some(Local::now() + chrono::Duration::hours(1))),
+            ..Default::default()
+        };
+        core.update_config(patch).unwrap();
+        assert!(alarm_enabled(&core));
+
+        // no weekday left, the armed alarm is disabled
+        let patch = ConfigPatch {
+            auto_wake_repeat: Some(0),
+            ..Default::default()
+        };
+        core.update_config(patch).unwrap();
+        assert!(!core.config().auto_wake_enabled());
+        assert!(!alarm_enabled(&core));
+
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_verify_rtc_write() {
+        let written = RTCRawTime::from_dec([30, 15, 10, 0, 1, 5, 22]);
+        assert!(verify_rtc_write(written, written).is_ok());
+        let read = RTCRawTime::from_dec([31, 15, 10, 0, 1, 5, 22]);
+        assert!(verify_rtc_write(written, read).is_ok());
+
+        // write protect not disabled, old time read back
+        let read = RTCRawTime::from_dec([5, 0, 8, 0, 1, 1, 21]);
+        assert!(verify_rtc_write(written, read).is_err());
+        let read = RTCRawTime::from_dec([33, 15, 10, 0, 1, 5, 22]);
+        assert!(verify_rtc_write(written, read).is_err());
+    }
+
+    #[test]
+    fn test_auto_shutdown_disabled() {
+        let mut config = PiSugarConfig::default();
+        assert!(!config.auto_shutdown_enabled());
+        assert!(!config.is_auto_shutdown_level(0.0));
+
+        for level in &[0.0, -1.0] {
+            config.auto_shutdown_level = Some(*level);
+            assert!(!config.auto_shutdown_enabled());
+            assert!(!config.is_auto_shutdown_level(0.0));
+        }
+
+        config.auto_shutdown_level = Some(10.0);
+        assert!(config.is_auto_shutdown_level(0.0));
+        assert!(config.is_auto_shutdown_level(9.5));
+        assert!(!config.is_auto_shutdown_level(10.0));
+    }
+
+    #[test]
+    fn test_shutdown_mode() {
+        let mut config = PiSugarConfig::default();
+        assert_eq!(config.auto_shutdown_shell(), "shutdown --poweroff 0");
+        config.soft_poweroff_shell = Some("poweroff".to_string());
+        assert_eq!(config.auto_shutdown_shell(), "poweroff");
+
+        config.shutdown_mode = Some(ShutdownMode::Poweroff);
+        assert_eq!(config.auto_shutdown_shell(), "shutdown --poweroff 0");
+        config.shutdown_mode = Some(ShutdownMode::Halt);
+        assert_eq!(config.auto_shutdown_shell(), "shutdown --halt 0");
+        config.shutdown_mode = Some(ShutdownMode::Custom("ups-halt".to_string()));
+        assert_eq!(config.auto_shutdown_shell(), "ups-halt");
+
+        let mode: ShutdownMode = serde_json::from_str("\"halt\"").unwrap();
+        assert_eq!(mode, ShutdownMode::Halt);
+        let mode: ShutdownMode = serde_json::from_str("{\"custom\": \"ups-halt\"}").unwrap();
+        assert_eq!(mode, ShutdownMode::Custom("ups-halt".to_string()));
+    }
+
+    #[test]
+    fn test_double_tap_patterns() {
+        let patterns = double_tap_patterns(2);
+        for pattern in &["1010", "10010", "10110", "100110", "101110", "1001110"] {
+            assert!(patterns.iter().any(|p| p == pattern));
+        }
+        assert_eq!(patterns.len(), 6);
+    }
+
+    #[test]
+    fn test_double_tap_max_gap() {
+        // default, gap of 3 polls is two single taps
+        let mut history = "10001".to_string();
+        assert_eq!(gpio_detect_tap(&mut history), Some(TapType::Single));
+
+        let mut history = String::new();
+        let mut taps = Vec::new();
+        for c in "1000100000".chars() {
+            history.push(c);
+            taps.extend(gpio_detect_tap_with_gap(&mut history, 3));
+        }
+        assert_eq!(taps, vec![TapType::Double]);
+    }
+
+    #[test]
+    fn test_long_tap_polls() {
+        assert_eq!(long_tap_polls(Duration::from_secs(2), Duration::from_millis(100)), 20);
+        assert_eq!(
+            long_tap_polls(Duration::from_millis(250), Duration::from_millis(100)),
+            3
+        );
+        assert_eq!(
+            long_tap_polls(Duration::from_secs(10), Duration::from_millis(100)),
+            TAP_HISTORY_LEN - 1
+        );
+
+        let config = PiSugarConfig {
+            long_press_duration: Some(2.0),
+            ..Default::default()
+        };
+        assert_eq!(config.long_tap_polls(), 20);
+        assert_eq!(PiSugarConfig::default().long_tap_polls(), LONG_TAP_POLLS);
+
+        // held 1.9s is not long enough
+        let mut history = format!("{}0", "1".repeat(19));
+        assert_eq!(gpio_detect_tap_with(&mut history, DOUBLE_TAP_MAX_GAP, 20), None);
+        let mut history = format!("{}0", "1".repeat(20));
+        assert_eq!(
+            gpio_detect_tap_with(&mut history, DOUBLE_TAP_MAX_GAP, 20),
+            Some(TapType::Long)
+        );
+
+        let mut history = "111111110".to_string();
+        assert_eq!(gpio_detect_tap(&mut history), Some(TapType::Long));
+    }
+
+    #[test]
+    fn test_execute_shell_with_timeout() {
+        let status = execute_shell_with_timeout("exit 3", &[], Some(Duration::from_secs(5))).unwrap();
+        assert_eq!(status.code(), Some(3));
+
+        let start = Instant::now();
+        let e = execute_shell_with_timeout("sleep 10", &[], Some(Duration::from_millis(200))).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::TimedOut);
+        assert!(start.elapsed() < Duration::from_secs(5));
+
+        // background job of the script is killed too
+        let pid_file = std::env::temp_dir().join(format!("pisugar-shell-timeout-{}", std::process::id()));
+        let shell = format!("sleep 30 & echo $! > {}; wait", pid_file.display());
+        let e = execute_shell_with_timeout(&shell, &[], Some(Duration::from_millis(200))).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::TimedOut);
+        let pid = fs::read_to_string(&pid_file).unwrap().trim().to_string();
+        // gone, or a zombie nobody reaped yet
+        let dead = || match fs::read_to_string(format!("/proc/{}/stat", pid)) {
+            Ok(stat) => stat.split_whitespace().nth(2) == Some("Z"),
+            Err(_) => true,
+        };
+        assert!(wait_until(Duration::from_secs(1), Duration::from_millis(10), dead));
+        let _ = fs::remove_file(&pid_file);
+    }
+
+    #[test]
+    fn test_tap_env() {
+        let envs = tap_env(TapType::Double, Some(77.6));
+        assert_eq!(
+            envs,
+            vec![
+                ("PISUGAR_TAP", "double".to_string()),
+                ("PISUGAR_LEVEL", "78".to_string())
+            ]
+        );
+        assert_eq!(tap_env(TapType::Long, None), vec![("PISUGAR_TAP", "long".to_string())]);
+
+        let command = shell_command("echo $PISUGAR_TAP", &tap_env(TapType::Single, Some(50.0)));
+        let command_envs: Vec<_> = command.get_envs().collect();
+        assert!(command_envs.contains(&("PISUGAR_TAP".as_ref(), Some("single".as_ref()))));
+        assert!(command_envs.contains(&("PISUGAR_LEVEL".as_ref(), Some("50".as_ref()))));
+        let args: Vec<_> = command.get_args().collect();
+        assert_eq!(args, ["-c", "echo $PISUGAR_TAP"]);
+    }
+
+    #[test]
+    fn test_gpio_hold_duration() {
+        let mut history = "0010".to_string();
+        assert_eq!(gpio_hold_duration(&history), None);
+
+        let mut last = Duration::from_secs(0);
+        for _ in 0..10 {
+            history.push('1');
+            let d = gpio_hold_duration(&history).unwrap();
+            assert!(d > last);
+            last = d;
+        }
+        assert_eq!(last, Duration::from_secs(1));
+
+        history.push('0');
+        assert_eq!(gpio_hold_duration(&history), None);
+    }
+
+    #[test]
+    fn test_tap_cooldown() {
+        let config = PiSugarConfig {
+            single_tap_cooldown: Some(10.0),
+            long_tap_cooldown: Some(2.0),
+            ..Default::default()
+        };
+        let mut cooldown = TapCooldown::default();
+        let now = Instant::now();
+        let at = |secs| now + Duration::from_secs(secs);
+        let mut fire = |tap_type, secs| cooldown.fire(tap_type, config.tap_cooldown(tap_type), at(secs));
+
+        assert!(fire(TapType::Single, 0));
+        assert!(fire(TapType::Long, 0));
+        assert!(fire(TapType::Double, 0));
+        assert!(!fire(TapType::Single, 1));
+        assert!(!fire(TapType::Long, 1));
+        assert!(fire(TapType::Double, 1));
+
+        // long cooled down, single still cooling down
+        assert!(fire(TapType::Long, 3));
+        assert!(!fire(TapType::Single, 9));
+        assert!(fire(TapType::Single, 10));
+        assert!(!fire(TapType::Single, 11));
+
+        // overflows Instant
+        let mut cooldown = TapCooldown::default();
+        assert!(cooldown.fire(TapType::Single, Some(Duration::MAX), now));
+        assert!(!cooldown.fire(TapType::Single, Some(Duration::MAX), at(1)));
+
+        assert!(PiSugarConfig::from_json_str(r#"{"single_tap_cooldown": -1}"#).is_err());
+        assert!(PiSugarConfig::from_json_str(r#"{"long_tap_cooldown": 1e300}"#).is_err());
+        assert!(PiSugarConfig::from_json_str(r#"{"double_tap_cooldown": 0.5}"#).is_ok());
+    }
+
+    #[test]
+    fn test_remote_io_reinit() {
+        let mut core = PiSugarCore::new(PiSugarConfig::default(), Model::PiSugar_3).unwrap();
+        let remote_io = || Error::I2c(I2cError::Io(io::Error::from_raw_os_error(EREMOTEIO)));
+        assert!(remote_io().is_remote_io());
+
+        for _ in 1..REMOTE_IO_ERROR_THRESHOLD {
+            assert!(!core.check_remote_io(&remote_io()));
+        }
+        assert!(core.check_remote_io(&remote_io()));
+
+        // other errors reset the count
+        for _ in 1..REMOTE_IO_ERROR_THRESHOLD {
+            assert!(!core.check_remote_io(&remote_io()));
+        }
+        assert!(!core.check_remote_io(&Error::Other("other".to_string())));
+        assert!(!core.check_remote_io(&remote_io()));
+    }
+
+    #[test]
+    fn test_wait_until() {
+        // bus answers after the 3rd probe
+        let mut probes = 0;
+        let answered = wait_until(Duration::from_secs(1), Duration::from_millis(1), || {
+            probes += 1;
+            probes >= 3
+        });
+        assert!(answered);
+        assert_eq!(probes, 3);
+
+        let answered = wait_until(Duration::from_millis(10), Duration::from_millis(1), || false);
+        assert!(!answered);
+    }
+
+    #[test]
+    fn test_retry_times() {
+        // detected at the last retry
+        let mut probes = 0;
+        let detected = retry_times(3, Duration::from_millis(1), || {
+            probes += 1;
+            probes > 3
+        });
+        assert!(detected);
+        assert_eq!(probes, 4);
+
+        let mut probes = 0;
+        let detected = retry_times(3, Duration::from_millis(1), || {
+            probes += 1;
+            false
+        });
+        assert!(!detected);
+        assert_eq!(probes, 4);
+
+        let mut probes = 0;
+        assert!(!retry_times(0, Duration::from_millis(1), || {
+            probes += 1;
+            false
+        }));
+        assert_eq!(probes, 1);
+    }
+
+    #[test]
+    fn test_button_stuck() {
+        let mut detector = ButtonStuckDetector::default();
+        let mut history = String::with_capacity(30);
+        let mut stuck_events = 0;
+        let mut taps = Vec::new();
+        for _ in 0..(BUTTON_STUCK_POLLS * 3) {
+            if detector.update(true) {
+                stuck_events += 1;
+            }
+            if history.len() >= history.capacity() {
+                history.remove(0);
+            }
+            history.push('1');
+            if detector.is_stuck() {
+                history.clear();
+            } else {
+                taps.extend(gpio_detect_tap(&mut history));
+            }
+        }
+        assert_eq!(stuck_events, 1);
+        assert!(taps.is_empty());
+
+        // released
+        assert!(!detector.update(false));
+        assert!(!detector.is_stuck());
+    }
+
+    #[cfg(feature = "mock-hardware")]
+    #[tokio::test]
+    async fn test_poll_button_stuck() {
+        use crate::i2c::I2c;
+
+        let config = PiSugarConfig {
+            i2c_bus: 11,
+            ..Default::default()
+        };
+        I2c::plug_bus(11);
+        let mut chip = I2c::with_bus(11).unwrap();
+        chip.set_slave_address(crate::I2C_ADDR_BAT).unwrap();
+        // 3000 voltage counts
+        chip.smbus_write_byte(0xa2, 0xb8).unwrap();
+        chip.smbus_write_byte(0xa3, 0x0b).unwrap();
+        let mut core = PiSugarCore::new(config, Model::PiSugar_2_4LEDs).unwrap();
+
+        let mut now = core.now();
+        let mut events = Vec::new();
+        // (GPIO4, polls), held high then released, reported once and no long tap
+        for (gpio, polls) in [(0x10, BUTTON_STUCK_POLLS * 2), (0x00, 30)].iter() {
+            chip.smbus_write_byte(0x55, *gpio).unwrap();
+            for _ in 0..*polls {
+                now += Duration::from_millis(100);
+                events.extend(core.poll(now).await.unwrap());
+            }
+        }
+        assert_eq!(events, vec![Event::ButtonStuck]);
+
+        // taps are detected again
+        events.clear();
+        for (gpio, polls) in [(0x10, 2), (0x00, 30)].iter() {
+            chip.smbus_write_byte(0x55, *gpio).unwrap();
+            for _ in 0..*polls {
+                now += Duration::from_millis(100);
+                events.extend(core.poll(now).await.unwrap());
+            }
+        }
+        assert_eq!(events, vec![Event::Tap(TapType::Single)]);
+    }
+
+    #[test]
+    fn test_invert_button() {
+        // active-low, idle gpio reads 1
+        let mut history = String::with_capacity(30);
+        for _ in 0..20 {
+            assert!(!gpio_push_tap(&mut history, true, true));
+            assert_eq!(gpio_detect_tap(&mut history), None);
+        }
+        assert_eq!(history, "0".repeat(20));
+
+        // held low for 1s, then released
+        let mut taps = Vec::new();
+        for poll in 0..12 {
+            let pressed = gpio_push_tap(&mut history, poll >= 10, true);
+            assert_eq!(pressed, poll < 10);
+            taps.extend(gpio_detect_tap(&mut history));
+        }
+        assert_eq!(taps, vec![TapType::Long]);
+
+        // same stream without inversion never releases
+        let mut history = String::with_capacity(30);
+        for poll in 0..12 {
+            gpio_push_tap(&mut history, poll >= 10, false);
+            assert_eq!(gpio_detect_tap(&mut history), None);
+        }
+
+        assert_eq!(PiSugarConfig::default().invert_button, None);
+        let config = PiSugarConfig::from_json_str(r#"{"invert_button": true}"#).unwrap();
+        assert_eq!(config.invert_button, Some(true));
+    }
+
+    #[cfg(feature = "mock-hardware")]
+    #[tokio::test]
+    async fn test_poweroff_stalled() {
+        use crate::i2c::I2c;
+
+        let config = PiSugarConfig {
+            i2c_bus: 10,
+            force_shutdown_timeout: Some(10),
+            ..Default::default()
+        };
+        I2c::plug_bus(10);
+        let mut core = PiSugarCore::new_without_init(config, Model::PiSugar_3).unwrap();
+
+        // no poweroff issued
+        let now = core.now();
+        core.poll(now + Duration::from_secs(60)).await.unwrap();
+        assert_eq!(core.shutdown_state(), ShutdownState::None);
+
+        core.notify_poweroff(now + Duration::from_secs(60));
+        core.notify_poweroff(now + Duration::from_secs(65));
+        core.poll(now + Duration::from_secs(69)).await.unwrap();
+        assert_eq!(core.shutdown_state(), ShutdownState::GracefulPending);
+
+        core.poll(now + Duration::from_secs(70)).await.unwrap();
+        assert_eq!(core.shutdown_state(), ShutdownState::Forcing);
+
+        // watchdog disabled
+        let mut config = core.config().clone();
+        config.force_shutdown_timeout = None;
+        let mut core = PiSugarCore::new_without_init(config, Model::PiSugar_3).unwrap();
+        core.notify_poweroff(now);
+        core.poll(now + Duration::from_secs(60)).await.unwrap();
+        assert_eq!(core.shutdown_state(), ShutdownState::GracefulPending);
+    }
+
+    #[test]
+    fn test_check_critical() {
+        let config = PiSugarConfig {
+            critical_level: Some(3.0),
+            critical_shell: Some("sync-db".to_string()),
+            critical_timeout: Some(10),
+            ..Default::default()
+        };
+        let mut core = PiSugarCore::new(config, Model::PiSugar_3).unwrap();
+
+        let now = Instant::now();
+        let at = |secs| now + Duration::from_secs(secs);
+        assert_eq!(core.check_critical(50.0, false, now), None);
+        assert_eq!(
+            core.check_critical(2.5, false, now),
+            Some(CriticalAction::Launch("sync-db".to_string()))
+        );
+        assert_eq!(core.check_critical(2.5, false, at(9)), None);
+        assert_eq!(
+            core.check_critical(2.5, false, at(10)),
+            Some(CriticalAction::ForceShutdown)
+        );
+        // force shutdown failed, retried
+        assert_eq!(
+            core.check_critical(2.5, false, at(11)),
+            Some(CriticalAction::ForceShutdown)
+        );
+        core.critical_forced = true;
+        assert_eq!(core.check_critical(2.5, false, at(12)), None);
+
+        // battery recovered before the deadline
+        let mut core = PiSugarCore::new(core.config().clone(), Model::PiSugar_3).unwrap();
+        assert!(matches!(
+            core.check_critical(2.5, false, now),
+            Some(CriticalAction::Launch(_))
+        ));
+        assert_eq!(core.check_critical(50.0, false, at(5)), Some(CriticalAction::Cancel));
+        assert_eq!(core.check_critical(50.0, false, at(10)), None);
+
+        // charger plugged in, the shell finishing late doesn't force the next one
+        assert!(matches!(
+            core.check_critical(2.5, false, at(20)),
+            Some(CriticalAction::Launch(_))
+        ));
+        let done = core.critical_done.clone();
+        assert_eq!(core.check_critical(2.5, true, at(21)), Some(CriticalAction::Cancel));
+        assert_eq!(core.check_critical(2.5, true, at(40)), None);
+        assert!(matches!(
+            core.check_critical(2.5, false, at(41)),
+            Some(CriticalAction::Launch(_))
+        ));
+        done.store(true, Ordering::SeqCst);
+        assert_eq!(core.check_critical(2.5, false, at(42)), None);
+
+        // shell done before deadline
+        core.critical_done.store(true, Ordering::SeqCst);
+        assert_eq!(
+            core.check_critical(2.5, false, at(43)),
+            Some(CriticalAction::ForceShutdown)
+        );
+
+        // no shell
+        let mut core = PiSugarCore::new(core.config().clone(), Model::PiSugar_3).unwrap();
+        core.config_mut().critical_shell = None;
+        assert_eq!(
+            core.check_critical(2.5, false, now),
+            Some(CriticalAction::ForceShutdown)
+        );
+
+        // timeout overflows Instant
+        let mut core = PiSugarCore::new(core.config().clone(), Model::PiSugar_3).unwrap();
+        core.config_mut().critical_shell = Some("sync-db".to_string());
+        core.config_mut().critical_timeout = Some(u64::MAX);
+        assert!(matches!(
+            core.check_critical(2.5, false, now),
+            Some(CriticalAction::Launch(_))
+        ));
+        assert_eq!(core.check_critical(2.5, false, at(3600)), None);
+    }
+
+    #[test]
+    fn test_track_cycles() {
+        let config = PiSugarConfig {
+            battery_capacity: Some(1000.0),
+            ..Default::default()
+        };
+        let mut core = PiSugarCore::new(config, Model::PiSugar_3).unwrap();
+        core.state.battery_cycles = 2.0;
+        assert!(!core.track_cycles(0.3));
+        assert_eq!(core.estimated_cycles(), 2.0);
+
+        // discharge 1Ah, in 10mAh steps
+        let mut saves = 0;
+        for i in 1..=100 {
+            if core.track_cycles(0.3 - i as f64 * 0.01) {
+                saves += 1;
+            }
+        }
+        assert!((core.estimated_cycles() - 3.0).abs() < 1e-6);
+        assert!((9..=10).contains(&saves));
+
+        // charging it back doesn't count again
+        assert!(!core.track_cycles(0.3));
+        assert!((core.estimated_cycles() - 3.0).abs() < 1e-6);
+        core.track_cycles(-0.2);
+        assert!((core.estimated_cycles() - 3.5).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_check_voltage_range() {
+        let config = PiSugarConfig {
+            over_voltage: Some(4.25),
+            under_voltage: Some(3.0),
+            ..Default::default()
+        };
+        let mut core = PiSugarCore::new(config, Model::PiSugar_3).unwrap();
+
+        assert!(core.check_voltage_range(4.0).is_empty());
+        assert_eq!(core.check_voltage_range(4.3), vec![Event::OverVoltage]);
+        assert!(core.check_voltage_range(4.3).is_empty());
+        assert!(core.check_voltage_range(4.1).is_empty());
+        assert_eq!(core.check_voltage_range(4.3), vec![Event::OverVoltage]);
+        assert_eq!(core.check_voltage_range(2.9), vec![Event::UnderVoltage]);
+        assert!(core.check_voltage_range(2.8).is_empty());
+    }
+
+    #[test]
+    fn test_check_charge_full() {
+        let config = PiSugarConfig {
+            charge_full_shell: Some("notify-send full".to_string()),
+            ..Default::default()
+        };
+        let mut core = PiSugarCore::new(config, Model::PiSugar_3).unwrap();
+
+        let levels = [90.0, 98.0, 100.0, 100.0, 99.95, 100.0];
+        let fired: Vec<_> = levels
+            .iter()
+            .filter_map(|level| core.check_charge_full(*level, true))
+            .collect();
+        assert_eq!(fired, vec!["notify-send full".to_string()]);
+
+        // rearmed after unplugged
+        assert_eq!(core.check_charge_full(100.0, false), None);
+        assert!(core.check_charge_full(100.0, true).is_some());
+
+        // rearmed after discharged
+        assert_eq!(core.check_charge_full(96.0, true), None);
+        assert_eq!(core.check_charge_full(100.0, true), None);
+        assert_eq!(core.check_charge_full(94.0, true), None);
+        assert!(core.check_charge_full(100.0, true).is_some());
+    }
+
+    #[test]
+    fn test_i2c_unavailable() {
+        match open_i2c(250, 0x57) {
+            Err(Error::I2cUnavailable(250, _)) => {}
+            _ => panic!("i2c bus 250 should be unavailable"),
+        }
+
+        let e = Error::I2cUnavailable(1, I2cError::Io(io::Error::from(io::ErrorKind::NotFound)));
+        assert!(e.to_string().contains("raspi-config"));
+
+        let e = Error::I2c(I2cError::Io(io::Error::from_raw_os_error(EREMOTEIO)));
+        assert!(e.to_string().contains("not responding"));
+        assert!(!e.to_string().contains("raspi-config"));
+    }
+
+    #[test]
+    fn test_boot_reason() {
+        assert_eq!(BootReason::from(true), BootReason::AlarmWake);
+        assert_eq!(BootReason::from(false), BootReason::ManualPowerOn);
+
+        let mut core = PiSugarCore::new(PiSugarConfig::default(), Model::PiSugar_3).unwrap();
+        // no rtc
+        core.boot_alarm_flag = None;
+        assert_eq!(core.boot_reason(), BootReason::Unknown);
+        assert_eq!(BootReason::Unknown.to_string(), "unknown");
+        core.boot_alarm_flag = Some(false);
+        assert_eq!(core.boot_reason(), BootReason::ManualPowerOn);
+        // flag read at init is kept, even if it can't be cleared now
+        core.boot_alarm_flag = Some(true);
+        assert_eq!(core.boot_reason(), BootReason::AlarmWake);
+    }
+
+    #[tokio::test]
+    async fn test_power_flapping() {
+        let mut core = PiSugarCore::new(PiSugarConfig::default(), Model::PiSugar_3).unwrap();
+        // no rtc
+        assert!(core.record_boot().is_err());
+
+        let t0 = Local::now();
+        let boots: Vec<bool> = [("a", 0), ("b", 50), ("c", 100), ("d", 150)]
+            .iter()
+            .map(|(id, s)| core.record_boot_at(id, t0 + chrono::Duration::seconds(*s)).unwrap())
+            .collect();
+        assert_eq!(boots, vec![false, false, false, true]);
+        let now = core.now();
+        assert_eq!(core.poll(now).await.unwrap(), vec![Event::PowerFlapping]);
+        assert!(core.poll(now).await.unwrap().is_empty());
+
+        // server restarted within the same boot
+        assert!(!core.record_boot_at("d", t0 + chrono::Duration::seconds(160)).unwrap());
+        assert!(core.poll(now).await.unwrap().is_empty());
+
+        // booted a while later
+        assert!(!core.record_boot_at("e", t0 + chrono::Duration::seconds(600)).unwrap());
+
+        core.config_mut().power_flapping_interval = Some(0);
+        assert!(!core.record_boot_at("f", t0 + chrono::Duration::seconds(610)).unwrap());
+    }
+
+    #[test]
+    fn test_status_broadcast() {
+        let core = PiSugarCore::new(PiSugarConfig::default(), Model::PiSugar_3).unwrap();
+        let mut fast = core.subscribe();
+        let mut slow = core.subscribe();
+        let snapshot = |level| StatusSnapshot::for_test(Model::PiSugar_3, level);
+
+        let frames = STATUS_BROADCAST_CAPACITY * 2;
+        for i in 0..frames {
+            core.publish_status(snapshot(i as f32));
+            assert_eq!(fast.try_recv().unwrap().level, i as f32);
+        }
+
+        // slow subscriber missed old snapshots, then catches up with the newest
+        assert!(matches!(slow.try_recv(), Err(TryRecvError::Lagged(_))));
+        let first = slow.try_recv().unwrap().level;
+        assert_eq!(first, (frames - STATUS_BROADCAST_CAPACITY) as f32);
+        while let Ok(s) = slow.try_recv() {
+            assert!(s.level > first);
+        }
+    }
+
+    #[test]
+    fn test_info() {
+        let core = PiSugarCore::new(PiSugarConfig::default(), Model::PiSugar_3).unwrap();
+        let info = core.info();
+        assert!(!info.version.is_empty());
+        assert_eq!(info.model, Model::PiSugar_3.to_string());
+        assert_eq!(info.battery_i2c_addr, 0x57);
+        assert!(serde_json::to_string(&info).is_ok());
+    }
+
+    #[test]
+    fn test_board() {
+        let core = PiSugarCore::new(PiSugarConfig::default(), Model::PiSugar_2_Pro).unwrap();
+        let board = core.board();
+        assert_eq!(board.model, Model::PiSugar_2_Pro);
+        assert_eq!(board.battery_chip, "IP5312");
+        assert_eq!(board.rtc_chip, "SD3078");
+        assert_eq!(board.battery_i2c_addr, 0x75);
+        assert_eq!(board.rtc_i2c_addr, 0x32);
+        assert_eq!(board.to_string(), "PiSugar 2 Pro | IP5312 0x75 | SD3078 0x32");
+        assert_eq!(
+            serde_json::to_string(&board).unwrap(),
+            r#"{"model":"PiSugar 2 Pro","battery_chip":"IP5312","rtc_chip":"SD3078","battery_i2c_addr":117,"rtc_i2c_addr":50}"#
+        );
+    }
+
+    #[test]
+    fn test_diagnostic_bundle() {
+        let config = PiSugarConfig {
+            auth_user: Some("admin".to_string()),
+            auth_password: Some("secret".to_string()),
+            single_tap_shell: "curl -u admin:secret http://localhost".to_string(),
+            ..Default::default()
+        };
+        let core = PiSugarCore::new(config, Model::PiSugar_3).unwrap();
+        let bundle = core.diagnostic_bundle();
+        for key in [
+            "info",
+            "board",
+            "battery_version",
+            "health",
+            "snapshot",
+            "tap_history",
+            "registers",
+            "config",
+        ]
+        .iter()
+        {
+            assert!(bundle.get(key).is_some(), "{}", key);
+        }
+        assert_eq!(bundle["info"]["model"], Model::PiSugar_3.to_string());
+        // no battery, reading error is kept
+        assert!(bundle["snapshot"]["error"].is_string());
+        assert_eq!(bundle["config"]["auth_user"], DIAGNOSTIC_REDACTED);
+        assert_eq!(bundle["config"]["auth_password"], DIAGNOSTIC_REDACTED);
+        assert_eq!(bundle["config"]["single_tap_shell"], DIAGNOSTIC_REDACTED);
+        // unset fields are not redacted, so a missing one is still visible
+        assert!(bundle["config"]["critical_shell"].is_null());
+        assert!(!bundle.to_string().contains("secret"));
+        assert!(!bundle.to_string().contains("admin"));
+        // no chips bound, no registers
+        assert!(bundle["registers"]["battery"].is_null());
+        assert!(bundle["registers"]["rtc"].is_null());
+    }
+
+    #[test]
+    fn test_i2c_addr_override() {
+        let config = PiSugarConfig::from_json_str(r#"{"i2c_addr": 116, "rtc_i2c_addr": 51}"#).unwrap();
+        #[allow(deprecated)]
+        let old = config.i2c_addr;
+        assert_eq!(old, Some(0x74));
+        assert_eq!(config.battery_i2c_addr, None);
+
+        let core = PiSugarCore::new(config, Model::PiSugar_2_Pro).unwrap();
+        let info = core.info();
+        assert_eq!(info.battery_i2c_addr, 0x74);
+        assert_eq!(info.rtc_i2c_addr, 0x33);
+
+        let core = PiSugarCore::new(PiSugarConfig::default(), Model::PiSugar_2_Pro).unwrap();
+        let info = core.info();
+        assert_eq!(info.battery_i2c_addr, 0x75);
+        assert_eq!(info.rtc_i2c_addr, 0x32);
+    }
+
+    #[test]
+    fn test_auto_wake_repeat_serde() {
+        let config = PiSugarConfig::from_json_str(r#"{"auto_wake_repeat": ["mon", "tue"]}"#).unwrap();
+        assert_eq!(config.auto_wake_repeat, 0b0000_0110);
+
+        // backward compatible
+        let config = PiSugarConfig::from_json_str(r#"{"auto_wake_repeat": 127}"#).unwrap();
+        assert_eq!(config.auto_wake_repeat, 0b0111_1111);
+        let config = PiSugarConfig::from_json_str("{}").unwrap();
+        assert_eq!(config.auto_wake_repeat, 0);
+
+        let config = PiSugarConfig {
+            auto_wake_repeat: 0b0100_0001,
+            ..Default::default()
+        };
+        let json = config.to_json_pretty().unwrap();
+        let value: serde_json::Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(value["auto_wake_repeat"], serde_json::json!(["sun", "sat"]));
+        assert_eq!(
+            PiSugarConfig::from_json_str(&json).unwrap().auto_wake_repeat,
+            0b0100_0001
+        );
+
+        // same in a config patch
+        let patch: ConfigPatch = serde_json::from_str(r#"{"auto_wake_repeat": ["mon", "fri"]}"#).unwrap();
+        assert_eq!(patch.auto_wake_repeat, Some(0b0010_0010));
+        let patch: ConfigPatch = serde_json::from_str(r#"{"auto_wake_repeat": 127}"#).unwrap();
+        assert_eq!(patch.auto_wake_repeat, Some(0b0111_1111));
+        let patch: ConfigPatch = serde_json::from_str("{}").unwrap();
+        assert_eq!(patch.auto_wake_repeat, None);
+        let value = serde_json::to_value(ConfigPatch {
+            auto_wake_repeat: Some(0b0000_0001),
+            ..Default::default()
+        })
+        .unwrap();
+        assert_eq!(value["auto_wake_repeat"], serde_json::json!(["sun"]));
+    }
+
+    #[test]
+    fn test_age() {
+        let core = PiSugarCore::new(PiSugarConfig::default(), Model::PiSugar_3).unwrap();
+        let updated_at = core.updated_at;
+        assert_eq!(core.age(updated_at), Duration::from_secs(0));
+        assert_eq!(core.age(updated_at + Duration::from_secs(12)), Duration::from_secs(12));
+        assert_eq!(core.age(updated_at - Duration::from_secs(1)), Duration::from_secs(0));
+    }
+
+    #[test]
+    fn test_auto_power_on_recover() {
+        let config = PiSugarConfig {
+            auto_power_on: Some(true),
+            auto_shutdown_level: Some(5.0),
+            auto_shutdown_recover_level: Some(20.0),
+            ..Default::default()
+        };
+        let mut core = PiSugarCore::new(config, Model::PiSugar_3).unwrap();
+        assert!(!core.check_auto_power_on_recovered(50.0, false));
+        // not disarmed at startup, e.g. after a power loss at low battery
+        assert!(!core.auto_power_on_disarmed());
+
+        // disarmed on request before shutting down at 5%
+        core.state.auto_power_on_disarmed = true;
+
+        // rebound after load drops
+        assert!(!core.check_auto_power_on_recovered(6.0, false));
+        assert!(!core.check_auto_power_on_recovered(8.0, false));
+        assert!(core.auto_power_on_disarmed());
+
+        // recovered
+        assert!(core.check_auto_power_on_recovered(20.0, false));
+        assert!(!core.auto_power_on_disarmed());
+        assert!(!core.check_auto_power_on_recovered(21.0, false));
+
+        // power restored before the battery recovered
+        core.state.auto_power_on_disarmed = true;
+        assert!(core.check_auto_power_on_recovered(6.0, true));
+        assert!(!core.auto_power_on_disarmed());
+    }
+
+    #[cfg(feature = "mock-hardware")]
+    #[test]
+    fn test_disarm_auto_power_on_persisted() {
+        let dir = std::env::temp_dir().join(format!("pisugar-disarm-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("config.json");
+        let config = PiSugarConfig {
+            auto_power_on: Some(true),
+            ..Default::default()
+        };
+        config.save_to(&path).unwrap();
+        let mut core = PiSugarCore::new_with_path(path.to_str().unwrap(), false, Model::PiSugar_2_Pro).unwrap();
+        core.rtc = Some(Box::new(crate::SD3078::mock(core.config.clone())));
+        core.battery = Some(Box::new(ip5209::IP5209Battery::mock(
+            core.config.clone(),
+            Model::PiSugar_2_Pro,
+        )));
+        core.disarm_auto_power_on().unwrap();
+        assert!(core.auto_power_on_disarmed());
+
+        // user config is untouched, the disarm is kept in the state file
+        assert_eq!(PiSugarConfig::load_checked(&path).unwrap().auto_power_on, Some(true));
+        let core = PiSugarCore::new_with_path(path.to_str().unwrap(), false, Model::PiSugar_2_Pro).unwrap();
+        assert!(core.auto_power_on_disarmed());
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[cfg(feature = "mock-hardware")]
+    #[test]
+    fn test_restore_shutdown_config() {
+        use crate::i2c::I2c;
+
+        let dir = std::env::temp_dir().join(format!("pisugar-shutdown-config-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("config.json");
+        let config = PiSugarConfig {
+            i2c_bus: 5,
+            ..Default::default()
+        };
+        config.save_to(&path).unwrap();
+        I2c::plug_bus(5);
+        let mut chip = I2c::with_bus(5).unwrap();
+        chip.set_slave_address(crate::I2C_ADDR_BAT).unwrap();
+        // 3.7V
+        chip.smbus_write_byte(0xd0, 0x80).unwrap();
+        chip.smbus_write_byte(0xd1, 0x0f).unwrap();
+        // force shutdown disarmed
+        chip.smbus_write_byte(0x01, 0b0000_0100).unwrap();
+        let core = PiSugarCore::new_with_path(path.to_str().unwrap(), false, Model::PiSugar_2_Pro).unwrap();
+        let saved = core.state.shutdown_config.clone().unwrap();
+
+        // force shutdown armed, then the pi kept running
+        let battery = core.battery.as_ref().unwrap();
+        battery.shutdown().unwrap();
+        assert_ne!(battery.read_shutdown_config().unwrap(), saved);
+
+        // restarted
+        let core = PiSugarCore::new_with_path(path.to_str().unwrap(), false, Model::PiSugar_2_Pro).unwrap();
+        let battery = core.battery.as_ref().unwrap();
+        assert_eq!(battery.read_shutdown_config().unwrap(), saved);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[cfg(feature = "mock-hardware")]
+    #[test]
+    fn test_diagnostic_bundle_registers() {
+        use crate::i2c::I2c;
+
+        let config = PiSugarConfig {
+            i2c_bus: 9,
+            ..Default::default()
+        };
+        I2c::plug_bus(9);
+        let mut chip = I2c::with_bus(9).unwrap();
+        chip.set_slave_address(crate::I2C_ADDR_BAT).unwrap();
+        // 3.7V
+        chip.smbus_write_byte(0xd0, 0x80).unwrap();
+        chip.smbus_write_byte(0xd1, 0x0f).unwrap();
+        let core = PiSugarCore::new(config, Model::PiSugar_2_Pro).unwrap();
+
+        let registers = &core.diagnostic_bundle()["registers"];
+        assert_eq!(registers["battery"]["0xd0"], "0x80");
+        assert_eq!(registers["battery"]["0xd1"], "0x0f");
+        assert!(registers["rtc"]["0x0f"].is_string());
+    }
+
+    /// Fake clock, shared so the test can advance it
+    #[derive(Clone)]
+    struct FakeClock {
+        start: Instant,
+        start_local: DateTime<Local>,
+        elapsed: Arc<Mutex<Duration>>,
+    }
+
+    impl FakeClock {
+        /// Starting now
+        fn new() -> Self {
+            Self::starting_at(Local::now())
+        }
+
+        /// Starting at `start_local`, elapsed 0
+        fn starting_at(start_local: DateTime<Local>) -> Self {
+            Self {
+                start: Instant::now(),
+                start_local,
+                elapsed: Arc::new(Mutex::new(Duration::from_secs(0))),
+            }
+        }
+
+        fn advance(&self, d: Duration) {
+            *self.elapsed.lock().unwrap() += d;
+        }
+    }
+
+    impl Clock for FakeClock {
+        fn now_instant(&self) -> Instant {
+            self.start + *self.elapsed.lock().unwrap()
+        }
+
+        fn now_local(&self) -> DateTime<Local> {
+            self.start_local + chrono::Duration::from_std(*self.elapsed.lock().unwrap()).unwrap()
+        }
+    }
+
+    #[cfg(feature = "mock-hardware")]
+    #[test]
+    fn test_init_rtc_time_from_system() {
+        use chrono::TimeZone;
+
+        use crate::i2c::I2c;
+
+        /// Fixed system time, NTP synchronized or not
+        struct NtpClock {
+            local: DateTime<Local>,
+            synchronized: bool,
+        }
+
+        impl Clock for NtpClock {
+            fn now_instant(&self) -> Instant {
+                Instant::now()
+            }
+
+            fn now_local(&self) -> DateTime<Local> {
+                self.local
+            }
+
+            fn is_synchronized(&self) -> bool {
+                self.synchronized
+            }
+        }
+
+        let config = PiSugarConfig {
+            i2c_bus: 6,
+            rtc_init_from_system_if_invalid: Some(true),
+            ..Default::default()
+        };
+        I2c::plug_bus(6);
+        let mut chip = I2c::with_bus(6).unwrap();
+        chip.set_slave_address(crate::I2C_ADDR_RTC).unwrap();
+        // power down flag, year 2000
+        chip.smbus_write_byte(0x0f, 0b0000_0001).unwrap();
+        let local = Local.with_ymd_and_hms(2026, 10, 16, 8, 30, 0).unwrap();
+        let mut core = PiSugarCore::new_without_init(config, Model::PiSugar_2_Pro).unwrap();
+
+        // not synchronized, e.g. no network yet
+        core.rtc = None;
+        core.set_clock(Box::new(NtpClock {
+            local,
+            synchronized: false,
+        }));
+        core.init_rtc().unwrap();
+        let rtc = core.rtc.as_ref().unwrap();
+        assert!(rtc.read_power_down_flag().unwrap());
+        assert_eq!(rtc.read_time().unwrap().0[6], 0);
+
+        core.rtc = None;
+        core.set_clock(Box::new(NtpClock {
+            local,
+            synchronized: true,
+        }));
+        core.init_rtc().unwrap();
+        let rtc = core.rtc.as_ref().unwrap();
+        assert!(!rtc.read_power_down_flag().unwrap());
+        let expected = RTCRawTime::checked_from(local.with_timezone(&chrono::Utc)).unwrap();
+        assert_eq!(rtc.read_time().unwrap(), expected);
+    }
+
+    #[test]
+    fn test_fake_clock() {
+        let config = PiSugarConfig {
+            force_shutdown_timeout: Some(10),
+            ..Default::default()
+        };
+        let mut core = PiSugarCore::new(config, Model::PiSugar_3).unwrap();
+        let clock = FakeClock::new();
+        core.set_clock(Box::new(clock.clone()));
+
+        let (at, dt) = (core.now(), core.now_local());
+        core.notify_poweroff(core.now());
+        clock.advance(Duration::from_secs(9));
+        assert!(!core.poweroff_stalled(core.now()));
+        clock.advance(Duration::from_secs(1));
+        assert!(core.poweroff_stalled(core.now()));
+        assert_eq!(core.now() - at, Duration::from_secs(10));
+        assert_eq!(core.now_local() - dt, chrono::Duration::seconds(10));
+    }
+
+    #[test]
+    fn test_scheduled_shutdown() {
+        let mut core = PiSugarCore::new(PiSugarConfig::default(), Model::PiSugar_3).unwrap();
+        let clock = FakeClock::new();
+        core.set_clock(Box::new(clock.clone()));
+
+        assert!(!core.cancel_scheduled_shutdown());
+        core.schedule_shutdown(Duration::from_secs(60)).unwrap();
+        assert!(core.cancel_scheduled_shutdown());
+        clock.advance(Duration::from_secs(120));
+        assert!(!core.take_scheduled_shutdown(core.now()));
+
+        core.schedule_shutdown(Duration::from_secs(60)).unwrap();
+        clock.advance(Duration::from_secs(59));
+        assert!(!core.take_scheduled_shutdown(core.now()));
+        clock.advance(Duration::from_secs(2));
+        assert!(core.take_scheduled_shutdown(core.now()));
+        clock.advance(Duration::from_secs(1));
+        assert!(!core.take_scheduled_shutdown(core.now()));
+        assert_eq!(core.scheduled_shutdown(), None);
+
+        // client input, e.g. u64::MAX minutes, is an error instead of a panic
+        assert!(core.schedule_shutdown(Duration::from_secs(u64::MAX)).is_err());
+        assert_eq!(core.scheduled_shutdown(), None);
+    }
+
+    #[cfg(feature = "mock-hardware")]
+    #[test]
+    fn test_test_wake_clock() {
+        use std::convert::TryInto;
+
+        use chrono::{TimeZone, Utc};
+
+        let mut core = PiSugarCore::new(PiSugarConfig::default(), Model::PiSugar_2_Pro).unwrap();
+        core.rtc = Some(Box::new(crate::SD3078::mock(PiSugarConfig::default())));
+        let start_local = Local.with_ymd_and_hms(2030, 6, 1, 12, 0, 0).unwrap();
+        core.set_clock(Box::new(FakeClock::starting_at(start_local)));
+
+        // rtc time and alarm from the core clock, not the system one
+        core.test_wake().unwrap();
+        assert_eq!(core.read_time().unwrap(), start_local);
+        let alarm: DateTime<Utc> = core.read_alarm_time().unwrap().try_into().unwrap();
+        assert_eq!(
+            alarm.with_timezone(&Local).time(),
+            (start_local + chrono::Duration::seconds(90)).time()
+        );
+    }
+
+    #[cfg(feature = "mock-hardware")]
+    #[test]
+    fn test_auto_wake_keeps_wake_queue() {
+        let mut core = PiSugarCore::new(PiSugarConfig::default(), Model::PiSugar_2_Pro).unwrap();
+        core.rtc = Some(Box::new(crate::SD3078::mock(PiSugarConfig::default())));
+        let path = std::env::temp_dir().join(format!("pisugar-wake-queue-{}.json", std::process::id()));
+        core.config_path = Some(path.to_string_lossy().to_string());
+        core.config.auto_wake_time = Some(core.now_local() + chrono::Duration::hours(2));
+        core.config.auto_wake_repeat = 0x7f;
+
+        let at = core.now_local() + chrono::Duration::hours(1);
+        core.add_wake_alarm(at).unwrap();
+
+        // turning auto wake off leaves the queued wake armed
+        let patch = ConfigPatch {
+            auto_wake_repeat: Some(0),
+            ..Default::default()
+        };
+        core.update_config(patch).unwrap();
+        assert!(core.read_alarm_enabled().unwrap());
+        assert_eq!(core.read_alarm_time().unwrap().0[..3], RTCRawTime::from(at).0[..3]);
+        let _ = fs::remove_file(&path);
+    }
+
+    #[cfg(feature = "mock-hardware")]
+    #[test]
+    fn test_on_terminate_keeps_wake_queue() {
+        let mut core = PiSugarCore::new(PiSugarConfig::default(), Model::PiSugar_3).unwrap();
+        core.rtc = Some(Box::new(crate::SD3078::mock(PiSugarConfig::default())));
+        let at = core.now_local() + chrono::Duration::hours(1);
+        core.config.wake_queue.add(at).unwrap();
+        core.rearm_wake_queue().unwrap();
+
+        // SIGTERM at shutdown, auto wake is off
+        core.on_terminate();
+        assert!(core.read_alarm_enabled().unwrap());
+        assert_eq!(core.read_alarm_time().unwrap().0[..3], RTCRawTime::from(at).0[..3]);
+    }
+
+    #[test]
+    fn test_wake_queue_rearm() {
+        let mut core = PiSugarCore::new(PiSugarConfig::default(), Model::PiSugar_2_Pro).unwrap();
+        let clock = FakeClock::new();
+        core.set_clock(Box::new(clock.clone()));
+        #[cfg(feature = "mock-hardware")]
+        {
+            core.rtc = Some(Box::new(crate::SD3078::mock(PiSugarConfig::default())));
+        }
+
+        let first = core.now_local() + chrono::Duration::hours(1);
+        let second = core.now_local() + chrono::Duration::hours(13);
+        core.config.wake_queue.add(second).unwrap();
+        core.config.wake_queue.add(first).unwrap();
+
+        // one-off wake time in the past is rejected
+        let past = core.now_local() - chrono::Duration::minutes(1);
+        assert!(core.add_wake_alarm(past).is_err());
+        assert_eq!(core.config.wake_queue.alarms(), &[first, second]);
+
+        // woken by the first alarm
+        clock.advance(Duration::from_secs(3600 + 5));
+        let armed = core.rearm_wake_queue();
+        assert_eq!(core.config.wake_queue.alarms(), &[second]);
+        #[cfg(not(feature = "mock-hardware"))]
+        assert!(armed.is_err());
+        #[cfg(feature = "mock-hardware")]
+        {
+            assert_eq!(armed.unwrap(), Some(second));
+            let alarm = core.read_alarm_time().unwrap();
+            assert_eq!(alarm.0[..3], RTCRawTime::from(second).0[..3]);
+            let weekday = crate::WeekdayMask::default().with(chrono::Datelike::weekday(&second));
+            assert_eq!(alarm.0[3], weekday.bits());
+        }
+    }
+
+    #[test]
+    fn test_refresh_now() {
+        let mut core = PiSugarCore::new(PiSugarConfig::default(), Model::PiSugar_2_4LEDs).unwrap();
+        // no i2c device
+        assert!(core.refresh_now().is_err());
+
+        #[cfg(feature = "mock-hardware")]
+        {
+            let battery = ip5209::IP5209Battery::mock(PiSugarConfig::default(), Model::PiSugar_2_4LEDs);
+            // 3000 counts, 3.4057V
+            battery.set_register(0xa2, 0xb8);
+            battery.set_register(0xa3, 0x0b);
+            core.battery = Some(Box::new(battery));
+            let clock = FakeClock::new();
+            core.set_clock(Box::new(clock.clone()));
+            clock.advance(Duration::from_secs(10));
+            assert!(core.age(core.now()) >= Duration::from_secs(10));
+
+            core.refresh_now().unwrap();
+            assert_eq!(core.age(core.now()), Duration::from_secs(0));
+            let v = core.voltage_avg().unwrap();
+            assert!((v - (2.6 + 3000.0 * 0.00026855)).abs() < 1e-6);
+        }
+    }
+
+    #[test]
+    fn test_tap_history() {
+        let core = PiSugarCore::new(PiSugarConfig::default(), Model::PiSugar_3).unwrap();
+        assert_eq!(core.tap_history(), "");
+
+        #[cfg(feature = "mock-hardware")]
+        {
+            use crate::battery::Battery;
+
+            let config = PiSugarConfig::default();
+            let mut battery = ip5209::IP5209Battery::mock(config.clone(), Model::PiSugar_2_4LEDs);
+            let now = Instant::now();
+            for gpio in [0x00, 0x10, 0x10, 0x00].iter() {
+                battery.set_register(0x55, *gpio);
+                battery.poll(now, &config).unwrap();
+            }
+            let mut core = core;
+            core.battery = Some(Box::new(battery));
+            assert_eq!(core.tap_history(), "0110");
+        }
+    }
+
+    #[cfg(feature = "mock-hardware")]
+    #[tokio::test]
+    async fn test_cores_on_separate_buses() {
+        use crate::i2c::I2c;
+
+        // same chip addresses, one board per bus
+        let mut cores = Vec::new();
+        for (bus, voltage_low) in [(0, 0xb8), (1, 0xa0)].iter() {
+            I2c::plug_bus(*bus);
+            let mut chip = I2c::with_bus(*bus).unwrap();
+            chip.set_slave_address(crate::I2C_ADDR_BAT).unwrap();
+            chip.smbus_write_byte(0xa2, *voltage_low).unwrap();
+            chip.smbus_write_byte(0xa3, 0x0b).unwrap();
+            let config = PiSugarConfig {
+                i2c_bus: *bus,
+                ..Default::default()
+            };
+            cores.push(PiSugarCore::new(config, Model::PiSugar_2_4LEDs).unwrap());
+        }
+
+        assert_eq!(cores[0].info().battery_i2c_addr, cores[1].info().battery_i2c_addr);
+        let now = Instant::now();
+        for core in cores.iter_mut() {
+            core.poll(now).await.unwrap();
+        }
+        // 3000 and 2976 counts
+        let v0 = cores[0].voltage().unwrap();
+        let v1 = cores[1].voltage().unwrap();
+        assert!((v0 - (2.6 + 3000.0 * 0.00026855)).abs() < 1e-6);
+        assert!((v1 - (2.6 + 2976.0 * 0.00026855)).abs() < 1e-6);
+
+        // pausing one core leaves the other polling
+        cores[0].set_paused(true);
+        let later = now + Duration::from_secs(1);
+        assert!(cores[0].poll(later).await.unwrap().is_empty());
+        cores[1].poll(later).await.unwrap();
+        assert_eq!(cores[0].updated_at, now);
+        assert_eq!(cores[1].updated_at, later);
+    }
+
+    #[cfg(feature = "mock-hardware")]
+    #[tokio::test]
+    async fn test_rtc_poll_divisor() {
+        use crate::I2C_READ_INTERVAL;
+
+        let config = PiSugarConfig {
+            rtc_poll_divisor: Some(5),
+            ..Default::default()
+        };
+        let mut core = PiSugarCore::new(config.clone(), Model::PiSugar_2_4LEDs).unwrap();
+        let battery = ip5209::IP5209Battery::mock(config.clone(), Model::PiSugar_2_4LEDs);
+        core.battery = Some(Box::new(battery));
+        let rtc = crate::SD3078::mock(config);
+        let rtc_reads = rtc.block_reads();
+        core.rtc = Some(Box::new(rtc));
+        let _rx = core.subscribe();
+
+        // battery every poll, rtc time once per 5 polls, published snapshots don't read it
+        let start = core.now();
+        for round in 0..3 {
+            for i in 0..5 {
+                let now = start + I2C_READ_INTERVAL * (round * 5 + i);
+                core.poll(now).await.unwrap();
+                assert_eq!(core.updated_at, now);
+            }
+            assert_eq!(rtc_reads.load(Ordering::SeqCst), round as usize + 1);
+        }
+    }
+
+    #[cfg(feature = "mock-hardware")]
+    #[tokio::test]
+    async fn test_redetect() {
+        use crate::i2c::I2c;
+
+        let config = PiSugarConfig {
+            i2c_bus: 3,
+            redetect_interval: Some(5),
+            ..Default::default()
+        };
+        let mut core = PiSugarCore::new(config, Model::PiSugar_2_4LEDs).unwrap();
+        assert!(core.battery.is_none());
+
+        // nothing on the bus, re-detect every 5 polls
+        let now = core.now();
+        for poll in 1..=10 {
+            let r = core.poll(now).await;
+            assert_eq!(r.is_err(), poll % 5 == 0, "poll {}", poll);
+        }
+
+        // hot-plugged
+        I2c::plug_bus(3);
+        let mut events = Vec::new();
+        for _ in 0..5 {
+            events.extend(core.poll(now).await.unwrap());
+        }
+        assert_eq!(events, vec![Event::Detected]);
+        assert!(core.battery.is_some() && core.rtc.is_some());
+        assert!(core.poll(now).await.unwrap().is_empty());
+
+        // remote I/O errors, chips re-init at the next poll without a detected event
+        let remote_io = || Error::I2c(I2cError::Io(io::Error::from_raw_os_error(EREMOTEIO)));
+        for _ in 0..REMOTE_IO_ERROR_THRESHOLD {
+            core.check_remote_io(&remote_io());
+        }
+        assert!(core.battery.is_none() && core.rtc.is_none());
+        assert!(core.poll(now).await.unwrap().is_empty());
+        assert!(core.battery.is_some() && core.rtc.is_some());
+    }
+
+    #[cfg(feature = "mock-hardware")]
+    #[tokio::test]
+    async fn test_tap_script_overlap() {
+        let dir = std::env::temp_dir().join(format!("pisugar-tap-scripts-{}", std::process::id()));
+        // each run leaves a marker, then waits for go
+        let shell = format!(
+            "mktemp -p {d} run.XXXXXX; while [ ! -e {d}/go ]; do sleep 0.05; done",
+            d = dir.display()
+        );
+        let runs = || {
+            fs::read_dir(&dir)
+                .unwrap()
+                .filter(|e| e.as_ref().unwrap().file_name().to_string_lossy().starts_with("run."))
+                .count()
+        };
+        let wait_runs = |n| {
+            let start = Instant::now();
+            while runs() < n && start.elapsed() < Duration::from_secs(5) {
+                std::thread::sleep(Duration::from_millis(20));
+            }
+            runs()
+        };
+
+        for (overlap, expected) in [(None, 2), (Some(crate::ScriptOverlap::Queue), 1)] {
+            let _ = fs::remove_dir_all(&dir);
+            fs::create_dir_all(&dir).unwrap();
+            let config = PiSugarConfig {
+                single_tap_enable: true,
+                single_tap_shell: shell.clone(),
+                tap_script_overlap: overlap,
+                shell_timeout: overlap.map(|_| 10),
+                ..Default::default()
+            };
+            let mut core = PiSugarCore::new(config.clone(), Model::PiSugar_3).unwrap();
+            let battery = crate::pisugar3::PiSugar3Battery::mock(config, Model::PiSugar_3);
+            let regs = battery.registers();
+            core.battery = Some(Box::new(battery));
+            core.rtc = Some(Box::new(crate::SD3078::mock(core.config.clone())));
+            let clock = FakeClock::new();
+            core.set_clock(Box::new(clock.clone()));
+
+            // two single taps while the first script still runs
+            for _ in 0..2 {
+                regs.smbus_write_byte(0x08, 1).unwrap();
+                clock.advance(Duration::from_secs(1));
+                let now = core.now();
+                let start = Instant::now();
+                core.poll(now).await.unwrap();
+                assert!(start.elapsed() < Duration::from_secs(1));
+            }
+            assert_eq!(wait_runs(expected), expected);
+            std::thread::sleep(Duration::from_millis(200));
+            assert_eq!(runs(), expected);
+
+            // queued one runs once the first is done
+            fs::write(dir.join("go"), "").unwrap();
+            assert_eq!(wait_runs(2), 2);
+        }
+        let _ = fs::remove_dir_all(&dir);
+
+        // queue without timeout is rejected
+        assert!(PiSugarConfig::from_json_str(r#"{"tap_script_overlap": "queue"}"#).is_err());
+        assert!(PiSugarConfig::from_json_str(r#"{"tap_script_overlap": "queue", "shell_timeout": 5}"#).is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_poll_paused() {
+        let mut core = PiSugarCore::new(PiSugarConfig::default(), Model::PiSugar_3).unwrap();
+        let updated_at = core.updated_at;
+        core.set_paused(true);
+        assert!(core.is_paused());
+
+        // no chips are opened or read while paused
+        let now = core.now() + Duration::from_secs(10);
+        assert!(core.poll(now).await.unwrap().is_empty());
+        assert!(core.battery.is_none());
+        assert!(core.rtc.is_none());
+        assert_eq!(core.updated_at, updated_at);
+
+        // due scheduled shutdown waits, force shutdown is refused
+        core.schedule_shutdown(Duration::from_secs(0)).unwrap();
+        assert!(core.poll(now).await.unwrap().is_empty());
+        assert!(core.scheduled_shutdown().is_some());
+        assert_eq!(core.shutdown_state(), ShutdownState::None);
+        assert!(core.force_shutdown().is_err());
+        assert!(!core.shutdown_forced.load(Ordering::SeqCst));
+        core.cancel_scheduled_shutdown();
+
+        core.set_paused(false);
+        assert!(!core.is_paused());
+    }
+
+    #[test]
+    fn test_issue_shutdown() {
+        let mut core = PiSugarCore::new(PiSugarConfig::default(), Model::PiSugar_3).unwrap();
+        let issued = (0..10).filter(|_| core.issue_shutdown()).count();
+        assert_eq!(issued, 1);
+    }
+
+    #[test]
+    fn test_shutdown_state() {
+        let mut core = PiSugarCore::new(PiSugarConfig::default(), Model::PiSugar_3).unwrap();
+        assert_eq!(core.shutdown_state(), ShutdownState::None);
+        assert!(core.issue_shutdown());
+        assert_eq!(core.shutdown_state(), ShutdownState::GracefulPending);
+        core.notify_poweroff(core.now());
+        assert_eq!(core.shutdown_state(), ShutdownState::GracefulPending);
+        // chips not connected in tests, not forcing
+        assert!(core.force_shutdown().is_err());
+        assert_eq!(core.shutdown_state(), ShutdownState::GracefulPending);
+        assert!(!core.issue_shutdown());
+
+        // shutdown command failed, issued again
+        core.shutdown_failed();
+        assert!(core.issue_shutdown());
+        assert!(!core.issue_shutdown());
+
+        // soft poweroff goes to the watchdog without the latch
+        let mut core = PiSugarCore::new(PiSugarConfig::default(), Model::PiSugar_3).unwrap();
+        core.notify_poweroff(core.now());
+        assert_eq!(core.shutdown_state(), ShutdownState::GracefulPending);
+        assert_eq!(ShutdownState::GracefulPending.to_string(), "graceful_pending");
+    }
+
+    #[cfg(feature = "mock-hardware")]
+    #[test]
+    fn test_shutdown_state_forcing() {
+        use crate::i2c::I2c;
+
+        let config = PiSugarConfig {
+            i2c_bus: 8,
+            ..Default::default()
+        };
+        I2c::plug_bus(8);
+        let core = PiSugarCore::new_without_init(config, Model::PiSugar_3).unwrap();
+        core.force_shutdown().unwrap();
+        assert_eq!(core.shutdown_state(), ShutdownState::Forcing);
+    }
+
+    #[test]
+    fn test_on_terminate() {
+        let mut core = PiSugarCore::new(PiSugarConfig::default(), Model::PiSugar_3).unwrap();
+        let path = std::env::temp_dir().join(format!("pisugar-terminate-{}.json", std::process::id()));
+        core.config_path = Some(path.to_string_lossy().to_string());
+        core.schedule_shutdown(Duration::from_secs(60)).unwrap();
+
+        core.on_terminate();
+        assert!(path.exists());
+        assert!(core.scheduled_shutdown().is_none());
+
+        // second call is a no-op
+        std::fs::remove_file(&path).unwrap();
+        core.on_terminate();
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn test_retry_i2c() {
+        let interrupted = || I2cError::Io(io::Error::from(io::ErrorKind::Interrupted));
+
+        let mut calls = 0;
+        let r = retry_i2c(|| {
+            calls += 1;
+            if calls == 1 {
+                Err(interrupted())
+            } else {
+                Ok(42)
+            }
+        });
+        assert_eq!(r.unwrap(), 42);
+        assert_eq!(calls, 2);
+
+        let mut calls = 0;
+        let r: super::Result<()> = retry_i2c(|| {
+            calls += 1;
+            Err(I2cError::Io(io::Error::from_raw_os_error(EREMOTEIO)))
+        });
+        assert!(r.is_err());
+        assert_eq!(calls, 1);
+
+        let mut calls = 0;
+        let r: super::Result<()> = retry_i2c(|| {
+            calls += 1;
+            Err(interrupted())
+        });
+        assert!(r.is_err());
+        assert_eq!(calls, 3);
+    }
 }
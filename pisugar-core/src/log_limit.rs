@@ -0,0 +1,99 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// Window of repeated error summary
+pub const LOG_LIMIT_WINDOW: Duration = Duration::from_secs(60);
+
+/// Rate limit repeated log messages, keyed per message, logs the first one, then a summary once per window
+pub struct LogRateLimiter {
+    window: Duration,
+    /// Message => (window start, suppressed count)
+    messages: HashMap<String, (Instant, u32)>,
+}
+
+impl LogRateLimiter {
+    pub fn new(window: Duration) -> Self {
+        Self {
+            window,
+            messages: HashMap::new(),
+        }
+    }
+
+    /// Message to log, None if suppressed
+    pub fn check(&mut self, now: Instant, msg: &str) -> Option<String> {
+        let window = self.window;
+        // windows ended without repeats
+        self.messages
+            .retain(|_, (start, suppressed)| *suppressed > 0 || now.saturating_duration_since(*start) < window);
+
+        let (start, suppressed) = match self.messages.get_mut(msg) {
+            Some(entry) => entry,
+            None => {
+                self.messages.insert(msg.to_string(), (now, 0));
+                return Some(msg.to_string());
+            }
+        };
+        if now.saturating_duration_since(*start) < window {
+            *suppressed += 1;
+            return None;
+        }
+        let msg = if *suppressed > 0 {
+            format!("{} ({} more in last {}s)", msg, suppressed, window.as_secs())
+        } else {
+            msg.to_string()
+        };
+        *start = now;
+        *suppressed = 0;
+        Some(msg)
+    }
+
+    /// Forget all messages, e.g. error recovered
+    pub fn clear(&mut self) {
+        self.messages.clear();
+    }
+}
+
+impl Default for LogRateLimiter {
+    fn default() -> Self {
+        Self::new(LOG_LIMIT_WINDOW)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::LogRateLimiter;
+
+    #[test]
+    fn test_log_rate_limiter() {
+        let mut limiter = LogRateLimiter::new(Duration::from_secs(60));
+        let start = Instant::now();
+
+        // a disconnected board, 10 errors per second for 2.5 minutes
+        let logged: Vec<String> = (0..1500)
+            .filter_map(|i| limiter.check(start + Duration::from_millis(i * 100), "Remote I/O error"))
+            .collect();
+        assert_eq!(logged.len(), 3);
+        assert_eq!(logged[0], "Remote I/O error");
+        assert_eq!(logged[1], "Remote I/O error (599 more in last 60s)");
+
+        // different error is logged at once
+        let now = start + Duration::from_secs(150);
+        assert_eq!(limiter.check(now, "I2C timeout").as_deref(), Some("I2C timeout"));
+        assert_eq!(limiter.check(now, "I2C timeout"), None);
+
+        // alternating errors are limited each
+        assert_eq!(limiter.check(now, "Remote I/O error"), None);
+        assert_eq!(limiter.check(now, "I2C timeout"), None);
+        let now = now + Duration::from_secs(60);
+        assert_eq!(
+            limiter.check(now, "I2C timeout").as_deref(),
+            Some("I2C timeout (2 more in last 60s)")
+        );
+        assert_eq!(limiter.check(now, "I2C timeout"), None);
+
+        limiter.clear();
+        assert!(limiter.check(now, "I2C timeout").is_some());
+    }
+}
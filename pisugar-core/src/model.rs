@@ -1,6 +1,8 @@
 use std::convert::TryFrom;
 use std::fmt;
 
+use serde::{Serialize, Serializer};
+
 use crate::ip5312::IP5312Battery;
 use crate::pisugar3::{PiSugar3Battery, PiSugar3RTC, I2C_ADDR_P3};
 use crate::rtc::RTC;
@@ -33,6 +35,39 @@ impl Model {
         }
     }
 
+    /// Default battery capacity (mAh)
+    pub fn battery_capacity(&self) -> f64 {
+        match *self {
+            Model::PiSugar_2_Pro => 5000.0,
+            _ => 1200.0,
+        }
+    }
+
+    /// Typical idle intensity of the Pi drawn from battery (A)
+    pub fn idle_intensity(&self) -> f64 {
+        match *self {
+            Model::PiSugar_2_Pro => 0.2,
+            _ => 0.11,
+        }
+    }
+
+    /// Battery chip name
+    pub fn battery_chip(&self) -> &'static str {
+        match *self {
+            Model::PiSugar_2_4LEDs | Model::PiSugar_2_2LEDs => "IP5209",
+            Model::PiSugar_2_Pro => "IP5312",
+            Model::PiSugar_3 => "PiSugar 3 MCU",
+        }
+    }
+
+    /// RTC chip name
+    pub fn rtc_chip(&self) -> &'static str {
+        match *self {
+            Model::PiSugar_3 => "PiSugar 3 MCU",
+            _ => "SD3078",
+        }
+    }
+
     pub fn default_battery_i2c_addr(&self) -> u16 {
         match *self {
             Model::PiSugar_3 => I2C_ADDR_P3,
@@ -47,11 +82,24 @@ impl Model {
         }
     }
 
+    /// Battery chip I2C addr, configured or default
+    #[allow(deprecated)]
+    pub fn battery_i2c_addr(&self, cfg: &PiSugarConfig) -> u16 {
+        cfg.battery_i2c_addr
+            .or(cfg.i2c_addr)
+            .unwrap_or_else(|| self.default_battery_i2c_addr())
+    }
+
+    /// RTC chip I2C addr, configured or default
+    pub fn rtc_i2c_addr(&self, cfg: &PiSugarConfig) -> u16 {
+        cfg.rtc_i2c_addr.unwrap_or_else(|| self.default_rtc_i2c_addr())
+    }
+
     pub fn bind(&self, cfg: PiSugarConfig) -> Result<Box<dyn Battery + Send>> {
         log::info!(
             "Binding battery i2c bus={} addr={}",
             cfg.i2c_bus,
-            cfg.i2c_addr.unwrap_or(self.default_battery_i2c_addr())
+            self.battery_i2c_addr(&cfg)
         );
         let b: Box<dyn Battery + Send> = match *self {
             Model::PiSugar_2_4LEDs => Box::new(IP5209Battery::new(cfg, *self)?),
@@ -63,11 +111,7 @@ impl Model {
     }
 
     pub fn rtc(&self, cfg: PiSugarConfig) -> Result<Box<dyn RTC + Send>> {
-        log::info!(
-            "Bindig rtc i2c bus={} addr={}",
-            cfg.i2c_bus,
-            self.default_rtc_i2c_addr()
-        );
+        log::info!("Bindig rtc i2c bus={} addr={}", cfg.i2c_bus, self.rtc_i2c_addr(&cfg));
         let r: Box<dyn RTC + Send> = match *self {
             Model::PiSugar_3 => Box::new(PiSugar3RTC::new(cfg, *self)?),
             _ => Box::new(SD3078::new(cfg, *self)?),
@@ -76,6 +120,13 @@ impl Model {
     }
 }
 
+/// Serialized as model name, e.g. "PiSugar 3"
+impl Serialize for Model {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
 impl fmt::Display for Model {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         let s = match *self {
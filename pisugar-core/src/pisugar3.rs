@@ -4,15 +4,15 @@ use std::collections::VecDeque;
 use std::ffi::CStr;
 use std::time::Instant;
 
-use rppal::i2c::I2c;
+use crate::i2c::I2c;
 
 use crate::ip5312::IP5312;
-use crate::rtc::{bcd_to_dec, dec_to_bcd, RTC};
+use crate::rtc::{bcd_to_dec, dec_to_bcd, WeekdayMask, RTC};
 use crate::{
-    battery::{Battery, BatteryEvent},
+    battery::{estimate_internal_resistance, Battery, BatteryEvent, ChargeCounter},
     ip5312::BATTERY_CURVE,
 };
-use crate::{Error, Model, PiSugarConfig, RTCRawTime, Result, TapType};
+use crate::{curve_segment_for, open_i2c, Error, Model, PiSugarConfig, RTCRawTime, Result, TapType};
 
 /// PiSugar 3 i2c addr
 pub const I2C_ADDR_P3: u16 = 0x57;
@@ -91,8 +91,7 @@ pub struct PiSugar3 {
 impl PiSugar3 {
     pub fn new(i2c_bus: u8, i2c_addr: u16) -> Result<Self> {
         log::debug!("PiSugar3 bus 0x{:02x} addr 0x{:02x}", i2c_bus, i2c_addr);
-        let mut i2c = I2c::with_bus(i2c_bus)?;
-        i2c.set_slave_address(i2c_addr)?;
+        let i2c = open_i2c(i2c_bus, i2c_addr)?;
         Ok(Self { i2c })
     }
 
@@ -409,6 +408,7 @@ pub struct PiSugar3Battery {
     model: Model,
     voltages: VecDeque<(Instant, f32)>,
     intensities: VecDeque<(Instant, f32)>,
+    charge: ChargeCounter,
     levels: VecDeque<f32>,
     poll_at: Instant,
     version: String,
@@ -417,19 +417,49 @@ pub struct PiSugar3Battery {
 
 impl PiSugar3Battery {
     pub fn new(cfg: PiSugarConfig, model: Model) -> Result<Self> {
-        let pisugar3 = PiSugar3::new(cfg.i2c_bus, cfg.i2c_addr.unwrap_or(model.default_battery_i2c_addr()))?;
+        let pisugar3 = PiSugar3::new(cfg.i2c_bus, model.battery_i2c_addr(&cfg))?;
         let poll_at = Instant::now() - std::time::Duration::from_secs(10);
         Ok(Self {
             pisugar3,
             model,
             voltages: VecDeque::with_capacity(30),
             intensities: VecDeque::with_capacity(30),
+            charge: ChargeCounter::default(),
             levels: VecDeque::with_capacity(30),
             poll_at,
             version: "".to_string(),
             cfg,
         })
     }
+
+    /// Battery of an in-memory chip
+    #[cfg(all(test, feature = "mock-hardware"))]
+    pub(crate) fn mock(cfg: PiSugarConfig, model: Model) -> Self {
+        let poll_at = Instant::now() - std::time::Duration::from_secs(10);
+        Self {
+            pisugar3: PiSugar3 { i2c: I2c::mock() },
+            model,
+            voltages: VecDeque::with_capacity(30),
+            intensities: VecDeque::with_capacity(30),
+            charge: ChargeCounter::default(),
+            levels: VecDeque::with_capacity(30),
+            poll_at,
+            version: "".to_string(),
+            cfg,
+        }
+    }
+
+    /// Set a register of the in-memory chip
+    #[cfg(all(test, feature = "mock-hardware"))]
+    pub(crate) fn set_register(&self, reg: u8, value: u8) {
+        self.pisugar3.i2c.smbus_write_byte(reg, value).unwrap();
+    }
+
+    /// Registers of the in-memory chip, still reachable after the battery is moved
+    #[cfg(all(test, feature = "mock-hardware"))]
+    pub(crate) fn registers(&self) -> I2c {
+        self.pisugar3.i2c.share()
+    }
 }
 
 impl Battery for PiSugar3Battery {
@@ -469,7 +499,7 @@ impl Battery for PiSugar3Battery {
 
     fn voltage(&self) -> crate::Result<f32> {
         let v = self.pisugar3.read_voltage()?;
-        Ok((v as f32) / 1000.0)
+        Ok(self.cfg.calibrate_voltage((v as f32) / 1000.0))
     }
 
     fn voltage_avg(&self) -> crate::Result<f32> {
@@ -483,13 +513,17 @@ impl Battery for PiSugar3Battery {
     }
 
     fn level(&self) -> crate::Result<f32> {
-        let curve = self
-            .cfg
-            .battery_curve
-            .as_ref()
-            .map(|x| &x[..])
-            .unwrap_or(BATTERY_CURVE.as_ref());
-        self.voltage_avg().map(|v| IP5312::parse_voltage_level(v, curve))
+        self.voltage_avg().and_then(|v| self.voltage_level(v))
+    }
+
+    fn voltage_level(&self, voltage: f32) -> crate::Result<f32> {
+        let curve = self.cfg.battery_curve(BATTERY_CURVE.as_ref());
+        Ok(IP5312::parse_voltage_level(voltage, curve))
+    }
+
+    fn curve_segment(&self) -> crate::Result<Option<usize>> {
+        let curve = self.cfg.battery_curve(BATTERY_CURVE.as_ref());
+        self.voltage_avg().map(|v| curve_segment_for(v, curve))
     }
 
     fn intensity(&self) -> crate::Result<f32> {
@@ -507,7 +541,21 @@ impl Battery for PiSugar3Battery {
         }
     }
 
+    fn discharge_current(&self) -> crate::Result<f32> {
+        // output current is unsigned, the battery only discharges without external power
+        if self.is_power_plugged()? {
+            Ok(0.0)
+        } else {
+            self.intensity_avg()
+        }
+    }
+
     fn is_power_plugged(&self) -> crate::Result<bool> {
+        self.external_power_present()
+    }
+
+    fn external_power_present(&self) -> Result<bool> {
+        // ctr1 bit7, external power plugged
         let ctr1 = self.pisugar3.read_ctr1()?;
         Ok((ctr1 & (1 << 7)) != 0)
     }
@@ -552,13 +600,7 @@ impl Battery for PiSugar3Battery {
         self.pisugar3.toggle_output_enabled(enable)
     }
 
-    fn poll(&mut self, now: Instant, config: &PiSugarConfig) -> crate::Result<Vec<BatteryEvent>> {
-        // slow down, 500ms
-        if self.poll_at > now || self.poll_at + std::time::Duration::from_millis(500) > now {
-            return Ok(Vec::default());
-        }
-        self.poll_at = now;
-
+    fn refresh(&mut self, now: Instant) -> Result<()> {
         let voltage = self.voltage()?;
         self.voltages.pop_front();
         while self.voltages.len() < self.voltages.capacity() {
@@ -572,11 +614,26 @@ impl Battery for PiSugar3Battery {
         }
 
         let intensity = self.intensity()?;
+        // output current is unsigned, it only drains the battery without external power
+        let into_battery = if self.is_power_plugged()? { 0.0 } else { -intensity };
+        self.charge.update(now, into_battery);
         self.intensities.pop_front();
         while self.intensities.len() < self.intensities.capacity() {
             self.intensities.push_back((now, intensity));
         }
 
+        Ok(())
+    }
+
+    fn poll(&mut self, now: Instant, config: &PiSugarConfig) -> crate::Result<Vec<BatteryEvent>> {
+        // slow down, 500ms
+        if self.poll_at > now || self.poll_at + std::time::Duration::from_millis(500) > now {
+            return Ok(Vec::default());
+        }
+        self.poll_at = now;
+
+        self.refresh(now)?;
+
         let tap = match self.pisugar3.read_tap()? {
             1 => Some(TapType::Single),
             2 => Some(TapType::Double),
@@ -639,6 +696,14 @@ impl Battery for PiSugar3Battery {
     fn temperature(&self) -> Result<f32> {
         Ok(self.pisugar3.read_temp()? as f32)
     }
+
+    fn internal_resistance(&self) -> Option<f64> {
+        estimate_internal_resistance(&self.voltages, &self.intensities)
+    }
+
+    fn net_charge(&self) -> Option<f64> {
+        self.charge.intensity_updated_at().map(|_| self.charge.charge_ah())
+    }
 }
 
 pub struct PiSugar3RTC {
@@ -648,7 +713,7 @@ pub struct PiSugar3RTC {
 
 impl PiSugar3RTC {
     pub fn new(cfg: PiSugarConfig, model: Model) -> Result<Self> {
-        let pisugar3 = PiSugar3::new(cfg.i2c_bus, model.default_rtc_i2c_addr())?;
+        let pisugar3 = PiSugar3::new(cfg.i2c_bus, model.rtc_i2c_addr(&cfg))?;
         Ok(Self { pisugar3, cfg })
     }
 }
@@ -731,7 +796,8 @@ impl RTC for PiSugar3RTC {
         self.pisugar3.write_alarm_hh(time.hour())?;
         self.pisugar3.write_alarm_mn(time.minute())?;
         self.pisugar3.write_alarm_ss(time.second())?;
-        self.pisugar3.write_alarm_weekday_repeat(weekday_repeat)?;
+        self.pisugar3
+            .write_alarm_weekday_repeat(WeekdayMask::from_bits(weekday_repeat).bits())?;
         self.pisugar3.toggle_alarm_enable(true)?;
         Ok(())
     }
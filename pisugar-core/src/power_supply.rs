@@ -0,0 +1,112 @@
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+/// Battery status in linux power_supply class format
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum PowerSupplyStatus {
+    Charging,
+    Discharging,
+    NotCharging,
+    Full,
+}
+
+impl PowerSupplyStatus {
+    /// Status of battery, power plugged but not charging is full or not charging
+    pub fn new(charging: bool, power_plugged: bool, full: bool) -> Self {
+        match (charging, power_plugged) {
+            (true, _) => PowerSupplyStatus::Charging,
+            (false, true) if full => PowerSupplyStatus::Full,
+            (false, true) => PowerSupplyStatus::NotCharging,
+            (false, false) => PowerSupplyStatus::Discharging,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            PowerSupplyStatus::Charging => "Charging",
+            PowerSupplyStatus::Discharging => "Discharging",
+            PowerSupplyStatus::NotCharging => "Not charging",
+            PowerSupplyStatus::Full => "Full",
+        }
+    }
+}
+
+/// Export `capacity` and `status` files like `/sys/class/power_supply/BAT0`, for generic UPS monitors
+pub struct PowerSupplyExport {
+    dir: PathBuf,
+    exported: Option<(u8, PowerSupplyStatus)>,
+}
+
+impl PowerSupplyExport {
+    /// Create new export, directory is created on first write
+    pub fn new(dir: &str) -> Self {
+        Self {
+            dir: PathBuf::from(dir),
+            exported: None,
+        }
+    }
+
+    /// Export directory
+    pub fn dir(&self) -> &str {
+        self.dir.to_str().unwrap_or_default()
+    }
+
+    /// Write level (%) and status, files are only rewritten when changed
+    pub fn write(&mut self, level: f32, status: PowerSupplyStatus) -> io::Result<()> {
+        let capacity = level.clamp(0.0, 100.0).round() as u8;
+        if self.exported == Some((capacity, status)) {
+            return Ok(());
+        }
+        fs::create_dir_all(&self.dir)?;
+        write_atomic(&self.dir.join("capacity"), &format!("{}\n", capacity))?;
+        write_atomic(&self.dir.join("status"), &format!("{}\n", status.as_str()))?;
+        self.exported = Some((capacity, status));
+        Ok(())
+    }
+}
+
+/// Write to a temp file then rename, readers never see a partial file
+fn write_atomic(path: &Path, content: &str) -> io::Result<()> {
+    let mut tmp = path.to_path_buf().into_os_string();
+    tmp.push(".tmp");
+    let mut file = fs::File::create(&tmp)?;
+    file.write_all(content.as_bytes())?;
+    file.sync_all()?;
+    fs::rename(&tmp, path)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::{PowerSupplyExport, PowerSupplyStatus};
+
+    #[test]
+    fn test_power_supply_export() {
+        let dir = std::env::temp_dir().join(format!("pisugar-power-supply-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+
+        let mut export = PowerSupplyExport::new(dir.to_str().unwrap());
+        export.write(87.6, PowerSupplyStatus::Charging).unwrap();
+        assert_eq!(fs::read_to_string(dir.join("capacity")).unwrap(), "88\n");
+        assert_eq!(fs::read_to_string(dir.join("status")).unwrap(), "Charging\n");
+
+        export.write(100.2, PowerSupplyStatus::Full).unwrap();
+        assert_eq!(fs::read_to_string(dir.join("capacity")).unwrap(), "100\n");
+        assert_eq!(fs::read_to_string(dir.join("status")).unwrap(), "Full\n");
+        assert!(fs::metadata(dir.join("status.tmp")).is_err());
+
+        assert_eq!(
+            PowerSupplyStatus::new(false, false, true),
+            PowerSupplyStatus::Discharging
+        );
+        assert_eq!(
+            PowerSupplyStatus::new(false, true, false),
+            PowerSupplyStatus::NotCharging
+        );
+        assert_eq!(PowerSupplyStatus::new(true, true, true), PowerSupplyStatus::Charging);
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
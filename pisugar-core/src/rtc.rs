@@ -1,10 +1,15 @@
 use std::convert::{TryFrom, TryInto};
 use std::fmt::{self, Display};
+use std::iter::FromIterator;
+use std::str::FromStr;
 
 use chrono::prelude::*;
 use chrono::{DateTime, Local, LocalResult, Utc};
+use serde::de::Error as _;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 
-use crate::{PiSugarConfig, Result};
+use crate::i2c::RegisterDump;
+use crate::{Error, PiSugarConfig, Result};
 
 pub fn bcd_to_dec(bcd: u8) -> u8 {
     (bcd & 0x0F) + (((bcd & 0xF0) >> 4) * 10)
@@ -14,6 +19,11 @@ pub fn dec_to_bcd(dec: u8) -> u8 {
     (dec % 10) | ((dec / 10) << 4)
 }
 
+/// Both nibbles are 0-9
+pub fn is_bcd(bcd: u8) -> bool {
+    bcd & 0x0F <= 9 && bcd >> 4 <= 9
+}
+
 #[allow(dead_code)]
 pub fn ensure_bcd(bcd: u8, max: u8) -> u8 {
     let mut r1 = bcd >> 4;
@@ -86,6 +96,27 @@ impl RTCRawTime {
         bcd_to_dec(self.0[0])
     }
 
+    /// From datetime, error if year is out of 2000-2099
+    pub fn checked_from<Tz: TimeZone>(dt: DateTime<Tz>) -> Result<Self> {
+        let dt = dt.with_timezone(&Utc);
+        if !(2000..=2099).contains(&dt.year()) {
+            return Err(Error::Other(format!("Year {} out of rtc range 2000-2099", dt.year())));
+        }
+        Ok(dt.into())
+    }
+
+    /// From RFC 3339 string, error if year is out of 2000-2099
+    pub fn from_rfc3339(s: &str) -> Result<Self> {
+        let dt = DateTime::parse_from_rfc3339(s).map_err(|e| Error::Other(format!("Invalid time {}: {}", s, e)))?;
+        Self::checked_from(dt)
+    }
+
+    /// To RFC 3339 string in local time, e.g. 2020-01-01T08:00:00+08:00
+    pub fn to_rfc3339(&self) -> Result<String> {
+        let dt: DateTime<Local> = (*self).try_into().map_err(Error::Other)?;
+        Ok(dt.to_rfc3339_opts(SecondsFormat::Secs, false))
+    }
+
     /// To dec
     pub fn to_dec(&self) -> [u8; 7] {
         [
@@ -110,6 +141,7 @@ impl Display for RTCRawTime {
     }
 }
 
+/// NOTE: year wraps around out of 2000-2099, e.g. 2100 -> 2000, use `RTCRawTime::checked_from` instead
 impl From<DateTime<Utc>> for RTCRawTime {
     fn from(dt: DateTime<Utc>) -> Self {
         let mut t = RTCRawTime([0; 7]);
@@ -128,6 +160,11 @@ impl TryFrom<RTCRawTime> for DateTime<Utc> {
     type Error = String;
 
     fn try_from(t: RTCRawTime) -> std::result::Result<Self, Self::Error> {
+        // e.g. decimal 14 (0x0e) written instead of BCD 0x14, weekday is skipped, alarm keeps a repeat mask there
+        let bcd = [t.0[0], t.0[1], t.0[2], t.0[4], t.0[5], t.0[6]];
+        if let Some(b) = bcd.iter().find(|b| !is_bcd(**b)) {
+            return Err(format!("Invalid BCD byte {:#04x} in rtc time {}", b, t));
+        }
         let sec = bcd_to_dec(t.0[0]) as u32;
         let min = bcd_to_dec(t.0[1]) as u32;
         let hour = bcd_to_dec(t.0[2]) as u32;
@@ -161,6 +198,155 @@ impl TryFrom<RTCRawTime> for DateTime<Local> {
     }
 }
 
+/// Serialized as RFC 3339 string in local time, invalid BCD fails
+impl Serialize for RTCRawTime {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        let s = self.to_rfc3339().map_err(serde::ser::Error::custom)?;
+        serializer.serialize_str(&s)
+    }
+}
+
+/// RFC 3339 string within rtc range (2000-2099)
+impl<'de> Deserialize<'de> for RTCRawTime {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        RTCRawTime::from_rfc3339(&s).map_err(D::Error::custom)
+    }
+}
+
+/// Alarm weekday repeat mask, bit 0 = Sunday, bit 6 = Saturday
+#[derive(Default, Copy, Clone, Eq, PartialEq, Debug)]
+pub struct WeekdayMask(u8);
+
+impl WeekdayMask {
+    /// Mask of every day
+    pub const EVERYDAY: WeekdayMask = WeekdayMask(0b0111_1111);
+
+    /// From raw mask, bit 7 is ignored
+    pub fn from_bits(bits: u8) -> Self {
+        Self(bits & 0b0111_1111)
+    }
+
+    /// Raw mask
+    pub fn bits(&self) -> u8 {
+        self.0
+    }
+
+    /// Add a weekday
+    pub fn with(mut self, weekday: Weekday) -> Self {
+        self.0 |= 1 << weekday.num_days_from_sunday();
+        self
+    }
+
+    /// Weekday is repeated
+    pub fn contains(&self, weekday: Weekday) -> bool {
+        self.0 & (1 << weekday.num_days_from_sunday()) != 0
+    }
+
+    /// Repeated weekdays, from Sunday
+    pub fn weekdays(&self) -> Vec<Weekday> {
+        let mut weekdays = Vec::new();
+        let mut weekday = Weekday::Sun;
+        for _ in 0..7 {
+            if self.contains(weekday) {
+                weekdays.push(weekday);
+            }
+            weekday = weekday.succ();
+        }
+        weekdays
+    }
+}
+
+impl FromIterator<Weekday> for WeekdayMask {
+    fn from_iter<I: IntoIterator<Item = Weekday>>(iter: I) -> Self {
+        iter.into_iter().fold(WeekdayMask::default(), WeekdayMask::with)
+    }
+}
+
+impl FromStr for WeekdayMask {
+    type Err = String;
+
+    /// Raw mask number, or weekday names, e.g. "mon,tue,fri"
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        if let Ok(bits) = s.parse::<u8>() {
+            return Ok(Self::from_bits(bits));
+        }
+        if s == "none" {
+            return Ok(Self::default());
+        }
+        s.split(',')
+            .map(|name| {
+                name.trim()
+                    .parse::<Weekday>()
+                    .map_err(|_| format!("Invalid weekday: {}", name))
+            })
+            .collect()
+    }
+}
+
+impl Display for WeekdayMask {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let weekdays = self.weekdays();
+        if weekdays.is_empty() {
+            return write!(f, "none");
+        }
+        let names: Vec<String> = weekdays.iter().map(|w| w.to_string().to_lowercase()).collect();
+        write!(f, "{}", names.join(","))
+    }
+}
+
+/// Serialized as weekday names, e.g. ["mon", "tue"]
+impl Serialize for WeekdayMask {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        let names: Vec<String> = self.weekdays().iter().map(|w| w.to_string().to_lowercase()).collect();
+        names.serialize(serializer)
+    }
+}
+
+/// Weekday names, raw mask number, or a comma separated string
+impl<'de> Deserialize<'de> for WeekdayMask {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Repr {
+            Bits(u8),
+            Names(Vec<String>),
+            Text(String),
+        }
+        match Repr::deserialize(deserializer)? {
+            Repr::Bits(bits) => Ok(WeekdayMask::from_bits(bits)),
+            Repr::Names(names) => names
+                .iter()
+                .map(|name| {
+                    name.parse::<Weekday>()
+                        .map_err(|_| D::Error::custom(format!("Invalid weekday: {}", name)))
+                })
+                .collect(),
+            Repr::Text(s) => s.parse().map_err(D::Error::custom),
+        }
+    }
+}
+
+/// Next time the alarm fires after `now`, alarm time of day on a weekday in `weekday_repeat`, None if no weekday
+///
+/// NOTE: rtc runs in UTC, so are the alarm time and weekdays
+pub fn next_alarm_datetime(
+    alarm: RTCRawTime,
+    weekday_repeat: WeekdayMask,
+    now: DateTime<Utc>,
+) -> Option<DateTime<Utc>> {
+    let time = NaiveTime::from_hms_opt(
+        bcd_to_dec(alarm.0[2]) as u32,
+        bcd_to_dec(alarm.0[1]) as u32,
+        bcd_to_dec(alarm.0[0]) as u32,
+    )?;
+    let today = now.naive_utc().date();
+    (0..=7)
+        .filter_map(|days| today.checked_add_signed(chrono::Duration::days(days)))
+        .map(|date| Utc.from_utc_datetime(&date.and_time(time)))
+        .find(|t| *t > now && weekday_repeat.contains(t.weekday()))
+}
+
 /// RTC trait
 pub trait RTC {
     /// Init
@@ -196,9 +382,23 @@ pub trait RTC {
     /// Toggle frequency alarm (to prevent falling asleep)
     fn toggle_frequency_alarm(&self, enable: bool) -> Result<()>;
 
-    /// Set a test wake up after 1 minutes
-    fn set_test_wake(&self) -> Result<()> {
-        let now = Utc::now();
+    /// Rtc lost power and its time is invalid, false if the rtc can't tell
+    fn read_power_down_flag(&self) -> Result<bool> {
+        Ok(false)
+    }
+
+    /// Clear power down flag, after a valid time is written
+    fn clear_power_down_flag(&self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Chip registers for diagnostics, None if the chip has no register dump
+    fn register_dump(&self) -> Option<RegisterDump> {
+        None
+    }
+
+    /// Set rtc time to `now` and a test wake up after 1 minutes
+    fn set_test_wake(&self, now: DateTime<Utc>) -> Result<()> {
         self.write_time(now.into())?;
 
         let duration = chrono::Duration::seconds(90);
@@ -222,3 +422,157 @@ pub trait RTC {
     /// Is battery full
     fn read_battery_high_flag(&self) -> Result<bool>;
 }
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryFrom;
+
+    use chrono::{DateTime, TimeZone, Timelike, Utc, Weekday};
+
+    use super::{is_bcd, next_alarm_datetime, RTCRawTime, WeekdayMask};
+    use crate::PiSugarConfig;
+
+    #[test]
+    fn test_checked_from() {
+        assert!(RTCRawTime::checked_from(Utc.with_ymd_and_hms(1999, 12, 31, 23, 59, 59).unwrap()).is_err());
+        assert!(RTCRawTime::checked_from(Utc.with_ymd_and_hms(2100, 1, 1, 0, 0, 0).unwrap()).is_err());
+
+        let t = RTCRawTime::checked_from(Utc.with_ymd_and_hms(2000, 1, 1, 0, 0, 0).unwrap()).unwrap();
+        assert_eq!(t.year(), 2000);
+        let t = RTCRawTime::checked_from(Utc.with_ymd_and_hms(2099, 12, 31, 23, 59, 59).unwrap()).unwrap();
+        assert_eq!(t.year(), 2099);
+
+        // infallible conversion wraps around
+        let t: RTCRawTime = Utc.with_ymd_and_hms(2100, 1, 1, 0, 0, 0).unwrap().into();
+        assert_eq!(t.year(), 2000);
+    }
+
+    #[test]
+    fn test_weekday_mask() {
+        let workdays: WeekdayMask = [Weekday::Mon, Weekday::Tue, Weekday::Wed, Weekday::Thu, Weekday::Fri]
+            .iter()
+            .copied()
+            .collect();
+        assert_eq!(workdays.bits(), 0b0011_1110);
+        assert_eq!(
+            workdays.weekdays(),
+            vec![Weekday::Mon, Weekday::Tue, Weekday::Wed, Weekday::Thu, Weekday::Fri]
+        );
+        assert_eq!(workdays.to_string(), "mon,tue,wed,thu,fri");
+        assert_eq!("mon,tue,wed,thu,fri".parse::<WeekdayMask>(), Ok(workdays));
+        assert_eq!("62".parse::<WeekdayMask>(), Ok(workdays));
+
+        let weekend = WeekdayMask::default().with(Weekday::Sun).with(Weekday::Sat);
+        assert_eq!(weekend.bits(), 0b0100_0001);
+        assert_eq!("Sunday, Saturday".parse::<WeekdayMask>(), Ok(weekend));
+
+        assert_eq!(WeekdayMask::from_bits(0xff), WeekdayMask::EVERYDAY);
+        assert_eq!(WeekdayMask::default().to_string(), "none");
+        assert!("mon,funday".parse::<WeekdayMask>().is_err());
+
+        assert_eq!(
+            serde_json::to_string(&workdays).unwrap(),
+            r#"["mon","tue","wed","thu","fri"]"#
+        );
+        assert_eq!(serde_json::from_str::<WeekdayMask>("62").unwrap(), workdays);
+        assert_eq!(serde_json::from_str::<WeekdayMask>(r#""sun,sat""#).unwrap(), weekend);
+        assert!(serde_json::from_str::<WeekdayMask>(r#"["mon","funday"]"#).is_err());
+    }
+
+    #[test]
+    fn test_rfc3339() {
+        let times = [
+            "2000-01-01T00:00:00+00:00",
+            "2021-06-15T12:00:59+00:00",
+            "2024-02-29T23:59:00+08:00",
+            "2024-03-01T00:00:00-05:30",
+            "2099-12-31T23:59:59+00:00",
+        ];
+        for s in times.iter() {
+            let t = RTCRawTime::from_rfc3339(s).unwrap();
+            let formatted = t.to_rfc3339().unwrap();
+            assert_eq!(
+                DateTime::parse_from_rfc3339(&formatted).unwrap(),
+                DateTime::parse_from_rfc3339(s).unwrap()
+            );
+            assert_eq!(RTCRawTime::from_rfc3339(&formatted).unwrap(), t);
+        }
+
+        let t = RTCRawTime::from_rfc3339("2021-06-15T12:00:59+02:00").unwrap();
+        assert_eq!((t.hour(), t.minute(), t.second()), (10, 0, 59));
+
+        // out of rtc range
+        assert!(RTCRawTime::from_rfc3339("1999-12-31T23:59:59+00:00").is_err());
+        assert!(RTCRawTime::from_rfc3339("2100-01-01T00:00:00+00:00").is_err());
+        assert!(RTCRawTime::from_rfc3339("2100-01-01T00:30:00+01:00").is_ok());
+        assert!(RTCRawTime::from_rfc3339("yesterday").is_err());
+        assert!(RTCRawTime::from_dec([0, 0, 0, 0, 0, 13, 21]).to_rfc3339().is_err());
+    }
+
+    #[test]
+    fn test_rtc_raw_time_serde() {
+        let t = RTCRawTime::from_rfc3339("2024-02-29T23:59:00+08:00").unwrap();
+        let json = serde_json::to_string(&t).unwrap();
+        assert_eq!(json, format!("\"{}\"", t.to_rfc3339().unwrap()));
+        assert_eq!(serde_json::from_str::<RTCRawTime>(&json).unwrap(), t);
+
+        assert!(serde_json::from_str::<RTCRawTime>(r#""2100-01-01T00:00:00+00:00""#).is_err());
+        assert!(serde_json::from_str::<RTCRawTime>(r#""not a time""#).is_err());
+        assert!(serde_json::from_str::<RTCRawTime>("[0, 0, 0, 0, 1, 1, 0]").is_err());
+        assert!(serde_json::to_string(&RTCRawTime::from_dec([0, 0, 0, 0, 0, 13, 21])).is_err());
+    }
+
+    #[test]
+    fn test_illegal_bcd() {
+        assert!(is_bcd(0x59));
+        assert!(!is_bcd(0x0e));
+        assert!(!is_bcd(0xa0));
+
+        // hour 14 written as decimal
+        let t = RTCRawTime([0x00, 0x30, 14, 0x05, 0x16, 0x10, 0x26]);
+        let e = DateTime::<Utc>::try_from(t).unwrap_err();
+        assert!(e.contains("Invalid BCD byte 0x0e"), "{}", e);
+        assert!(t.to_rfc3339().is_err());
+        assert!(serde_json::to_string(&t).is_err());
+
+        // alarm time, weekday repeat mask of every day
+        let alarm = RTCRawTime([0x00, 0x30, 0x07, 0x7f, 0x01, 0x01, 0x00]);
+        assert_eq!(DateTime::<Utc>::try_from(alarm).unwrap().hour(), 7);
+        assert!(serde_json::to_string(&alarm).is_ok());
+
+        // auto_wake_time is a time string, converted to BCD when armed
+        let config: PiSugarConfig = serde_json::from_str(r#"{"auto_wake_time": "2026-10-16T14:30:00+00:00"}"#).unwrap();
+        let t = RTCRawTime::from(config.auto_wake_time.unwrap());
+        assert_eq!(&t.0[..3], &[0x00, 0x30, 0x14]);
+        assert_eq!(DateTime::<Utc>::try_from(t).unwrap().hour(), 14);
+        assert!(serde_json::from_str::<PiSugarConfig>(r#"{"auto_wake_time": "14:30"}"#).is_err());
+    }
+
+    #[test]
+    fn test_next_alarm_datetime() {
+        let at = |d, h, m| {
+            let t = chrono::NaiveDate::from_ymd_opt(2026, 10, d)
+                .unwrap()
+                .and_hms_opt(h, m, 0)
+                .unwrap();
+            Utc.from_utc_datetime(&t)
+        };
+        // Friday 2026-10-16 08:00
+        let now = at(16, 8, 0);
+        let alarm = RTCRawTime::from(at(1, 18, 30));
+
+        assert_eq!(
+            next_alarm_datetime(alarm, WeekdayMask::EVERYDAY, now),
+            Some(at(16, 18, 30))
+        );
+        assert_eq!(
+            next_alarm_datetime(alarm, WeekdayMask::EVERYDAY, at(16, 18, 30)),
+            Some(at(17, 18, 30))
+        );
+        let mask = WeekdayMask::default().with(Weekday::Mon);
+        assert_eq!(next_alarm_datetime(alarm, mask, now), Some(at(19, 18, 30)));
+        let mask = WeekdayMask::default().with(Weekday::Fri);
+        assert_eq!(next_alarm_datetime(alarm, mask, at(16, 19, 0)), Some(at(23, 18, 30)));
+        assert_eq!(next_alarm_datetime(alarm, WeekdayMask::default(), now), None);
+    }
+}
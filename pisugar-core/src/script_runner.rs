@@ -0,0 +1,103 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Sender};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use crate::config::ScriptOverlap;
+use crate::execute_shell_with_timeout;
+
+/// Max scripts waiting or running, more are dropped
+pub const SCRIPT_QUEUE_CAPACITY: usize = 8;
+
+/// Script to run on the worker
+struct Script {
+    shell: String,
+    envs: Vec<(&'static str, String)>,
+    timeout: Option<Duration>,
+}
+
+/// Runs scripts one by one on a worker thread, so poll never waits for them
+///
+/// The worker exits when the runner is dropped
+pub struct ScriptRunner {
+    tx: Sender<Script>,
+    pending: Arc<AtomicUsize>,
+}
+
+impl ScriptRunner {
+    pub fn new() -> Self {
+        let (tx, rx) = mpsc::channel::<Script>();
+        let pending = Arc::new(AtomicUsize::new(0));
+        let worker_pending = pending.clone();
+        thread::spawn(move || {
+            for script in rx {
+                match execute_shell_with_timeout(&script.shell, &script.envs, script.timeout) {
+                    Ok(r) => log::info!("Script ok, code: {:?}", r.code()),
+                    Err(e) => log::error!("{}", e),
+                }
+                worker_pending.fetch_sub(1, Ordering::SeqCst);
+            }
+        });
+        Self { tx, pending }
+    }
+
+    /// Queue a script, false if it's dropped by `overlap` or a full queue, `Parallel` queues too
+    pub fn submit(
+        &self,
+        shell: String,
+        envs: Vec<(&'static str, String)>,
+        timeout: Option<Duration>,
+        overlap: ScriptOverlap,
+    ) -> bool {
+        let pending = self.pending.load(Ordering::SeqCst);
+        if pending >= SCRIPT_QUEUE_CAPACITY || (overlap == ScriptOverlap::Drop && pending > 0) {
+            return false;
+        }
+        self.pending.fetch_add(1, Ordering::SeqCst);
+        let script = Script { shell, envs, timeout };
+        if self.tx.send(script).is_err() {
+            self.pending.fetch_sub(1, Ordering::SeqCst);
+            return false;
+        }
+        true
+    }
+
+    /// Scripts waiting or running
+    pub fn pending(&self) -> usize {
+        self.pending.load(Ordering::SeqCst)
+    }
+}
+
+impl Default for ScriptRunner {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::{ScriptRunner, SCRIPT_QUEUE_CAPACITY};
+    use crate::config::ScriptOverlap;
+
+    #[test]
+    fn test_script_runner() {
+        let runner = ScriptRunner::new();
+        let start = Instant::now();
+        assert!(runner.submit("sleep 1".to_string(), vec![], None, ScriptOverlap::Queue));
+        // a slow script doesn't hold up the caller
+        assert!(start.elapsed() < Duration::from_millis(500));
+
+        assert!(!runner.submit("true".to_string(), vec![], None, ScriptOverlap::Drop));
+        assert!(runner.submit("true".to_string(), vec![], None, ScriptOverlap::Queue));
+        assert_eq!(runner.pending(), 2);
+
+        while runner.pending() < SCRIPT_QUEUE_CAPACITY {
+            assert!(runner.submit("true".to_string(), vec![], None, ScriptOverlap::Queue));
+        }
+        assert!(!runner.submit("true".to_string(), vec![], None, ScriptOverlap::Queue));
+        assert!(start.elapsed() < Duration::from_millis(500));
+    }
+}
@@ -1,58 +1,257 @@
-use rppal::i2c::I2c;
+use std::cell::Cell;
+use std::convert::TryInto;
+use std::fmt;
+use std::time::Duration;
 
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+
+use crate::i2c::{I2c, RegisterDump};
+
+use crate::{open_i2c, retry_i2c, Error, I2cError, PiSugarConfig, Result};
 use crate::{
-    rtc::{bcd_to_dec, dec_to_bcd, RTCRawTime, RTC},
+    rtc::{bcd_to_dec, dec_to_bcd, next_alarm_datetime, RTCRawTime, WeekdayMask, RTC},
     Model,
 };
-use crate::{PiSugarConfig, Result};
+
+/// SD3078 battery backed user RAM, 70 bytes at 0x2c-0x71
+pub const SD3078_USER_RAM_ADDR: u8 = 0x2c;
+pub const SD3078_USER_RAM_SIZE: usize = 70;
+
+/// SMBus block read/write limit
+const I2C_BLOCK_MAX: usize = 32;
+
+/// Byte-wise time reads before giving up on a consistent time
+const SD3078_TIME_READ_TRIES: usize = 3;
+
+/// Countdown timer, 24-bit counter at 0x13 (low) - 0x15 (high), clock source in CTR3 TDS1/TDS0 (bit5:4)
+pub const SD3078_COUNTDOWN_REG: u8 = 0x13;
+pub const SD3078_COUNTDOWN_MAX: u32 = 0x00ff_ffff;
+
+/// Countdown clock source, TDS1/TDS0: 0b00 4096Hz, 0b01 1024Hz, 0b10 1Hz, 0b11 1/60Hz
+const COUNTDOWN_TDS_1HZ: u8 = 0b10;
+const COUNTDOWN_TDS_1_60HZ: u8 = 0b11;
+
+/// Countdown clock source and counter bytes of `after`, in seconds up to ~194 days, then in minutes, rounded up
+fn countdown_registers(after: Duration) -> Result<(u8, [u8; 3])> {
+    let secs = after.as_secs() + if after.subsec_nanos() > 0 { 1 } else { 0 };
+    let (tds, count) = if secs <= SD3078_COUNTDOWN_MAX as u64 {
+        (COUNTDOWN_TDS_1HZ, secs)
+    } else {
+        (COUNTDOWN_TDS_1_60HZ, secs.div_ceil(60))
+    };
+    if count == 0 || count > SD3078_COUNTDOWN_MAX as u64 {
+        return Err(Error::Other(format!("Countdown {}s out of range", secs)));
+    }
+    let b = (count as u32).to_le_bytes();
+    Ok((tds, [b[0], b[1], b[2]]))
+}
+
+/// Duration of countdown clock source and counter bytes
+fn countdown_duration(tds: u8, count: [u8; 3]) -> Duration {
+    let count = u32::from_le_bytes([count[0], count[1], count[2], 0]) as u64;
+    match tds & 0b11 {
+        0b00 => Duration::from_micros(count * 1_000_000 / 4096),
+        0b01 => Duration::from_micros(count * 1_000_000 / 1024),
+        COUNTDOWN_TDS_1HZ => Duration::from_secs(count),
+        _ => Duration::from_secs(count * 60),
+    }
+}
+
+/// User RAM register of offset, error if `len` bytes from offset don't fit
+fn user_ram_reg(offset: usize, len: usize) -> Result<u8> {
+    match offset.checked_add(len) {
+        Some(end) if end <= SD3078_USER_RAM_SIZE => Ok(SD3078_USER_RAM_ADDR + offset as u8),
+        _ => Err(Error::Other(format!(
+            "User RAM {} bytes at {} out of range, size {}",
+            len, offset, SD3078_USER_RAM_SIZE
+        ))),
+    }
+}
+
+/// Alarm registers 0x07-0x10: alarm time 0x07-0x0d, alarm enable 0x0e, CTR1 0x0f, CTR2 0x10
+const ALARM_STATE_REG: u8 = 0x07;
+const ALARM_STATE_LEN: usize = 10;
+
+/// Alarm snapshot of one register read
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct AlarmState {
+    /// Alarm enabled and its interrupt on
+    pub armed: bool,
+    /// Alarm time, date is 2000-01-01
+    pub time: RTCRawTime,
+    /// Weekday repeat
+    pub weekday_mask: WeekdayMask,
+    /// Alarm or countdown fired (INTAF/INTDF)
+    pub flag_set: bool,
+}
+
+impl AlarmState {
+    /// Decode registers from 0x07
+    fn from_registers(regs: &[u8; ALARM_STATE_LEN]) -> Self {
+        let (enable, ctr1, ctr2) = (regs[7], regs[8], regs[9]);
+        // always 24hr
+        let time = RTCRawTime([regs[0], regs[1], regs[2] & 0b0011_1111, regs[3], 1, 1, 0]);
+        Self {
+            armed: enable & 0b0000_0111 != 0 && ctr2 & 0b0000_0010 != 0,
+            time,
+            weekday_mask: WeekdayMask::from_bits(regs[3]),
+            flag_set: ctr1 & 0b0011_0000 != 0,
+        }
+    }
+}
+
+/// Toggle RTC write protect
+trait WriteProtect {
+    /// Disable write protect
+    fn enable_write(&self) -> Result<()>;
+
+    /// Enable write protect
+    fn disable_write(&self) -> Result<()>;
+}
+
+/// Write protect is disabled while the guard lives
+///
+/// `finish` enables it again and returns the error, drop is the best-effort fallback on early return
+struct WriteProtectGuard<'a, T: WriteProtect> {
+    rtc: Option<&'a T>,
+}
+
+impl<'a, T: WriteProtect> WriteProtectGuard<'a, T> {
+    fn new(rtc: &'a T) -> Result<Self> {
+        // a partially disabled write protect is restored by drop too
+        let guard = Self { rtc: Some(rtc) };
+        rtc.enable_write()?;
+        Ok(guard)
+    }
+
+    /// Enable write protect
+    fn finish(mut self) -> Result<()> {
+        match self.rtc.take() {
+            Some(rtc) => rtc.disable_write(),
+            None => Ok(()),
+        }
+    }
+}
+
+impl<'a, T: WriteProtect> Drop for WriteProtectGuard<'a, T> {
+    fn drop(&mut self) {
+        if let Some(rtc) = self.rtc.take() {
+            if let Err(e) = rtc.disable_write() {
+                log::error!("RTC enable write protect error: {}", e);
+            }
+        }
+    }
+}
+
+/// Registers in `Debug` output, time, alarm and control registers
+const SD3078_DEBUG_REGS: [u8; 18] = [
+    0x00, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x0a, 0x0b, 0x0c, 0x0d, 0x0e, 0x0f, 0x10, 0x11,
+];
 
 /// SD3078, rtc chip
 pub struct SD3078 {
     i2c: I2c,
+    i2c_addr: u16,
     cfg: PiSugarConfig,
+    /// Bus supports SMBus block transfers, cleared at the first unsupported one
+    block_supported: Cell<bool>,
 }
 
 impl SD3078 {
     /// Create new SD3078
     pub fn new(cfg: PiSugarConfig, model: Model) -> Result<Self> {
-        let mut i2c = I2c::with_bus(cfg.i2c_bus)?;
-        i2c.set_slave_address(model.default_rtc_i2c_addr())?;
-        Ok(Self { i2c, cfg })
+        let i2c_addr = model.rtc_i2c_addr(&cfg);
+        let i2c = open_i2c(cfg.i2c_bus, i2c_addr)?;
+        Ok(Self {
+            i2c,
+            i2c_addr,
+            cfg,
+            block_supported: Cell::new(true),
+        })
     }
 
-    /// Disable write protect
-    fn enable_write(&self) -> Result<()> {
-        // ctr2 - wrtc1
-        let mut crt2 = self.i2c.smbus_read_byte(0x10)?;
-        crt2 |= 0b1000_0000;
-        self.i2c.smbus_write_byte(0x10, crt2)?;
+    /// Read `SD3078_DEBUG_REGS` one by one, hardware reads, keep it out of hot paths
+    pub fn debug_dump(&self) -> RegisterDump {
+        RegisterDump::read(&self.i2c, &SD3078_DEBUG_REGS)
+    }
 
-        // ctr1 - wrtc2 and wrtc3
-        let mut crt2 = self.i2c.smbus_read_byte(0x0f)?;
-        crt2 |= 0b1000_0100;
-        self.i2c.smbus_write_byte(0x0f, crt2)?;
+    /// SD3078 of an in-memory chip
+    #[cfg(all(test, feature = "mock-hardware"))]
+    pub(crate) fn mock(cfg: PiSugarConfig) -> Self {
+        Self {
+            i2c: I2c::mock(),
+            i2c_addr: crate::I2C_ADDR_RTC,
+            cfg,
+            block_supported: Cell::new(true),
+        }
+    }
 
+    /// Count of block reads of the in-memory chip
+    #[cfg(all(test, feature = "mock-hardware"))]
+    pub(crate) fn block_reads(&self) -> std::sync::Arc<std::sync::atomic::AtomicUsize> {
+        self.i2c.block_reads()
+    }
+
+    /// Read registers from `reg`, one by one if the bus doesn't support block transfers
+    fn read_regs(&self, reg: u8, buf: &mut [u8]) -> Result<()> {
+        if self.block_supported.get() {
+            match retry_i2c(|| self.i2c.block_read(reg, buf)) {
+                Err(Error::I2c(I2cError::FeatureNotSupported)) => self.block_unsupported(),
+                r => return r,
+            }
+        }
+        for (i, v) in buf.iter_mut().enumerate() {
+            *v = retry_i2c(|| self.i2c.smbus_read_byte(reg + i as u8))?;
+        }
         Ok(())
     }
 
-    /// Enable write protect
-    fn disable_write(&self) -> Result<()> {
-        // ctr1 - wrtc2 and wrtc3
-        let mut crt1 = self.i2c.smbus_read_byte(0x0f)?;
-        crt1 &= 0b0111_1011;
-        self.i2c.smbus_write_byte(0x0f, crt1)?;
+    /// Write registers from `reg`, one by one if the bus doesn't support block transfers
+    ///
+    /// NOTE: byte writes of time registers are not atomic, a carry between them is possible but rare
+    fn write_regs(&self, reg: u8, buf: &[u8]) -> Result<()> {
+        if self.block_supported.get() {
+            match retry_i2c(|| self.i2c.block_write(reg, buf)) {
+                Err(Error::I2c(I2cError::FeatureNotSupported)) => self.block_unsupported(),
+                r => return r,
+            }
+        }
+        for (i, v) in buf.iter().enumerate() {
+            retry_i2c(|| self.i2c.smbus_write_byte(reg + i as u8, *v))?;
+        }
+        Ok(())
+    }
 
-        // ctr2 - wrtc1
-        let mut crt2 = self.i2c.smbus_read_byte(0x10)?;
-        crt2 &= 0b0111_1111;
-        self.i2c.smbus_write_byte(0x10, crt2)?;
+    /// Read time registers, a byte-wise read is retried if the seconds changed meanwhile, e.g. a carry from
+    /// 59s into the minutes between two byte reads
+    fn read_time_regs(&self, buf: &mut [u8; 7]) -> Result<()> {
+        for _ in 0..SD3078_TIME_READ_TRIES {
+            self.read_regs(0, buf)?;
+            if self.block_supported.get() {
+                return Ok(());
+            }
+            let sec = retry_i2c(|| self.i2c.smbus_read_byte(0))?;
+            if sec == buf[0] {
+                return Ok(());
+            }
+        }
+        Err(Error::Other("RTC time kept changing while reading".to_string()))
+    }
 
-        Ok(())
+    fn block_unsupported(&self) {
+        log::warn!("I2C block transfer not supported, fall back to byte transfers");
+        self.block_supported.set(false);
+    }
+
+    /// Disable write protect until the guard is dropped
+    fn unprotect(&self) -> Result<WriteProtectGuard<'_, Self>> {
+        WriteProtectGuard::new(self)
     }
 
     /// Disable frequency alarm
     pub fn disable_frequency_alarm(&self) -> Result<()> {
-        self.enable_write()?;
+        let guard = self.unprotect()?;
 
         // CTR2 - INTS1=0, INTS0=1, INTFE=0
         let mut ctr2 = self.i2c.smbus_read_byte(0x10)?;
@@ -60,14 +259,12 @@ impl SD3078 {
         ctr2 &= 0b1101_1110;
         self.i2c.smbus_write_byte(0x10, ctr2)?;
 
-        self.disable_write()?;
-
-        Ok(())
+        guard.finish()
     }
 
     /// Set frequency alarm in auto_power_on, 1/2Hz
     pub fn enable_frequency_alarm(&self) -> Result<()> {
-        self.enable_write()?;
+        let guard = self.unprotect()?;
 
         // CTR3 - 1/2Hz, FS3=1, FS2=0, FS1=1, FS0=1
         let mut ctr3 = self.i2c.smbus_read_byte(0x11)?;
@@ -81,13 +278,11 @@ impl SD3078 {
         ctr2 |= 0b0010_0001;
         self.i2c.smbus_write_byte(0x10, ctr2)?;
 
-        self.disable_write()?;
-
-        Ok(())
+        guard.finish()
     }
 
     pub fn enable_alarm(&self) -> Result<()> {
-        self.enable_write()?;
+        let guard = self.unprotect()?;
 
         // CTR2 - alarm interrupt and frequency, INTS1=0, INTS0=1, INTDE=0, INTAE=1, INTFE=0
         let mut ctr2 = self.i2c.smbus_read_byte(0x10)?;
@@ -98,13 +293,12 @@ impl SD3078 {
         // alarm allows weekday, hour/minus/second
         self.i2c.smbus_write_byte(0x0e, 0b0000_1111)?;
 
-        self.disable_write()?;
-        Ok(())
+        guard.finish()
     }
 
     /// Disable alarm
     pub fn disable_alarm(&self) -> Result<()> {
-        self.enable_write()?;
+        let guard = self.unprotect()?;
 
         // CTR2 - INTS1, clear
         let mut ctr2 = self.i2c.smbus_read_byte(0x10)?;
@@ -115,11 +309,56 @@ impl SD3078 {
         // disable alarm
         self.i2c.smbus_write_byte(0x0e, 0b0000_0000)?;
 
-        self.disable_write()?;
+        guard.finish()
+    }
+
+    /// Reset alarm to a known-good state: alarm disabled, alarm flag cleared and write protected
+    pub fn reset_alarm_state(&self) -> Result<()> {
+        let guard = self.unprotect()?;
+        self.reset_alarm_registers()?;
+        guard.finish()
+    }
+
+    /// Reset alarm registers, write protect must be disabled
+    fn reset_alarm_registers(&self) -> Result<()> {
+        // CTR2 - INTS1=0, INTAE=0, INTFE=0
+        let mut ctr2 = self.i2c.smbus_read_byte(0x10)?;
+        ctr2 &= 0b1101_1100;
+        self.i2c.smbus_write_byte(0x10, ctr2)?;
+
+        // disable alarm
+        self.i2c.smbus_write_byte(0x0e, 0b0000_0000)?;
+
+        // CTR1 - clear INTDF and INTAF
+        let mut ctr1 = self.i2c.smbus_read_byte(0x0f)?;
+        ctr1 &= 0b1100_1111;
+        self.i2c.smbus_write_byte(0x0f, ctr1)?;
 
         Ok(())
     }
 
+    /// Read user RAM, offset 0-69, see `SD3078_USER_RAM_SIZE`
+    pub fn read_user_ram(&self, offset: usize, len: usize) -> Result<Vec<u8>> {
+        let reg = user_ram_reg(offset, len)?;
+        let mut data = vec![0_u8; len];
+        for (i, chunk) in data.chunks_mut(I2C_BLOCK_MAX).enumerate() {
+            let reg = reg + (i * I2C_BLOCK_MAX) as u8;
+            self.read_regs(reg, chunk)?;
+        }
+        Ok(data)
+    }
+
+    /// Write user RAM, offset 0-69, kept on battery across power loss
+    pub fn write_user_ram(&self, offset: usize, data: &[u8]) -> Result<()> {
+        let reg = user_ram_reg(offset, data.len())?;
+        let guard = self.unprotect()?;
+        for (i, chunk) in data.chunks(I2C_BLOCK_MAX).enumerate() {
+            let reg = reg + (i * I2C_BLOCK_MAX) as u8;
+            self.write_regs(reg, chunk)?;
+        }
+        guard.finish()
+    }
+
     /// Read battery charging flag
     pub fn read_battery_charging_flag(&self) -> Result<bool> {
         let v = self.i2c.smbus_read_byte(0x18)?;
@@ -140,6 +379,112 @@ impl SD3078 {
 
         Ok(true)
     }
+
+    /// Wake after `after` by the countdown timer, independent of rtc time
+    ///
+    /// NOTE: INT pin is switched to the countdown interrupt, so it replaces the alarm and frequency alarm
+    pub fn set_countdown_wake(&self, after: Duration) -> Result<()> {
+        let (tds, count) = countdown_registers(after)?;
+
+        let guard = self.unprotect()?;
+
+        // CTR2 - INTDE=0, stop countdown while setting it up
+        let mut ctr2 = self.i2c.smbus_read_byte(0x10)?;
+        ctr2 &= 0b1111_1011;
+        self.i2c.smbus_write_byte(0x10, ctr2)?;
+
+        // CTR3 - countdown clock source, TDS1/TDS0
+        let mut ctr3 = self.i2c.smbus_read_byte(0x11)?;
+        ctr3 &= 0b1100_1111;
+        ctr3 |= tds << 4;
+        self.i2c.smbus_write_byte(0x11, ctr3)?;
+
+        // countdown
+        self.write_regs(SD3078_COUNTDOWN_REG, count.as_ref())?;
+
+        // CTR1 - clear INTDF
+        let mut ctr1 = self.i2c.smbus_read_byte(0x0f)?;
+        ctr1 &= 0b1110_1111;
+        self.i2c.smbus_write_byte(0x0f, ctr1)?;
+
+        // CTR2 - countdown interrupt, INTS1=1, INTS0=1, INTDE=1 starts it, INTAE=0, INTFE=0
+        ctr2 |= 0b0011_0100;
+        ctr2 &= 0b1111_1100;
+        self.i2c.smbus_write_byte(0x10, ctr2)?;
+
+        guard.finish()
+    }
+
+    /// Countdown left, None if the countdown is not enabled
+    pub fn read_countdown_remaining(&self) -> Result<Option<Duration>> {
+        let ctr2 = self.i2c.smbus_read_byte(0x10)?;
+        if ctr2 & 0b0000_0100 == 0 {
+            return Ok(None);
+        }
+        let ctr3 = self.i2c.smbus_read_byte(0x11)?;
+        let mut count = [0_u8; 3];
+        self.read_regs(SD3078_COUNTDOWN_REG, &mut count)?;
+        Ok(Some(countdown_duration(ctr3 >> 4, count)))
+    }
+
+    /// Alarm time, weekday mask, enabled and flag in one block read
+    pub fn read_alarm_state(&self) -> Result<AlarmState> {
+        let mut regs = [0_u8; ALARM_STATE_LEN];
+        self.read_regs(ALARM_STATE_REG, &mut regs)?;
+        Ok(AlarmState::from_registers(&regs))
+    }
+
+    /// Time until the armed alarm fires next, None if the alarm is not armed
+    pub fn time_until_alarm(&self) -> Result<Option<Duration>> {
+        if !self.read_alarm_enabled()? {
+            return Ok(None);
+        }
+        let alarm = self.read_alarm_time()?;
+        let now: DateTime<Utc> = self.read_time()?.try_into().map_err(Error::Other)?;
+        let next = next_alarm_datetime(alarm, WeekdayMask::from_bits(alarm.0[3]), now);
+        Ok(next.and_then(|next| (next - now).to_std().ok()))
+    }
+}
+
+impl fmt::Debug for SD3078 {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SD3078")
+            .field("i2c_addr", &format_args!("{:#04x}", self.i2c_addr))
+            .field("block_supported", &self.block_supported.get())
+            .finish()
+    }
+}
+
+impl WriteProtect for SD3078 {
+    /// Disable write protect
+    fn enable_write(&self) -> Result<()> {
+        // ctr2 - wrtc1
+        let mut crt2 = self.i2c.smbus_read_byte(0x10)?;
+        crt2 |= 0b1000_0000;
+        self.i2c.smbus_write_byte(0x10, crt2)?;
+
+        // ctr1 - wrtc2 and wrtc3
+        let mut crt2 = self.i2c.smbus_read_byte(0x0f)?;
+        crt2 |= 0b1000_0100;
+        self.i2c.smbus_write_byte(0x0f, crt2)?;
+
+        Ok(())
+    }
+
+    /// Enable write protect
+    fn disable_write(&self) -> Result<()> {
+        // ctr1 - wrtc2 and wrtc3
+        let mut crt1 = self.i2c.smbus_read_byte(0x0f)?;
+        crt1 &= 0b0111_1011;
+        self.i2c.smbus_write_byte(0x0f, crt1)?;
+
+        // ctr2 - wrtc1
+        let mut crt2 = self.i2c.smbus_read_byte(0x10)?;
+        crt2 &= 0b0111_1111;
+        self.i2c.smbus_write_byte(0x10, crt2)?;
+
+        Ok(())
+    }
 }
 
 impl RTC for SD3078 {
@@ -167,7 +512,7 @@ impl RTC for SD3078 {
     /// Read time
     fn read_time(&self) -> Result<RTCRawTime> {
         let mut bcd_time = [0_u8; 7];
-        self.i2c.block_read(0, &mut bcd_time)?;
+        self.read_time_regs(&mut bcd_time)?;
 
         // 12hr or 24hr
         if bcd_time[2] & 0b1000_0000 != 0 {
@@ -188,11 +533,10 @@ impl RTC for SD3078 {
         let mut bcd_time = t.0;
         bcd_time[2] |= 0b1000_0000;
 
-        self.enable_write()?;
-        self.i2c.block_write(0, bcd_time.as_ref())?;
-        self.disable_write()?;
+        let guard = self.unprotect()?;
+        self.write_regs(0, bcd_time.as_ref())?;
 
-        Ok(())
+        guard.finish()
     }
 
     /// Adjust ppm
@@ -203,7 +547,7 @@ impl RTC for SD3078 {
     /// Read alarm time
     fn read_alarm_time(&self) -> Result<RTCRawTime> {
         let mut bcd_time = [0_u8; 7];
-        self.i2c.block_read(0x07, &mut bcd_time)?;
+        self.read_regs(0x07, &mut bcd_time)?;
 
         // always 24hr
         bcd_time[2] &= 0b0011_1111;
@@ -218,12 +562,12 @@ impl RTC for SD3078 {
     /// Set alarm, weekday_repeat from sunday 0-6
     fn set_alarm(&self, t: RTCRawTime, weekday_repeat: u8) -> Result<()> {
         let mut bcd_time = t.0;
-        bcd_time[3] = weekday_repeat & 0x7f;
+        bcd_time[3] = WeekdayMask::from_bits(weekday_repeat).bits();
 
-        self.enable_write()?;
+        let guard = self.unprotect()?;
 
         // alarm time
-        self.i2c.block_write(0x07, bcd_time.as_ref())?;
+        self.write_regs(0x07, bcd_time.as_ref())?;
 
         // CTR2 - alarm interrupt and frequency, INTS1=0, INTS0=1, INTDE=0, INTAE=1, INTFE=0
         let mut ctr2 = self.i2c.smbus_read_byte(0x10)?;
@@ -234,9 +578,7 @@ impl RTC for SD3078 {
         // alarm allows weekday, hour/minus/second
         self.i2c.smbus_write_byte(0x0e, 0b0000_1111)?;
 
-        self.disable_write()?;
-
-        Ok(())
+        guard.finish()
     }
 
     fn is_alarm_enable(&self) -> Result<bool> {
@@ -265,12 +607,11 @@ impl RTC for SD3078 {
     /// Clear alarm flag
     fn clear_alarm_flag(&self) -> Result<()> {
         if let Ok(true) = self.read_alarm_flag() {
-            self.enable_write()?;
+            let guard = self.unprotect()?;
             let mut ctr1 = self.i2c.smbus_read_byte(0x0f)?;
             ctr1 &= 0b1100_1111;
             self.i2c.smbus_write_byte(0x0f, ctr1)?;
-
-            self.disable_write()?;
+            guard.finish()?;
         }
         Ok(())
     }
@@ -283,6 +624,24 @@ impl RTC for SD3078 {
         }
     }
 
+    /// Oscillator stopped, CTR1 RTCF
+    fn read_power_down_flag(&self) -> Result<bool> {
+        let ctr1 = self.i2c.smbus_read_byte(0x0f)?;
+        Ok(ctr1 & 0b0000_0001 != 0)
+    }
+
+    fn clear_power_down_flag(&self) -> Result<()> {
+        let guard = self.unprotect()?;
+        let mut ctr1 = self.i2c.smbus_read_byte(0x0f)?;
+        ctr1 &= 0b1111_1110;
+        self.i2c.smbus_write_byte(0x0f, ctr1)?;
+        guard.finish()
+    }
+
+    fn register_dump(&self) -> Option<RegisterDump> {
+        Some(self.debug_dump())
+    }
+
     /// Force shutdown
     fn force_shutdown(&self) -> Result<()> {
         self.disable_frequency_alarm()
@@ -296,10 +655,10 @@ impl RTC for SD3078 {
 
     /// Toggle rtc battery charging
     fn toggle_charging(&self, enable: bool) -> Result<()> {
-        self.enable_write()?;
+        let guard = self.unprotect()?;
         let v = if enable { 0x82 } else { 0x82 & 0b0111_1111 };
         self.i2c.smbus_write_byte(0x18, v)?;
-        self.disable_write()
+        guard.finish()
     }
 
     /// Read battery high flag
@@ -308,3 +667,297 @@ impl RTC for SD3078 {
         Ok(v & 0b0000_0010 != 0)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::cell::{Cell, RefCell};
+
+    use std::time::Duration;
+
+    use super::{
+        countdown_duration, countdown_registers, user_ram_reg, AlarmState, WriteProtect, WriteProtectGuard,
+        SD3078_USER_RAM_ADDR, SD3078_USER_RAM_SIZE,
+    };
+    use crate::{Error, Result};
+
+    #[derive(Default)]
+    struct FakeRTC {
+        unprotected: Cell<bool>,
+        protect_fails: Cell<bool>,
+        writes: RefCell<Vec<u8>>,
+    }
+
+    impl FakeRTC {
+        fn write(&self, v: u8) -> Result<()> {
+            assert!(self.unprotected.get(), "write while protected");
+            if v == 0xff {
+                return Err(Error::Other("write failed".to_string()));
+            }
+            self.writes.borrow_mut().push(v);
+            Ok(())
+        }
+
+        fn write_all(&self, values: &[u8]) -> Result<()> {
+            let guard = WriteProtectGuard::new(self)?;
+            for v in values {
+                self.write(*v)?;
+            }
+            guard.finish()
+        }
+    }
+
+    impl WriteProtect for FakeRTC {
+        fn enable_write(&self) -> Result<()> {
+            self.unprotected.set(true);
+            Ok(())
+        }
+
+        fn disable_write(&self) -> Result<()> {
+            if self.protect_fails.get() {
+                return Err(Error::Other("protect failed".to_string()));
+            }
+            self.unprotected.set(false);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_write_protect_guard() {
+        let rtc = FakeRTC::default();
+        rtc.write_all(&[1, 2]).unwrap();
+        assert!(!rtc.unprotected.get());
+
+        // failed in the middle, still protected
+        assert!(rtc.write_all(&[3, 0xff, 4]).is_err());
+        assert!(!rtc.unprotected.get());
+        assert_eq!(*rtc.writes.borrow(), vec![1, 2, 3]);
+
+        // writes done, write protect not restored
+        rtc.protect_fails.set(true);
+        assert!(rtc.write_all(&[5]).is_err());
+        assert!(rtc.unprotected.get());
+    }
+
+    #[test]
+    fn test_alarm_state() {
+        use crate::rtc::{RTCRawTime, WeekdayMask};
+
+        // 07:30:00 mon-fri, enabled, alarm interrupt on, alarm flag set
+        let regs = [
+            0x00,
+            0x30,
+            0x07,
+            0b0011_1110,
+            0x00,
+            0x00,
+            0x00,
+            0b0000_1111,
+            0b0010_0000,
+            0b0101_0010,
+        ];
+        let state = AlarmState::from_registers(&regs);
+        assert_eq!(
+            state,
+            AlarmState {
+                armed: true,
+                time: RTCRawTime([0x00, 0x30, 0x07, 0b0011_1110, 1, 1, 0]),
+                weekday_mask: WeekdayMask::from_bits(0b0011_1110),
+                flag_set: true,
+            }
+        );
+
+        // enabled but interrupt off, 12hr bit stripped
+        let regs = [
+            0x00,
+            0x00,
+            0x86,
+            0b0111_1111,
+            0x00,
+            0x00,
+            0x00,
+            0b0000_1111,
+            0x00,
+            0b0001_0000,
+        ];
+        let state = AlarmState::from_registers(&regs);
+        assert!(!state.armed);
+        assert!(!state.flag_set);
+        assert_eq!(state.time.hour(), 6);
+        assert_eq!(state.weekday_mask, WeekdayMask::EVERYDAY);
+    }
+
+    #[cfg(feature = "mock-hardware")]
+    #[test]
+    fn test_byte_transfer_fallback() {
+        use super::SD3078;
+        use crate::rtc::{RTCRawTime, RTC};
+        use crate::PiSugarConfig;
+
+        let t = RTCRawTime::from_dec([30, 15, 8, 5, 16, 10, 26]);
+        let block = SD3078::mock(PiSugarConfig::default());
+        block.write_time(t).unwrap();
+        block.write_user_ram(0, &[1, 2, 3]).unwrap();
+
+        let bytes = SD3078::mock(PiSugarConfig::default());
+        bytes.i2c.set_block_supported(false);
+        bytes.write_time(t).unwrap();
+        assert!(!bytes.block_supported.get());
+        bytes.write_user_ram(0, &[1, 2, 3]).unwrap();
+
+        for reg in 0..0x30 {
+            assert_eq!(
+                bytes.i2c.smbus_read_byte(reg).unwrap(),
+                block.i2c.smbus_read_byte(reg).unwrap(),
+                "{:#x}",
+                reg
+            );
+        }
+        assert_eq!(bytes.read_time().unwrap(), block.read_time().unwrap());
+        assert_eq!(bytes.read_time().unwrap(), t);
+        assert_eq!(bytes.read_user_ram(0, 3).unwrap(), vec![1, 2, 3]);
+
+        // 08:15:59 ticks to 08:16:00 between the seconds and minutes reads
+        bytes
+            .write_time(RTCRawTime::from_dec([59, 15, 8, 5, 16, 10, 26]))
+            .unwrap();
+        let mut ticked = false;
+        bytes.i2c.on_byte_read(move |reg, regs| {
+            if reg == 0 && !ticked {
+                ticked = true;
+                regs[0] = 0x00;
+                regs[1] = 0x16;
+            }
+        });
+        assert_eq!(
+            bytes.read_time().unwrap(),
+            RTCRawTime::from_dec([0, 16, 8, 5, 16, 10, 26])
+        );
+    }
+
+    #[test]
+    fn test_countdown_registers() {
+        assert_eq!(countdown_registers(Duration::from_secs(1)).unwrap(), (0b10, [1, 0, 0]));
+        assert_eq!(
+            countdown_registers(Duration::from_secs(3600)).unwrap(),
+            (0b10, [0x10, 0x0e, 0x00])
+        );
+        // rounded up
+        assert_eq!(
+            countdown_registers(Duration::from_millis(1500)).unwrap(),
+            (0b10, [2, 0, 0])
+        );
+        assert_eq!(
+            countdown_registers(Duration::from_secs(0xff_ffff)).unwrap(),
+            (0b10, [0xff, 0xff, 0xff])
+        );
+        // minutes beyond 24-bit seconds
+        assert_eq!(
+            countdown_registers(Duration::from_secs(0x100_0000)).unwrap(),
+            (0b11, [0x45, 0x44, 0x04])
+        );
+        assert!(countdown_registers(Duration::from_secs(0)).is_err());
+        assert!(countdown_registers(Duration::from_secs(0x100_0000 * 60)).is_err());
+
+        for secs in [1, 90, 3600, 86400 * 7].iter() {
+            let (tds, count) = countdown_registers(Duration::from_secs(*secs)).unwrap();
+            assert_eq!(countdown_duration(tds, count), Duration::from_secs(*secs));
+        }
+        assert_eq!(countdown_duration(0b00, [0x00, 0x10, 0x00]), Duration::from_secs(1));
+        assert_eq!(countdown_duration(0b01, [0x00, 0x04, 0x00]), Duration::from_secs(1));
+        assert_eq!(countdown_duration(0b11, [2, 0, 0]), Duration::from_secs(120));
+    }
+
+    #[cfg(feature = "mock-hardware")]
+    #[test]
+    fn test_countdown_wake() {
+        use super::SD3078;
+        use crate::PiSugarConfig;
+
+        let rtc = SD3078::mock(PiSugarConfig::default());
+        assert_eq!(rtc.read_countdown_remaining().unwrap(), None);
+
+        rtc.set_countdown_wake(Duration::from_secs(3600)).unwrap();
+        assert_eq!(rtc.i2c.smbus_read_byte(0x13).unwrap(), 0x10);
+        assert_eq!(rtc.i2c.smbus_read_byte(0x14).unwrap(), 0x0e);
+        assert_eq!(rtc.i2c.smbus_read_byte(0x15).unwrap(), 0x00);
+        assert_eq!(rtc.i2c.smbus_read_byte(0x11).unwrap() & 0b0011_0000, 0b0010_0000);
+        assert_eq!(rtc.i2c.smbus_read_byte(0x10).unwrap() & 0b0011_0111, 0b0011_0100);
+        assert_eq!(rtc.read_countdown_remaining().unwrap(), Some(Duration::from_secs(3600)));
+    }
+
+    #[test]
+    fn test_user_ram_reg() {
+        assert_eq!(user_ram_reg(0, 4).unwrap(), SD3078_USER_RAM_ADDR);
+        assert_eq!(user_ram_reg(10, 60).unwrap(), 0x36);
+        assert_eq!(user_ram_reg(0, SD3078_USER_RAM_SIZE).unwrap(), 0x2c);
+        assert_eq!(user_ram_reg(69, 1).unwrap(), 0x71);
+        assert!(user_ram_reg(69, 2).is_err());
+        assert!(user_ram_reg(usize::MAX, 1).is_err());
+    }
+
+    #[cfg(feature = "mock-hardware")]
+    #[test]
+    fn test_time_until_alarm() {
+        use std::time::Duration;
+
+        use chrono::{NaiveDate, TimeZone, Utc};
+
+        use super::SD3078;
+        use crate::rtc::{RTCRawTime, WeekdayMask, RTC};
+        use crate::PiSugarConfig;
+
+        let at = |h, m| {
+            Utc.from_utc_datetime(
+                &NaiveDate::from_ymd_opt(2026, 10, 16)
+                    .unwrap()
+                    .and_hms_opt(h, m, 0)
+                    .unwrap(),
+            )
+        };
+        let rtc = SD3078::mock(PiSugarConfig::default());
+        rtc.write_time(RTCRawTime::from(at(8, 0))).unwrap();
+        assert_eq!(rtc.time_until_alarm().unwrap(), None);
+
+        // every day at 18:30, later today
+        rtc.set_alarm(RTCRawTime::from(at(18, 30)), WeekdayMask::EVERYDAY.bits())
+            .unwrap();
+        assert_eq!(
+            rtc.time_until_alarm().unwrap(),
+            Some(Duration::from_secs(10 * 3600 + 30 * 60))
+        );
+
+        rtc.disable_alarm().unwrap();
+        assert_eq!(rtc.time_until_alarm().unwrap(), None);
+    }
+
+    #[cfg(feature = "mock-hardware")]
+    #[test]
+    fn test_reset_alarm_state() {
+        use chrono::{TimeZone, Utc};
+
+        use super::SD3078;
+        use crate::rtc::{RTCRawTime, WeekdayMask, RTC};
+        use crate::PiSugarConfig;
+
+        let rtc = SD3078::mock(PiSugarConfig::default());
+        rtc.set_alarm(
+            RTCRawTime::from(Utc.with_ymd_and_hms(2026, 10, 16, 18, 30, 0).unwrap()),
+            WeekdayMask::EVERYDAY.bits(),
+        )
+        .unwrap();
+        // alarm fired
+        rtc.i2c.smbus_write_byte(0x0f, 0b0011_0000).unwrap();
+        assert!(rtc.is_alarm_enable().unwrap());
+        assert!(rtc.read_alarm_flag().unwrap());
+
+        rtc.reset_alarm_state().unwrap();
+        assert!(!rtc.is_alarm_enable().unwrap());
+        assert!(!rtc.read_alarm_flag().unwrap());
+        assert_eq!(rtc.i2c.smbus_read_byte(0x0e).unwrap(), 0);
+        assert_eq!(rtc.i2c.smbus_read_byte(0x10).unwrap() & 0b0010_0011, 0);
+
+        // write protected again
+        assert_eq!(rtc.i2c.smbus_read_byte(0x0f).unwrap() & 0b1000_0100, 0);
+        assert_eq!(rtc.i2c.smbus_read_byte(0x10).unwrap() & 0b1000_0000, 0);
+    }
+}
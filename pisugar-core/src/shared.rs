@@ -0,0 +1,170 @@
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use crate::{fetch_network_time, ConfigPatch, Event, PiSugarConfig, PiSugarCore, Result, StatusSnapshot};
+
+/// PiSugar core shared between threads, every method locks the core once and releases it on return
+///
+/// Lock ordering: the core lock is the only lock taken here, so never call `SharedCore` methods
+/// inside `with` (the lock is not reentrant), and release other locks before calling into it, e.g.
+/// the poll loop should not hold its own state lock while `poll_once` waits for the core.
+#[derive(Clone)]
+pub struct SharedCore {
+    core: Arc<Mutex<PiSugarCore>>,
+}
+
+impl SharedCore {
+    /// Share core
+    pub fn new(core: PiSugarCore) -> Self {
+        Self {
+            core: Arc::new(Mutex::new(core)),
+        }
+    }
+
+    /// Lock core, a panicked holder doesn't poison it for others
+    pub fn lock(&self) -> MutexGuard<'_, PiSugarCore> {
+        self.core.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Run `f` with the core locked
+    pub fn with<F, R>(&self, f: F) -> R
+    where
+        F: FnOnce(&mut PiSugarCore) -> R,
+    {
+        f(&mut self.lock())
+    }
+
+    /// Status snapshot
+    pub fn snapshot(&self) -> Result<StatusSnapshot> {
+        self.lock().snapshot()
+    }
+
+    /// Copy of current config
+    pub fn config(&self) -> PiSugarConfig {
+        self.lock().config().clone()
+    }
+
+    /// Update some config fields, see `PiSugarCore::update_config`
+    pub fn update_config(&self, patch: ConfigPatch) -> Result<()> {
+        self.lock().update_config(patch)
+    }
+
+    /// Poll once, the core is unlocked while the network time is fetched
+    pub async fn poll_once(&self) -> Result<Vec<Event>> {
+        let (events, rtc_sync) = {
+            let mut core = self.lock();
+            let now = core.now();
+            let events = core.poll_hardware(now)?;
+            (events, core.take_rtc_sync())
+        };
+        if rtc_sync {
+            if let Some(dt) = fetch_network_time().await {
+                self.lock().sync_network_time(dt);
+            }
+        }
+        Ok(events)
+    }
+}
+
+impl From<Arc<Mutex<PiSugarCore>>> for SharedCore {
+    fn from(core: Arc<Mutex<PiSugarCore>>) -> Self {
+        Self { core }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::SharedCore;
+    use crate::{ConfigPatch, Model, PiSugarConfig, PiSugarCore};
+
+    #[test]
+    fn test_shared_core() {
+        let mut core = PiSugarCore::new(PiSugarConfig::default(), Model::PiSugar_3).unwrap();
+        let path = std::env::temp_dir().join(format!("pisugar-shared-core-{}.json", std::process::id()));
+        core.config_path = Some(path.to_string_lossy().to_string());
+        let shared = SharedCore::new(core);
+
+        let readers: Vec<_> = (0..4)
+            .map(|_| {
+                let shared = shared.clone();
+                thread::spawn(move || {
+                    for _ in 0..50 {
+                        // no hardware in tests, snapshot fails without blocking
+                        let _ = shared.snapshot();
+                        let level = shared.config().auto_shutdown_level;
+                        assert!(level.is_none() || level == Some(15.0));
+                    }
+                })
+            })
+            .collect();
+
+        let patch = ConfigPatch {
+            auto_shutdown_level: Some(Some(15.0)),
+            ..Default::default()
+        };
+        shared.update_config(patch).unwrap();
+        for reader in readers {
+            reader.join().unwrap();
+        }
+        assert_eq!(shared.config().auto_shutdown_level, Some(15.0));
+        assert_eq!(shared.with(|core| core.config().auto_shutdown_level), Some(15.0));
+
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[cfg(feature = "mock-hardware")]
+    #[tokio::test]
+    async fn test_poll_once() {
+        use std::time::Duration;
+
+        use crate::i2c::I2c;
+
+        let config = PiSugarConfig {
+            i2c_bus: 12,
+            ..Default::default()
+        };
+        I2c::plug_bus(12);
+        let mut chip = I2c::with_bus(12).unwrap();
+        chip.set_slave_address(crate::I2C_ADDR_BAT).unwrap();
+        // 3000 voltage counts
+        chip.smbus_write_byte(0xa2, 0xb8).unwrap();
+        chip.smbus_write_byte(0xa3, 0x0b).unwrap();
+        let shared = SharedCore::new(PiSugarCore::new(config, Model::PiSugar_2_4LEDs).unwrap());
+
+        let readers: Vec<_> = (0..4)
+            .map(|_| {
+                let shared = shared.clone();
+                thread::spawn(move || {
+                    for _ in 0..50 {
+                        assert!(shared.snapshot().unwrap().voltage > 3.0);
+                    }
+                })
+            })
+            .collect();
+        // the poll future is Send, i.e. no core lock is held across an await
+        let poller = tokio::spawn({
+            let shared = shared.clone();
+            async move {
+                for _ in 0..50 {
+                    shared.poll_once().await.unwrap();
+                }
+            }
+        });
+        poller.await.unwrap();
+        for reader in readers {
+            reader.join().unwrap();
+        }
+
+        // network time sync is left to the caller of poll_hardware, once per due time
+        shared.with(|core| {
+            core.config_mut().auto_rtc_sync = Some(true);
+            let now = core.now() + Duration::from_secs(10);
+            core.poll_hardware(now).unwrap();
+            assert!(core.take_rtc_sync());
+            assert!(!core.take_rtc_sync());
+            core.poll_hardware(now).unwrap();
+            assert!(!core.take_rtc_sync());
+        });
+    }
+}
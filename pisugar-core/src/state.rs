@@ -0,0 +1,97 @@
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::boot_history::BootHistory;
+
+/// State file name, next to the config file
+pub const STATE_FILE: &str = "state.json";
+
+/// Runtime state kept by the server across restarts, separate from the user config
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CoreState {
+    /// Auto power on disarmed on request, until battery recovered or power restored
+    pub auto_power_on_disarmed: bool,
+
+    /// Last boot and rapid boots in a row
+    pub boot_history: BootHistory,
+
+    /// Estimated battery cycles, charge drawn from the battery in units of battery capacity
+    pub battery_cycles: f64,
+
+    /// Battery shutdown registers after the last init, (register, value)
+    pub shutdown_config: Option<Vec<(u8, u8)>>,
+}
+
+impl CoreState {
+    /// State file of a config file
+    pub fn path_for(config_path: &Path) -> PathBuf {
+        config_path.with_file_name(STATE_FILE)
+    }
+
+    /// Load state, default if missing or invalid
+    pub fn load(path: &Path) -> Self {
+        match fs::read_to_string(path) {
+            Ok(s) => serde_json::from_str(&s).unwrap_or_else(|e| {
+                log::warn!("Invalid state file {}, reset: {}", path.display(), e);
+                Self::default()
+            }),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Self::default(),
+            Err(e) => {
+                log::warn!("Could not read state file {}: {}", path.display(), e);
+                Self::default()
+            }
+        }
+    }
+
+    /// Write to a tmp file and rename it, a power cut leaves either the old or the new state
+    pub fn save_to(&self, path: &Path) -> io::Result<()> {
+        let tmp = path.with_extension("json.tmp");
+        {
+            let mut f = File::create(&tmp)?;
+            f.write_all(serde_json::to_string_pretty(self)?.as_bytes())?;
+            f.sync_all()?;
+        }
+        fs::rename(&tmp, path)?;
+        if let Some(dir) = path.parent() {
+            // make the rename durable
+            File::open(dir)?.sync_all()?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::{CoreState, STATE_FILE};
+
+    #[test]
+    fn test_core_state() {
+        let dir = std::env::temp_dir().join(format!("pisugar-state-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = CoreState::path_for(&dir.join("config.json"));
+        assert_eq!(path, dir.join(STATE_FILE));
+
+        // missing
+        assert_eq!(CoreState::load(&path), CoreState::default());
+
+        let state = CoreState {
+            auto_power_on_disarmed: true,
+            ..Default::default()
+        };
+        state.save_to(&path).unwrap();
+        assert_eq!(CoreState::load(&path), state);
+        assert!(!path.with_extension("json.tmp").exists());
+
+        // truncated by an older non-atomic writer
+        fs::write(&path, "{\"auto_power").unwrap();
+        assert_eq!(CoreState::load(&path), CoreState::default());
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
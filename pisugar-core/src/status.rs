@@ -0,0 +1,384 @@
+use std::fmt::{self, Display, Formatter};
+
+use chrono::{DateTime, Local};
+use serde::Serialize;
+
+use crate::Model;
+
+/// Core version and detected hardware
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct CoreInfo {
+    /// pisugar-core version
+    pub version: String,
+    /// PiSugar model
+    pub model: String,
+    /// Battery chip i2c address
+    pub battery_i2c_addr: u16,
+    /// RTC chip i2c address
+    pub rtc_i2c_addr: u16,
+}
+
+impl Display for CoreInfo {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} | {} | bat 0x{:02x} rtc 0x{:02x}",
+            self.version, self.model, self.battery_i2c_addr, self.rtc_i2c_addr
+        )
+    }
+}
+
+/// Board hardware, chips and their i2c addresses
+#[derive(Debug, Copy, Clone, PartialEq, Serialize)]
+pub struct Board {
+    /// PiSugar model
+    pub model: Model,
+    /// Battery chip
+    pub battery_chip: &'static str,
+    /// RTC chip
+    pub rtc_chip: &'static str,
+    /// Battery chip i2c address
+    pub battery_i2c_addr: u16,
+    /// RTC chip i2c address
+    pub rtc_i2c_addr: u16,
+}
+
+impl Display for Board {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} | {} 0x{:02x} | {} 0x{:02x}",
+            self.model, self.battery_chip, self.battery_i2c_addr, self.rtc_chip, self.rtc_i2c_addr
+        )
+    }
+}
+
+/// Seconds without a successful poll before PiSugar is unhealthy
+pub const HEALTH_POLL_MAX_AGE_SECS: u64 = 30;
+
+/// Overall health
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HealthStatus {
+    Healthy,
+    /// RTC or config failed, battery still works
+    Degraded,
+    /// Battery unreadable or polling stalled
+    Unhealthy,
+}
+
+impl Display for HealthStatus {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            HealthStatus::Healthy => "healthy",
+            HealthStatus::Degraded => "degraded",
+            HealthStatus::Unhealthy => "unhealthy",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+/// Subsystem health
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Health {
+    /// Battery chip read ok
+    pub battery_ok: bool,
+    /// RTC read ok
+    pub rtc_ok: bool,
+    /// Seconds since last successful poll
+    pub poll_age_secs: u64,
+    /// Config file loaded
+    pub config_loaded: bool,
+    /// Overall health
+    pub status: HealthStatus,
+}
+
+impl Health {
+    pub fn new(battery_ok: bool, rtc_ok: bool, poll_age_secs: u64, config_loaded: bool) -> Self {
+        let status = if !battery_ok || poll_age_secs > HEALTH_POLL_MAX_AGE_SECS {
+            HealthStatus::Unhealthy
+        } else if !rtc_ok || !config_loaded {
+            HealthStatus::Degraded
+        } else {
+            HealthStatus::Healthy
+        };
+        Self {
+            battery_ok,
+            rtc_ok,
+            poll_age_secs,
+            config_loaded,
+            status,
+        }
+    }
+}
+
+impl Display for Health {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let ok = |b: bool| if b { "ok" } else { "fail" };
+        write!(
+            f,
+            "{} | battery {} | rtc {} | config {} | poll {}s ago",
+            self.status,
+            ok(self.battery_ok),
+            ok(self.rtc_ok),
+            ok(self.config_loaded),
+            self.poll_age_secs
+        )
+    }
+}
+
+/// Shutdown in progress
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ShutdownState {
+    None,
+    /// Graceful poweroff issued, waiting for the system to go down
+    GracefulPending,
+    /// Power cut by the chips, e.g. critical battery or stalled graceful poweroff
+    Forcing,
+}
+
+impl Display for ShutdownState {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            ShutdownState::None => "none",
+            ShutdownState::GracefulPending => "graceful_pending",
+            ShutdownState::Forcing => "forcing",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+/// PiSugar status snapshot
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct StatusSnapshot {
+    /// PiSugar model
+    pub model: Model,
+    /// Battery voltage (V)
+    pub voltage: f32,
+    /// Battery current intensity (A)
+    pub intensity: f32,
+    /// Battery level (%)
+    pub level: f32,
+    /// Is battery charging
+    pub charging: bool,
+    /// External power present, if the chip has an input-present bit
+    pub power_plugged: Option<bool>,
+    /// Shutdown in progress
+    pub shutdown_state: ShutdownState,
+    /// RTC time
+    pub rtc_time: Option<DateTime<Local>>,
+    /// Seconds since last successful battery read
+    pub data_age_secs: u64,
+}
+
+/// Level quantum (%), smaller level changes are noise
+pub const LEVEL_QUANTUM: f32 = 1.0;
+
+impl StatusSnapshot {
+    /// Snapshot of an idle 3.9V battery at `level`, optional fields unset
+    #[cfg(test)]
+    pub(crate) fn for_test(model: Model, level: f32) -> Self {
+        StatusSnapshot {
+            model,
+            voltage: 3.9,
+            intensity: 0.0,
+            level,
+            charging: false,
+            power_plugged: None,
+            shutdown_state: ShutdownState::None,
+            rtc_time: None,
+            data_age_secs: 0,
+        }
+    }
+
+    /// Quantized level
+    fn level_quantized(&self) -> i32 {
+        (self.level / LEVEL_QUANTUM).round() as i32
+    }
+
+    /// RTC time in minutes
+    fn rtc_minute(&self) -> Option<i64> {
+        self.rtc_time.map(|t| t.timestamp() / 60)
+    }
+
+    /// Level rounded to the nearest `step` (%), clamped to 0-100, step 0 means 1%
+    pub fn level_stepped(&self, step: u8) -> u8 {
+        let level = self.level.clamp(0.0, 100.0);
+        if step == 0 {
+            return level.round() as u8;
+        }
+        let step = step as f32;
+        ((level / step).round() * step).min(100.0) as u8
+    }
+
+    /// Stepped level with hysteresis, keeps `last` displayed bucket until the level is `margin` (%) past its edge
+    pub fn level_stepped_hysteretic(&self, step: u8, margin: f32, last: Option<u8>) -> u8 {
+        let stepped = self.level_stepped(step);
+        let last = match last {
+            Some(last) if last != stepped => last,
+            _ => return stepped,
+        };
+        let half = step.max(1) as f32 / 2.0;
+        let level = self.level.clamp(0.0, 100.0);
+        if level > last as f32 + half + margin || level < last as f32 - half - margin {
+            stepped
+        } else {
+            last
+        }
+    }
+
+    /// Changed since previous snapshot, compares quantized level, charging and rtc minute
+    pub fn changed_since(&self, prev: &StatusSnapshot) -> bool {
+        self.level_quantized() != prev.level_quantized()
+            || self.charging != prev.charging
+            || self.rtc_minute() != prev.rtc_minute()
+    }
+}
+
+impl Display for StatusSnapshot {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} | {:.2}V {:.2}A | {:.0}% | {}",
+            self.model,
+            self.voltage,
+            self.intensity,
+            self.level,
+            if self.charging { "charging" } else { "discharging" }
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{Local, TimeZone};
+
+    use super::{Health, HealthStatus, StatusSnapshot, HEALTH_POLL_MAX_AGE_SECS};
+    use crate::Model;
+
+    #[test]
+    fn test_display() {
+        let status = StatusSnapshot {
+            voltage: 3.92,
+            intensity: 0.15,
+            charging: true,
+            ..StatusSnapshot::for_test(Model::PiSugar_2_Pro, 78.2)
+        };
+        assert_eq!(status.to_string(), "PiSugar 2 Pro | 3.92V 0.15A | 78% | charging");
+    }
+
+    #[test]
+    fn test_changed_since() {
+        let prev = StatusSnapshot {
+            voltage: 3.92,
+            intensity: -0.3,
+            rtc_time: Local.timestamp_opt(1651408200, 0).single(),
+            ..StatusSnapshot::for_test(Model::PiSugar_3, 78.1)
+        };
+        let status = StatusSnapshot {
+            voltage: 3.91,
+            level: 78.3,
+            rtc_time: Local.timestamp_opt(1651408240, 0).single(),
+            ..prev.clone()
+        };
+        assert!(!status.changed_since(&prev));
+
+        let status = StatusSnapshot {
+            level: 77.2,
+            ..prev.clone()
+        };
+        assert!(status.changed_since(&prev));
+
+        let status = StatusSnapshot {
+            charging: true,
+            ..prev.clone()
+        };
+        assert!(status.changed_since(&prev));
+
+        let status = StatusSnapshot {
+            rtc_time: Local.timestamp_opt(1651408260, 0).single(),
+            ..prev.clone()
+        };
+        assert!(status.changed_since(&prev));
+    }
+
+    #[test]
+    fn test_level_stepped() {
+        let mut status = StatusSnapshot::for_test(Model::PiSugar_3, 0.0);
+        let cases = [
+            (0.0, 5, 0),
+            (2.4, 5, 0),
+            (2.6, 5, 5),
+            (77.4, 5, 75),
+            (77.6, 10, 80),
+            (99.0, 10, 100),
+            (97.0, 30, 90),
+            (105.0, 10, 100),
+            (-3.0, 10, 0),
+            (42.6, 0, 43),
+        ];
+        for (level, step, expected) in cases.iter() {
+            status.level = *level;
+            assert_eq!(status.level_stepped(*step), *expected, "{} {}", level, step);
+        }
+    }
+
+    #[test]
+    fn test_level_stepped_hysteretic() {
+        let mut status = StatusSnapshot::for_test(Model::PiSugar_3, 76.0);
+        let mut shown = status.level_stepped_hysteretic(10, 1.0, None);
+        assert_eq!(shown, 80);
+
+        // hovering around the 75% edge of 70/80
+        for level in [74.8, 75.3, 74.2, 75.9, 74.5].iter() {
+            status.level = *level;
+            shown = status.level_stepped_hysteretic(10, 1.0, Some(shown));
+            assert_eq!(shown, 80, "{}", level);
+        }
+
+        // past the margin
+        status.level = 73.9;
+        shown = status.level_stepped_hysteretic(10, 1.0, Some(shown));
+        assert_eq!(shown, 70);
+        status.level = 75.5;
+        assert_eq!(status.level_stepped_hysteretic(10, 1.0, Some(shown)), 70);
+        status.level = 76.1;
+        assert_eq!(status.level_stepped_hysteretic(10, 1.0, Some(shown)), 80);
+    }
+
+    #[test]
+    fn test_health() {
+        let health = Health::new(true, true, 1, true);
+        assert_eq!(health.status, HealthStatus::Healthy);
+        assert_eq!(
+            health.to_string(),
+            "healthy | battery ok | rtc ok | config ok | poll 1s ago"
+        );
+
+        assert_eq!(Health::new(true, false, 1, true).status, HealthStatus::Degraded);
+        assert_eq!(Health::new(true, true, 1, false).status, HealthStatus::Degraded);
+
+        assert_eq!(Health::new(false, true, 1, true).status, HealthStatus::Unhealthy);
+        assert_eq!(
+            Health::new(true, true, HEALTH_POLL_MAX_AGE_SECS + 1, true).status,
+            HealthStatus::Unhealthy
+        );
+    }
+
+    #[test]
+    fn test_snapshot_json() {
+        let status = StatusSnapshot {
+            voltage: 4.0,
+            intensity: -0.5,
+            data_age_secs: 2,
+            ..StatusSnapshot::for_test(Model::PiSugar_3, 88.0)
+        };
+        let value: serde_json::Value = serde_json::from_str(&serde_json::to_string(&status).unwrap()).unwrap();
+        assert_eq!(value["model"], "PiSugar 3");
+        assert_eq!(value["level"], 88.0);
+        assert_eq!(value["charging"], false);
+        assert_eq!(value["power_plugged"], serde_json::Value::Null);
+        assert_eq!(value["data_age_secs"], 2);
+    }
+}
@@ -0,0 +1,135 @@
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
+
+use crate::{Error, Result};
+
+/// Max pending wake alarms
+pub const WAKE_QUEUE_CAPACITY: usize = 16;
+
+/// One-off wake time must be after `now` (rtc time), a passed one would wake on its weekday next week
+pub fn check_wake_time(at: DateTime<Local>, now: DateTime<Local>) -> Result<()> {
+    if at <= now {
+        return Err(Error::Other(format!(
+            "Wake time {} is not in the future (rtc time {})",
+            at.to_rfc3339(),
+            now.to_rfc3339()
+        )));
+    }
+    Ok(())
+}
+
+/// Pending wake alarms, sorted, the soonest one is armed on the single rtc alarm
+///
+/// NOTE: rtc alarm matches weekday and time, so an entry more than a week ahead wakes on the same weekday before it,
+/// it is re-armed after that wake
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(from = "Vec<DateTime<Local>>", into = "Vec<DateTime<Local>>")]
+pub struct WakeQueue {
+    alarms: Vec<DateTime<Local>>,
+}
+
+impl WakeQueue {
+    /// Add a wake alarm, an existing one is kept once
+    pub fn add(&mut self, at: DateTime<Local>) -> Result<()> {
+        if let Err(i) = self.alarms.binary_search(&at) {
+            if self.alarms.len() >= WAKE_QUEUE_CAPACITY {
+                return Err(Error::Other(format!("Wake queue is full ({})", WAKE_QUEUE_CAPACITY)));
+            }
+            self.alarms.insert(i, at);
+        }
+        Ok(())
+    }
+
+    /// Remove a wake alarm, false if not found
+    pub fn remove(&mut self, at: DateTime<Local>) -> bool {
+        match self.alarms.binary_search(&at) {
+            Ok(i) => {
+                self.alarms.remove(i);
+                true
+            }
+            Err(_) => false,
+        }
+    }
+
+    /// Drop alarms fired at or before `now`, returns how many
+    pub fn pop_fired(&mut self, now: DateTime<Local>) -> usize {
+        let fired = self.alarms.iter().take_while(|at| **at <= now).count();
+        self.alarms.drain(..fired);
+        fired
+    }
+
+    /// Soonest alarm
+    pub fn next(&self) -> Option<DateTime<Local>> {
+        self.alarms.first().copied()
+    }
+
+    /// Pending alarms, soonest first
+    pub fn alarms(&self) -> &[DateTime<Local>] {
+        &self.alarms
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.alarms.is_empty()
+    }
+}
+
+/// Sorted and truncated to capacity
+impl From<Vec<DateTime<Local>>> for WakeQueue {
+    fn from(mut alarms: Vec<DateTime<Local>>) -> Self {
+        alarms.sort();
+        alarms.dedup();
+        alarms.truncate(WAKE_QUEUE_CAPACITY);
+        Self { alarms }
+    }
+}
+
+impl From<WakeQueue> for Vec<DateTime<Local>> {
+    fn from(queue: WakeQueue) -> Self {
+        queue.alarms
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{Local, NaiveDate, TimeZone};
+
+    use super::{check_wake_time, WakeQueue, WAKE_QUEUE_CAPACITY};
+
+    #[test]
+    fn test_wake_queue() {
+        let at = |d, h| {
+            let t = NaiveDate::from_ymd_opt(2026, 10, d)
+                .unwrap()
+                .and_hms_opt(h, 0, 0)
+                .unwrap();
+            Local.from_local_datetime(&t).unwrap()
+        };
+        let mut queue = WakeQueue::default();
+        queue.add(at(2, 18)).unwrap();
+        queue.add(at(2, 6)).unwrap();
+        queue.add(at(1, 18)).unwrap();
+        queue.add(at(2, 6)).unwrap();
+        assert_eq!(queue.alarms(), &[at(1, 18), at(2, 6), at(2, 18)]);
+
+        // woken at 06:00, 18:00 is next
+        assert_eq!(queue.pop_fired(at(2, 6) + chrono::Duration::seconds(5)), 2);
+        assert_eq!(queue.next(), Some(at(2, 18)));
+
+        assert!(queue.remove(at(2, 18)));
+        assert!(!queue.remove(at(2, 18)));
+        assert!(queue.is_empty());
+
+        for h in 0..WAKE_QUEUE_CAPACITY as u32 {
+            queue.add(at(3, h)).unwrap();
+        }
+        assert!(queue.add(at(4, 0)).is_err());
+
+        assert!(check_wake_time(at(2, 6), at(2, 7)).is_err());
+        assert!(check_wake_time(at(2, 6), at(2, 6)).is_err());
+        assert!(check_wake_time(at(2, 7), at(2, 6)).is_ok());
+
+        let json = serde_json::to_string(&WakeQueue::from(vec![at(2, 18), at(2, 6)])).unwrap();
+        let queue: WakeQueue = serde_json::from_str(&json).unwrap();
+        assert_eq!(queue.alarms(), &[at(2, 6), at(2, 18)]);
+    }
+}
@@ -8,7 +8,7 @@ use std::path::Path;
 use std::process::exit;
 use std::sync::{Arc, Mutex};
 use std::thread::sleep;
-use std::time::{Instant, SystemTime};
+use std::time::SystemTime;
 
 use anyhow::{anyhow, bail, Result};
 use chrono::prelude::*;
@@ -34,10 +34,13 @@ use tokio::time::Duration;
 use tokio_util::codec::{BytesCodec, Framed};
 
 use pisugar_core::{
-    execute_shell, notify_shutdown_soon, sys_write_time, Error, Model, PiSugarConfig, PiSugarCore, RTCRawTime,
-    I2C_READ_INTERVAL, TIME_HOST,
+    execute_shell, notify_shutdown_soon, sys_write_time, Error, HealthStatus, LogRateLimiter, Model, PiSugarConfig,
+    PiSugarCore, RTCRawTime, WeekdayMask, I2C_READ_INTERVAL, TIME_HOST,
 };
 
+#[cfg(feature = "http")]
+mod rest;
+
 /// Websocket info
 const WS_JSON: &str = "_ws.json";
 
@@ -46,24 +49,33 @@ lazy_static! {
     static ref WS_ADDR: Mutex<Option<SocketAddr>> = Mutex::new(None);
 }
 
-/// Tap event tx
+/// Event tx
 type EventTx = tokio::sync::watch::Sender<String>;
 
-/// Tap event rx
+/// Event rx
 type EventRx = tokio::sync::watch::Receiver<String>;
 
 /// Poll pisugar status
-async fn poll_pisugar_status(core: &mut PiSugarCore, tx: &EventTx) {
+async fn poll_pisugar_status(core: &mut PiSugarCore, tx: &EventTx, poll_errors: &mut LogRateLimiter) {
     log::debug!("Polling state");
-    let now = Instant::now();
+    let now = core.now();
     match core.poll(now).await {
-        Ok(Some(tap_type)) => {
-            let _ = tx.send(format!("{}\n", tap_type));
+        Ok(events) => {
+            poll_errors.clear();
+            for event in events {
+                #[cfg(feature = "journald")]
+                if let Err(e) = pisugar_core::journald::send_event(&event) {
+                    log::debug!("Journald error: {}", e);
+                }
+                let _ = tx.send(format!("{}\n", event));
+            }
         }
         Err(e) => {
-            log::debug!("Poll error: {}", e);
+            // disconnected board fails every poll, don't flood logs
+            if let Some(msg) = poll_errors.check(now, &e.to_string()) {
+                log::warn!("Poll error: {}", msg);
+            }
         }
-        _ => {}
     }
 }
 
@@ -85,12 +97,32 @@ fn handle_request(core: Arc<Mutex<PiSugarCore>>, req: &str) -> String {
                         let resp = match parts[1].as_str() {
                             "version" => Ok(env!("CARGO_PKG_VERSION").to_string()),
                             "model" => Ok(core.model()),
+                            "info" => Ok(core.info().to_string()),
+                            "board" => Ok(core.board().to_string()),
+                            "shutdown_state" => Ok(core.shutdown_state().to_string()),
+                            "polling_paused" => Ok(core.is_paused().to_string()),
+                            "tap_history" => Ok(core.tap_history().to_string()),
+                            "diagnostics" => Ok(core.diagnostic_bundle().to_string()),
                             "firmware_version" => core.version(),
                             "battery" => core.level().map(|l| l.to_string()),
+                            "battery_compensated" => core.level_load_compensated().map(|l| l.to_string()),
                             "battery_v" => core.voltage_avg().map(|v| v.to_string()),
                             "battery_i" => core.intensity_avg().map(|i| i.to_string()),
+                            "battery_r" => core
+                                .estimated_internal_resistance()
+                                .map(|r| r.to_string())
+                                .ok_or_else(|| Error::Other("Not available".to_string())),
+                            "battery_standby_days" => core
+                                .standby_days_remaining(None)
+                                .map(|d| d.to_string())
+                                .ok_or_else(|| Error::Other("Not available".to_string())),
+                            "battery_cycles" => Ok(core.estimated_cycles().to_string()),
+                            "battery_curve_segment" => core
+                                .curve_segment()
+                                .map(|s| s.map_or_else(|| "none".to_string(), |s| s.to_string())),
                             "battery_led_amount" => core.led_amount().map(|n| n.to_string()),
                             "battery_power_plugged" => core.power_plugged().map(|p| p.to_string()),
+                            "battery_external_power" => core.external_power_present().map(|p| p.to_string()),
                             "battery_allow_charging" => core.allow_charging().map(|a| a.to_string()),
                             "battery_charging_range" => core
                                 .charging_range()
@@ -102,19 +134,28 @@ fn handle_request(core: Arc<Mutex<PiSugarCore>>, req: &str) -> String {
                                 .config()
                                 .full_charge_duration
                                 .map_or("".to_string(), |d| d.to_string())),
-                            "system_time" => Ok(Local::now().to_rfc3339_opts(SecondsFormat::Millis, false)),
+                            "system_time" => Ok(core.now_local().to_rfc3339_opts(SecondsFormat::Millis, false)),
                             "rtc_time" => core
                                 .read_time()
                                 .map(|t| t.to_rfc3339_opts(SecondsFormat::Millis, false)),
                             "rtc_time_list" => core.read_raw_time().map(|r| r.to_string()),
                             "rtc_alarm_flag" => core.read_alarm_flag().map(|f| f.to_string()),
+                            "boot_reason" => Ok(core.boot_reason().to_string()),
+                            "data_age" => Ok(core.age(core.now()).as_secs().to_string()),
+                            "wake_queue" => Ok(core
+                                .config()
+                                .wake_queue
+                                .alarms()
+                                .iter()
+                                .map(|t| t.to_rfc3339_opts(SecondsFormat::Secs, false))
+                                .collect::<Vec<_>>()
+                                .join(",")),
                             "rtc_alarm_time" => {
                                 let t = core
                                     .read_alarm_time()
                                     .and_then(|r| r.try_into().map_err(|_| Error::Other("Invalid".to_string())));
                                 t.map(|t: DateTime<Utc>| {
-                                    t.with_timezone(Local::now().offset())
-                                        .to_rfc3339_opts(SecondsFormat::Millis, false)
+                                    t.with_timezone(&Local).to_rfc3339_opts(SecondsFormat::Millis, false)
                                 })
                             }
                             "rtc_alarm_time_list" => core.read_alarm_time().map(|r| r.to_string()),
@@ -245,8 +286,17 @@ fn handle_request(core: Arc<Mutex<PiSugarCore>>, req: &str) -> String {
                         }
                     };
                 }
+                "refresh" => {
+                    return match core.refresh_now() {
+                        Ok(_) => format!("{}: done\n", parts[0]),
+                        Err(e) => {
+                            log::error!("{}", e);
+                            err
+                        }
+                    };
+                }
                 "rtc_pi2rtc" => {
-                    let now = Local::now();
+                    let now = core.now_local();
                     return match core.write_time(now) {
                         Ok(_) => format!("{}: done\n", parts[0]),
                         Err(e) => {
@@ -291,8 +341,15 @@ fn handle_request(core: Arc<Mutex<PiSugarCore>>, req: &str) -> String {
                     if parts.len() >= 3 {
                         if let Ok(datetime) = parts[1].parse::<DateTime<FixedOffset>>() {
                             let datetime: DateTime<Local> = datetime.into();
-                            let sd3078_time: RTCRawTime = datetime.into();
-                            if let Ok(weekday_repeat) = parts[2].parse::<u8>() {
+                            let sd3078_time = match RTCRawTime::checked_from(datetime) {
+                                Ok(t) => t,
+                                Err(e) => {
+                                    log::error!("{}", e);
+                                    return err;
+                                }
+                            };
+                            if let Ok(weekday_repeat) = parts[2].parse::<WeekdayMask>() {
+                                let weekday_repeat = weekday_repeat.bits();
                                 match core.write_alarm(sd3078_time, weekday_repeat) {
                                     Ok(_) => {
                                         core.config_mut().auto_wake_repeat = weekday_repeat;
@@ -309,6 +366,27 @@ fn handle_request(core: Arc<Mutex<PiSugarCore>>, req: &str) -> String {
                     }
                     return err;
                 }
+                "wake_queue_add" | "wake_queue_remove" => {
+                    // wake_queue_add <iso8601>
+                    if parts.len() >= 2 {
+                        if let Ok(datetime) = parts[1].parse::<DateTime<FixedOffset>>() {
+                            let datetime: DateTime<Local> = datetime.into();
+                            let r = if parts[0] == "wake_queue_add" {
+                                core.add_wake_alarm(datetime)
+                            } else {
+                                core.remove_wake_alarm(datetime).map(|_| ())
+                            };
+                            return match r {
+                                Ok(_) => format!("{}: done\n", parts[0]),
+                                Err(e) => {
+                                    log::error!("{}", e);
+                                    err
+                                }
+                            };
+                        }
+                    }
+                    return err;
+                }
                 "rtc_alarm_disable" => {
                     return match core.disable_alarm() {
                         Ok(_) => {
@@ -445,6 +523,30 @@ fn handle_request(core: Arc<Mutex<PiSugarCore>>, req: &str) -> String {
                     }
                     return format!("{}: done\n", parts[0]);
                 }
+                "schedule_shutdown" => {
+                    if parts.len() > 1 {
+                        if let Some(secs) = parts[1].parse::<u64>().ok().and_then(|m| m.checked_mul(60)) {
+                            match core.schedule_shutdown(Duration::from_secs(secs)) {
+                                Ok(_) => return format!("{}: done\n", parts[0]),
+                                Err(e) => log::error!("{}", e),
+                            }
+                        }
+                    }
+                    return err;
+                }
+                "disarm_auto_power_on" => {
+                    return match core.disarm_auto_power_on() {
+                        Ok(_) => format!("{}: done\n", parts[0]),
+                        Err(e) => {
+                            log::error!("{}", e);
+                            err
+                        }
+                    };
+                }
+                "cancel_scheduled_shutdown" => {
+                    core.cancel_scheduled_shutdown();
+                    return format!("{}: done\n", parts[0]);
+                }
                 "force_shutdown" => {
                     match core.force_shutdown() {
                         Ok(_) => {
@@ -456,6 +558,15 @@ fn handle_request(core: Arc<Mutex<PiSugarCore>>, req: &str) -> String {
                     }
                     return err;
                 }
+                "set_polling_paused" => {
+                    if parts.len() > 1 {
+                        if let Ok(paused) = parts[1].parse::<bool>() {
+                            core.set_paused(paused);
+                            return format!("{}: done\n", parts[0]);
+                        }
+                    }
+                    return err;
+                }
                 "set_anti_mistouch" => {
                     if parts.len() > 1 {
                         if let Ok(anti_mistouch) = parts[1].parse::<bool>() {
@@ -920,6 +1031,11 @@ async fn handle_http_req(
             }
         }
     }
+    // rest api
+    #[cfg(feature = "http")]
+    if rest::is_api(req.uri().path()) {
+        return rest::handle_rest_req(req, core).await;
+    }
     // _ws.json
     if req.uri().path().contains(WS_JSON) {
         if let Some(ws_addr) = *WS_ADDR.lock().map_err(|e| anyhow!("Lock WS_ADDR error: {}", e))? {
@@ -931,6 +1047,18 @@ async fn handle_http_req(
             bail!("Not found");
         }
     }
+    // health
+    if req.uri().path() == "/health" {
+        let health = core.lock().map_err(|e| anyhow!("Lock core error: {}", e))?.health();
+        let status = match health.status {
+            HealthStatus::Unhealthy => hyper::StatusCode::SERVICE_UNAVAILABLE,
+            _ => hyper::StatusCode::OK,
+        };
+        return Ok(Response::builder()
+            .status(status)
+            .header("Content-Type", "application/json")
+            .body(Body::from(serde_json::to_string(&health)?))?);
+    }
     // websocket
     if req.uri().path().ends_with("/ws") {
         if hyper_tungstenite::is_upgrade_request(&req) {
@@ -1124,13 +1252,27 @@ async fn main() -> std::io::Result<()> {
         sleep(Duration::from_secs(3));
     }
 
+    // boot reason to journal
+    #[cfg(feature = "journald")]
+    {
+        let message = format!("PiSugar boot reason: {}", core.lock().unwrap().boot_reason());
+        let _ = pisugar_core::journald::send("wake", pisugar_core::journald::PRIORITY_INFO, &message);
+    }
+
+    // boot time, to detect flapping power
+    if let Err(e) = core.lock().unwrap().record_boot() {
+        log::warn!("Record boot error: {}", e);
+    }
+
     // event watch
     let (event_tx, event_rx) = tokio::sync::watch::channel("".to_string());
 
-    // CTRL+C signal handling
+    // CTRL+C and SIGTERM signal handling
     let uds = matches.value_of("uds").map(|x| x.to_string());
     let web_dir = matches.value_of("web").map(|x| x.to_string());
+    let core_cloned = core.clone();
     ctrlc::set_handler(move || {
+        core_cloned.lock().unwrap_or_else(|e| e.into_inner()).on_terminate();
         clean_up(uds.clone(), web_dir.clone());
     })
     .expect("Failed to setup ctrl+c");
@@ -1253,24 +1395,16 @@ async fn main() -> std::io::Result<()> {
     let mut interval = tokio::time::interval(I2C_READ_INTERVAL);
     let mut notify_at = tokio::time::Instant::now();
     let mut battery_high_at = tokio::time::Instant::now(); // last battery high timestamp
+    let mut poll_errors = LogRateLimiter::default();
     loop {
         interval.tick().await;
         log::debug!("Polling");
         let mut core = core_cloned.lock().expect("unexpected lock failed");
-        poll_pisugar_status(&mut core, &event_tx).await;
+        poll_pisugar_status(&mut core, &event_tx, &mut poll_errors).await;
 
-        // auto shutdown at battery low
-        let mut battery_high = true;
+        // auto shutdown at battery low, skip if disabled or battery high, or paused with a stale level
         let level = core.level().unwrap_or(100.0);
-        let auto_shutdown_level = core.config().auto_shutdown_level.unwrap_or(0.0);
-
-        // check battery level
-        if auto_shutdown_level > 0.0 && auto_shutdown_level > (level as f64) {
-            battery_high = false;
-        }
-
-        // skip if battery high
-        if battery_high {
+        if core.is_paused() || !core.config().is_auto_shutdown_level(level) {
             battery_high_at = tokio::time::Instant::now();
             continue;
         }
@@ -1299,18 +1433,35 @@ async fn main() -> std::io::Result<()> {
         if should_notify {
             let message = format!("Low battery, will power off after {} seconds", shutdown_remain_secs);
             log::warn!("{}", message);
+            #[cfg(feature = "journald")]
+            let _ = pisugar_core::journald::send("low_battery", pisugar_core::journald::PRIORITY_WARNING, &message);
             notify_shutdown_soon(message.as_str());
             notify_at = now;
         }
 
-        // shutdown
-        if shutdown_remain_secs <= 0.0 {
-            let shell = core
-                .config()
-                .soft_poweroff_shell
-                .clone()
-                .unwrap_or_else(|| "shutdown --poweroff 0".to_string());
-            let _ = execute_shell(&shell);
+        // shutdown, once per boot even if poweroff returns quickly
+        if shutdown_remain_secs <= 0.0 && core.issue_shutdown() {
+            let shell = core.config().auto_shutdown_shell();
+            log::info!("Low battery, execute \"{}\"", shell);
+            #[cfg(feature = "journald")]
+            let _ = pisugar_core::journald::send(
+                "shutdown",
+                pisugar_core::journald::PRIORITY_NOTICE,
+                "Low battery, shutdown",
+            );
+            match execute_shell(&shell) {
+                Ok(r) if r.success() => {}
+                Ok(r) => {
+                    log::error!("Shutdown script failed, code: {:?}", r.code());
+                    core.shutdown_failed();
+                }
+                Err(e) => {
+                    log::error!("{}", e);
+                    core.shutdown_failed();
+                }
+            }
+            let now = core.now();
+            core.notify_poweroff(now);
             tokio::time::sleep(Duration::from_secs(1)).await;
         }
     }
@@ -0,0 +1,266 @@
+use std::fmt::Display;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use hyper::{Body, Method, Request, Response, StatusCode};
+use pisugar_core::{ConfigPatch, Error, PiSugarCore};
+
+/// JSON response
+fn json_response(status: StatusCode, json: String) -> Result<Response<Body>> {
+    Ok(Response::builder()
+        .status(status)
+        .header("Content-Type", "application/json")
+        .body(Body::from(json))?)
+}
+
+/// JSON error response, e.g. {"error": "Not found", "code": "not_found"}, `code` is stable
+fn error_response(status: StatusCode, code: &str, e: impl ToString) -> Result<Response<Body>> {
+    json_response(
+        status,
+        serde_json::json!({ "error": e.to_string(), "code": code }).to_string(),
+    )
+}
+
+/// JSON error response of a core error, with its stable code, e.g. {"error": "...", "code": "i2c"}
+fn core_error_response(status: StatusCode, e: &Error) -> Result<Response<Body>> {
+    error_response(status, e.code(), e)
+}
+
+/// JSON error response of a poisoned core lock
+fn lock_error_response(e: impl Display) -> Result<Response<Body>> {
+    error_response(
+        StatusCode::INTERNAL_SERVER_ERROR,
+        "internal",
+        anyhow!("Lock core error: {}", e),
+    )
+}
+
+/// Run a core action, {"result": "done"} or the core error
+fn action_response(
+    core: Arc<Mutex<PiSugarCore>>,
+    action: impl FnOnce(&mut PiSugarCore) -> pisugar_core::Result<()>,
+) -> Result<Response<Body>> {
+    match core.lock() {
+        Ok(mut core) => match action(&mut core) {
+            Ok(_) => json_response(StatusCode::OK, serde_json::json!({ "result": "done" }).to_string()),
+            Err(e) => core_error_response(StatusCode::INTERNAL_SERVER_ERROR, &e),
+        },
+        Err(e) => lock_error_response(e),
+    }
+}
+
+/// REST api paths
+const API_PATHS: &[&str] = &["/status", "/config", "/diagnostics", "/shutdown", "/rtc/sync"];
+
+/// Path is served by REST api
+pub fn is_api(path: &str) -> bool {
+    API_PATHS.contains(&path)
+}
+
+/// Serve REST api, GET /status, GET /config, PUT /config, GET /diagnostics, POST /shutdown, POST /rtc/sync
+pub async fn handle_rest_req(req: Request<Body>, core: Arc<Mutex<PiSugarCore>>) -> Result<Response<Body>> {
+    let path = req.uri().path().to_string();
+    match (req.method().clone(), path.as_str()) {
+        (Method::GET, "/status") => {
+            let snapshot = match core.lock() {
+                Ok(core) => core.snapshot(),
+                Err(e) => return lock_error_response(e),
+            };
+            match snapshot {
+                Ok(snapshot) => serde_json::to_string(&snapshot)
+                    .map_err(anyhow::Error::from)
+                    .and_then(|json| json_response(StatusCode::OK, json)),
+                Err(e) => core_error_response(StatusCode::SERVICE_UNAVAILABLE, &e),
+            }
+        }
+        (Method::GET, "/config") => {
+            let config = core.lock().map(|core| core.config().clone());
+            match config {
+                Ok(mut config) => {
+                    config.auth_password = None;
+                    config
+                        .to_json_pretty()
+                        .map_err(anyhow::Error::from)
+                        .and_then(|json| json_response(StatusCode::OK, json))
+                }
+                Err(e) => lock_error_response(e),
+            }
+        }
+        (Method::PUT, "/config") => {
+            let body = match hyper::body::to_bytes(req.into_body()).await {
+                Ok(body) => body,
+                Err(e) => return error_response(StatusCode::BAD_REQUEST, "bad_request", e),
+            };
+            match serde_json::from_slice::<ConfigPatch>(&body) {
+                Ok(patch) => match core.lock() {
+                    Ok(mut core) => match core.update_config(patch) {
+                        Ok(_) => json_response(StatusCode::OK, serde_json::json!({ "result": "done" }).to_string()),
+                        Err(e) => core_error_response(StatusCode::BAD_REQUEST, &e),
+                    },
+                    Err(e) => lock_error_response(e),
+                },
+                Err(e) => error_response(StatusCode::BAD_REQUEST, "bad_request", e),
+            }
+        }
+        (Method::GET, "/diagnostics") => match core.lock() {
+            Ok(core) => json_response(StatusCode::OK, core.diagnostic_bundle().to_string()),
+            Err(e) => lock_error_response(e),
+        },
+        // graceful, auto shutdown shell at next poll
+        (Method::POST, "/shutdown") => action_response(core, |core| core.schedule_shutdown(Duration::from_secs(0))),
+        (Method::POST, "/rtc/sync") => action_response(core, |core| core.write_time(core.now_local())),
+        _ if is_api(&path) => error_response(
+            StatusCode::METHOD_NOT_ALLOWED,
+            "method_not_allowed",
+            "Method not allowed",
+        ),
+        _ => error_response(StatusCode::NOT_FOUND, "not_found", "Not found"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use hyper::{Body, Method, Request, StatusCode};
+    use pisugar_core::{Model, PiSugarConfig, PiSugarCore};
+
+    use super::{handle_rest_req, is_api};
+
+    fn request(method: Method, path: &str, body: &str) -> Request<Body> {
+        Request::builder()
+            .method(method)
+            .uri(path)
+            .body(Body::from(body.to_string()))
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_rest_api() {
+        let core = PiSugarCore::new(PiSugarConfig::default(), Model::PiSugar_3).unwrap();
+        let core = Arc::new(Mutex::new(core));
+
+        // snapshot json, or a json error without hardware
+        let resp = handle_rest_req(request(Method::GET, "/status", ""), core.clone())
+            .await
+            .unwrap();
+        let status = resp.status();
+        let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        let value: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        if status == StatusCode::OK {
+            assert!(value["level"].is_number());
+            assert!(value["model"].is_string());
+        } else {
+            assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+            assert!(value["error"].is_string());
+            assert!(value["code"].is_string());
+        }
+
+        let resp = handle_rest_req(request(Method::GET, "/config", ""), core.clone())
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let resp = handle_rest_req(request(Method::GET, "/diagnostics", ""), core.clone())
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        let value: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert!(value["health"].is_object());
+        assert!(value["registers"].is_object());
+
+        // invalid config is rejected
+        let resp = handle_rest_req(
+            request(Method::PUT, "/config", r#"{"auto_charging_range": [90, 60]}"#),
+            core.clone(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        let value: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(value["code"], "other");
+
+        let resp = handle_rest_req(request(Method::DELETE, "/status", ""), core.clone())
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::METHOD_NOT_ALLOWED);
+        let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        let value: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(value["code"], "method_not_allowed");
+
+        // no rtc
+        let resp = handle_rest_req(request(Method::POST, "/rtc/sync", ""), core.clone())
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        let value: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert!(value["code"].is_string());
+
+        assert!(!is_api("/index.html"));
+        assert!(!is_api("/ws"));
+    }
+
+    #[cfg(feature = "mock-hardware")]
+    #[tokio::test]
+    async fn test_rest_api_mock_hardware() {
+        use pisugar_core::MockI2c;
+
+        // PiSugar 2 on bus 3, 3000 voltage counts
+        MockI2c::plug_bus(3);
+        let mut chip = MockI2c::with_bus(3).unwrap();
+        chip.set_slave_address(0x75).unwrap();
+        chip.smbus_write_byte(0xa2, 0xb8).unwrap();
+        chip.smbus_write_byte(0xa3, 0x0b).unwrap();
+        let dir = std::env::temp_dir().join(format!("pisugar-rest-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("config.json");
+        let config = PiSugarConfig {
+            i2c_bus: 3,
+            ..Default::default()
+        };
+        config.save_to(&path).unwrap();
+        let mut core = PiSugarCore::new_with_path(path.to_str().unwrap(), false, Model::PiSugar_2_4LEDs).unwrap();
+        let now = core.now();
+        core.poll(now).await.unwrap();
+        let core = Arc::new(Mutex::new(core));
+
+        let resp = handle_rest_req(request(Method::GET, "/status", ""), core.clone())
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        let value: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(value["model"], Model::PiSugar_2_4LEDs.to_string());
+        let voltage = value["voltage"].as_f64().unwrap();
+        assert!((voltage - (2.6 + 3000.0 * 0.00026855)).abs() < 1e-3, "{}", voltage);
+        assert!(value["level"].is_number());
+
+        let resp = handle_rest_req(
+            request(Method::PUT, "/config", r#"{"auto_shutdown_level": 10}"#),
+            core.clone(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        let resp = handle_rest_req(request(Method::GET, "/config", ""), core.clone())
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        let value: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(value["auto_shutdown_level"], 10.0);
+
+        let resp = handle_rest_req(request(Method::GET, "/diagnostics", ""), core.clone())
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        let value: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(value["registers"]["battery"]["0xa2"], "0xb8");
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}